@@ -0,0 +1,435 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::announcements::{Announcement, ANNOUNCEMENT_NOT_FOUND};
+use crate::auth::token_matches;
+use crate::codec::Envelope;
+use crate::http::{HttpRequest, HttpResponse};
+use crate::requests;
+use crate::responses::{self, ResponseType, UserInfo};
+use crate::room::RoomInfo;
+use crate::room_manager::ROOM_NOT_FOUND;
+use crate::server::{ServerState, CLIENT_NOT_FOUND};
+use crate::service;
+use crate::types::ChatError;
+
+pub const NOT_BANNED: &str = "Address is not banned";
+pub const NO_REMOTE_ADDRESS: &str = "Connection has no remote address";
+pub const INVALID_ADDRESS: &str = "Invalid address";
+
+const RESOURCES: [&str; 4] = ["connections", "bans", "rooms", "announcements"];
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ConnectionInfo {
+    id: Uuid,
+    name: Option<String>,
+    remote_addr: Option<String>,
+    connected_since: DateTime<Utc>,
+    bot: bool,
+    admin: bool,
+    rooms: Vec<Uuid>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RoomDetails {
+    #[serde(flatten)]
+    room: RoomInfo,
+    members: Vec<UserInfo>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Banned {
+    address: IpAddr,
+    // connections closed by the ban
+    closed: usize,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RoomRemoved {
+    room_id: Uuid,
+    members: usize,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NewOwner {
+    connection_id: Uuid,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: &'a str,
+}
+
+fn json<T: Serialize>(status: u16, body: &T) -> HttpResponse {
+    match serde_json::to_vec(body) {
+        Ok(body) => HttpResponse::json(status, body),
+        Err(err) => error(500, &err.to_string()),
+    }
+}
+
+fn error(status: u16, message: &str) -> HttpResponse {
+    HttpResponse::json(
+        status,
+        serde_json::to_vec(&ErrorBody { error: message }).unwrap_or_default(),
+    )
+}
+
+fn chat_error(err: ChatError) -> HttpResponse {
+    let status = match err.as_str() {
+        ROOM_NOT_FOUND | CLIENT_NOT_FOUND | ANNOUNCEMENT_NOT_FOUND | NOT_BANNED => 404,
+        _ => 400,
+    };
+    error(status, &err)
+}
+
+fn no_content() -> HttpResponse {
+    HttpResponse::new(204, "application/json", vec![])
+}
+
+fn parse_uuid(id: &str) -> Result<Uuid, ChatError> {
+    Uuid::parse_str(id).map_err(|e| e.to_string())
+}
+
+/// Handles one admin API request. Every route needs the admin token as a
+/// bearer token.
+pub(crate) async fn route(request: HttpRequest, state: ServerState) -> HttpResponse {
+    let authorized = match (
+        state.config.admin_token.as_deref(),
+        request
+            .header("authorization")
+            .and_then(|value| value.strip_prefix("Bearer ")),
+    ) {
+        (Some(expected), Some(given)) => token_matches(expected, given.trim()),
+        _ => false,
+    };
+    if !authorized {
+        return error(401, "Unauthorized");
+    }
+
+    let segments: Vec<&str> = request
+        .path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+    let result = match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["connections"]) => connections(&state).await,
+        ("DELETE", ["connections", id]) => kick(id, &state).await,
+        ("POST", ["connections", id, "ban"]) => ban(id, &state).await,
+        ("GET", ["bans"]) => bans(&state).await,
+        ("DELETE", ["bans", address]) => unban(address, &state).await,
+        ("GET", ["rooms"]) => rooms(&state).await,
+        ("GET", ["rooms", id]) => room(id, &state).await,
+        ("DELETE", ["rooms", id]) => delete_room(id, &state).await,
+        ("PUT", ["rooms", id, "owner"]) => set_owner(id, &request.body, &state).await,
+        ("GET", ["announcements"]) => announcements(&state).await,
+        ("POST", ["announcements"]) => announce(&request.body, &state).await,
+        ("DELETE", ["announcements", id]) => unpin(id, &state).await,
+        (_, [resource, ..]) if RESOURCES.contains(resource) => {
+            return HttpResponse::method_not_allowed()
+        }
+        _ => return HttpResponse::not_found(),
+    };
+    result.unwrap_or_else(chat_error)
+}
+
+/// Room ids by member.
+async fn memberships(state: &ServerState) -> Result<HashMap<Uuid, Vec<Uuid>>, ChatError> {
+    let lock_room_manager = state.room_manager.lock().await;
+    let mut memberships: HashMap<Uuid, Vec<Uuid>> = HashMap::new();
+    for room in lock_room_manager.list().await {
+        for member in lock_room_manager.members(&room.id).await? {
+            memberships.entry(member).or_default().push(room.id);
+        }
+    }
+    Ok(memberships)
+}
+
+async fn connections(state: &ServerState) -> Result<HttpResponse, ChatError> {
+    let mut memberships = memberships(state).await?;
+    let mut connections: Vec<ConnectionInfo> = state
+        .clients
+        .lock()
+        .await
+        .values()
+        .map(|connection| ConnectionInfo {
+            id: connection.id,
+            name: connection.name.clone(),
+            remote_addr: connection.remote_addr.map(|addr| addr.to_string()),
+            connected_since: connection.connected_at,
+            bot: connection.bot,
+            admin: connection.admin,
+            rooms: memberships.remove(&connection.id).unwrap_or_default(),
+        })
+        .collect();
+    connections.sort_by_key(|connection| connection.connected_since);
+    Ok(json(200, &connections))
+}
+
+/// Closes the connection, it leaves its rooms right away as on any
+/// disconnect.
+async fn kick(id: &str, state: &ServerState) -> Result<HttpResponse, ChatError> {
+    let id = parse_uuid(id)?;
+    let closing = state
+        .clients
+        .lock()
+        .await
+        .get_mut(&id)
+        .ok_or(CLIENT_NOT_FOUND)?
+        .close_handled()
+        .await;
+    // removed even when the close frame could not be sent
+    if let Err(err) = closing {
+        warn!(error = %err, "closing kicked connection failed");
+    }
+    service::disconnect(id, state).await;
+    info!(conn_id = %id, "admin closed connection");
+    Ok(no_content())
+}
+
+/// Bans the address of the connection and closes every connection from it.
+async fn ban(id: &str, state: &ServerState) -> Result<HttpResponse, ChatError> {
+    let id = parse_uuid(id)?;
+    let mut lock_connections = state.clients.lock().await;
+    let address = lock_connections
+        .get(&id)
+        .ok_or(CLIENT_NOT_FOUND)?
+        .remote_addr
+        .ok_or(NO_REMOTE_ADDRESS)?
+        .ip();
+    state.bans.lock().await.insert(address);
+
+    let mut banned = vec![];
+    for connection in lock_connections.values_mut() {
+        if connection.remote_addr.map(|addr| addr.ip()) == Some(address) {
+            if let Err(err) = connection.close_handled().await {
+                warn!(error = %err, "closing banned connection failed");
+            }
+            banned.push(connection.id);
+        }
+    }
+    drop(lock_connections);
+    let closed = banned.len();
+    for conn_id in banned {
+        service::disconnect(conn_id, state).await;
+    }
+    info!(%address, closed, "admin banned address");
+    Ok(json(200, &Banned { address, closed }))
+}
+
+async fn bans(state: &ServerState) -> Result<HttpResponse, ChatError> {
+    let mut bans: Vec<IpAddr> = state.bans.lock().await.iter().copied().collect();
+    bans.sort();
+    Ok(json(200, &bans))
+}
+
+async fn unban(address: &str, state: &ServerState) -> Result<HttpResponse, ChatError> {
+    let address: IpAddr = address.parse().map_err(|_| INVALID_ADDRESS)?;
+    if !state.bans.lock().await.remove(&address) {
+        return Err(NOT_BANNED.to_owned());
+    }
+    info!(%address, "admin lifted ban");
+    Ok(no_content())
+}
+
+async fn rooms(state: &ServerState) -> Result<HttpResponse, ChatError> {
+    let rooms = state.room_manager.lock().await.list().await;
+    let mut details = Vec::with_capacity(rooms.len());
+    for room in rooms {
+        let members = service::room_members(&room.id, &state.clients, &state.room_manager).await?;
+        details.push(RoomDetails { room, members });
+    }
+    Ok(json(200, &details))
+}
+
+async fn room(id: &str, state: &ServerState) -> Result<HttpResponse, ChatError> {
+    let id = parse_uuid(id)?;
+    let room = state.room_manager.lock().await.info(&id).await?;
+    let members = service::room_members(&id, &state.clients, &state.room_manager).await?;
+    Ok(json(200, &RoomDetails { room, members }))
+}
+
+async fn delete_room(id: &str, state: &ServerState) -> Result<HttpResponse, ChatError> {
+    let room_id = parse_uuid(id)?;
+    let members = service::delete_room(&room_id, state).await?;
+    Ok(json(200, &RoomRemoved { room_id, members }))
+}
+
+/// Hands a room to one of its members, e.g. after a restart left it
+/// without an owner.
+async fn set_owner(id: &str, body: &[u8], state: &ServerState) -> Result<HttpResponse, ChatError> {
+    let room_id = parse_uuid(id)?;
+    let req: NewOwner = serde_json::from_slice(body).map_err(|e| e.to_string())?;
+    let bot_account = service::bot_account(req.connection_id, &state.clients).await;
+    let lock_room_manager = &mut state.room_manager.lock().await;
+    let room = lock_room_manager
+        .set_owner(&room_id, &req.connection_id, bot_account.as_deref())
+        .await?;
+    let response = Envelope::new(
+        ResponseType::RoomUpdated,
+        responses::RoomUpdated {
+            room: room.clone(),
+            // no connection made the change
+            updated_by: Uuid::nil(),
+        },
+    );
+    lock_room_manager.all(&room_id, &response).await?;
+    info!(%room_id, owner = %req.connection_id, "admin appointed room owner");
+    Ok(json(200, &room))
+}
+
+async fn announcements(state: &ServerState) -> Result<HttpResponse, ChatError> {
+    let pinned: Vec<Announcement> = state.announcements.lock().await.pinned();
+    Ok(json(200, &pinned))
+}
+
+/// Takes the same body as the `Announce` request.
+async fn announce(body: &[u8], state: &ServerState) -> Result<HttpResponse, ChatError> {
+    let req: requests::Announce = serde_json::from_slice(body).map_err(|e| e.to_string())?;
+    let announcement = Announcement::new(
+        req.text.trim().to_owned(),
+        req.severity,
+        req.room_ids,
+        req.pinned,
+    );
+    let announcement_id = announcement.id;
+    let recipients = service::announce(announcement, state).await?;
+    Ok(json(
+        201,
+        &responses::Announced {
+            announcement_id,
+            recipients,
+        },
+    ))
+}
+
+async fn unpin(id: &str, state: &ServerState) -> Result<HttpResponse, ChatError> {
+    service::unpin_announcement(&parse_uuid(id)?, state).await?;
+    Ok(no_content())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+    use tokio_tungstenite::connect_async;
+
+    use crate::config::Config;
+    use crate::server::ServerHandle;
+    use crate::test_support::{self, TestClient};
+
+    const TOKEN: &str = "secret";
+
+    async fn start() -> ServerHandle {
+        test_support::builder()
+            .config(Config {
+                admin_addr: Some("127.0.0.1:0".to_owned()),
+                admin_token: Some(TOKEN.to_owned()),
+                ..test_support::config()
+            })
+            .start()
+            .await
+            .unwrap()
+    }
+
+    async fn admin(handle: &ServerHandle, method: &str, path: &str) -> (u16, Value) {
+        let (status, body) =
+            test_support::http(handle.admin_addr().unwrap(), method, path, Some(TOKEN), "").await;
+        (status, serde_json::from_str(&body).unwrap_or(Value::Null))
+    }
+
+    #[tokio::test]
+    async fn requests_need_the_admin_token() {
+        let handle = start().await;
+        let addr = handle.admin_addr().unwrap();
+
+        let (status, _) = test_support::http(addr, "GET", "/connections", None, "").await;
+        assert_eq!(status, 401);
+        let (status, _) = test_support::http(addr, "GET", "/connections", Some("guess"), "").await;
+        assert_eq!(status, 401);
+        let (status, _) = admin(&handle, "GET", "/connections").await;
+        assert_eq!(status, 200);
+
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn kicked_connections_leave_their_rooms() {
+        let handle = start().await;
+        let mut alice = TestClient::connect(handle.local_addr(), "alice").await;
+        let mut bob = TestClient::connect(handle.local_addr(), "bob").await;
+        let room_id = alice.create_room("team").await;
+        bob.join_room(room_id).await;
+        alice.drain().await;
+
+        let (status, _) = admin(&handle, "DELETE", &format!("/connections/{}", bob.id)).await;
+        assert_eq!(status, 204);
+        // gone as soon as the kick is answered
+        let (_, connections) = admin(&handle, "GET", "/connections").await;
+        assert_eq!(connections.as_array().unwrap().len(), 1);
+        let (_, room) = admin(&handle, "GET", &format!("/rooms/{}", room_id)).await;
+        assert_eq!(room["members"].as_array().unwrap().len(), 1);
+        assert!(bob.closed().await);
+        let left = alice.expect("MemberLeft").await;
+        assert_eq!(left["user"]["name"], "bob");
+        assert!(alice.drain().await.is_empty());
+
+        drop(alice);
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn banned_addresses_cannot_reconnect() {
+        let handle = start().await;
+        let mut alice = TestClient::connect(handle.local_addr(), "alice").await;
+
+        let (status, banned) =
+            admin(&handle, "POST", &format!("/connections/{}/ban", alice.id)).await;
+        assert_eq!(status, 200);
+        assert_eq!(banned["address"], "127.0.0.1");
+        assert_eq!(banned["closed"], 1);
+        assert!(alice.closed().await);
+        let (_, connections) = admin(&handle, "GET", "/connections").await;
+        assert!(connections.as_array().unwrap().is_empty());
+        assert!(connect_async(format!("ws://{}", handle.local_addr()))
+            .await
+            .is_err());
+
+        let (status, _) = admin(&handle, "DELETE", "/bans/127.0.0.1").await;
+        assert_eq!(status, 204);
+        let alice = TestClient::connect(handle.local_addr(), "alice").await;
+
+        drop(alice);
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn deleted_rooms_are_announced_to_members() {
+        let handle = start().await;
+        let mut alice = TestClient::connect(handle.local_addr(), "alice").await;
+        let mut bob = TestClient::connect(handle.local_addr(), "bob").await;
+        let room_id = alice.create_room("team").await;
+        bob.join_room(room_id).await;
+
+        let (status, removed) = admin(&handle, "DELETE", &format!("/rooms/{}", room_id)).await;
+        assert_eq!(status, 200);
+        assert_eq!(removed["members"], 2);
+        for client in [&mut alice, &mut bob] {
+            let deleted = client.expect("RoomDeleted").await;
+            assert_eq!(deleted["roomId"], room_id.to_string());
+        }
+        let (status, _) = admin(&handle, "GET", &format!("/rooms/{}", room_id)).await;
+        assert_eq!(status, 404);
+
+        drop((alice, bob));
+        handle.shutdown().await;
+    }
+}
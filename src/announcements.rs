@@ -0,0 +1,268 @@
+use std::collections::VecDeque;
+
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::codec::Envelope;
+use crate::responses::{self, ResponseType, UserInfo};
+use crate::types::ChatError;
+
+pub const SYSTEM_USER_NAME: &str = "System";
+// oldest pinned announcements are dropped beyond this
+pub const MAX_PINNED: usize = 20;
+
+pub const ADMINS_ONLY: &str = "Only admins can do this";
+pub const NO_ROOMS: &str = "No rooms to announce to";
+pub const ANNOUNCEMENT_NOT_FOUND: &str = "Announcement not found";
+
+/// The sender of everything the server posts itself. No connection has its
+/// id and nobody can take its name.
+pub fn system_user() -> UserInfo {
+    UserInfo {
+        id: Uuid::nil(),
+        name: SYSTEM_USER_NAME.to_owned(),
+        bot: false,
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum Severity {
+    #[default]
+    Info,
+    Warning,
+    Critical,
+}
+
+/// Builds a system message that is not an announcement, e.g. the reply to
+/// a slash command.
+pub fn system_message(room_id: Option<Uuid>, text: String, ephemeral: bool) -> Envelope {
+    Envelope::new(
+        ResponseType::SystemMessage,
+        responses::SystemMessage {
+            id: Uuid::new_v4(),
+            room_id,
+            sender: system_user(),
+            text,
+            severity: Severity::Info,
+            ephemeral,
+            pinned: false,
+            created_at: Utc::now(),
+        },
+    )
+}
+
+/// An operator message to everyone connected or to some rooms.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Announcement {
+    pub id: Uuid,
+    // `None` for everyone connected
+    pub room_ids: Option<Vec<Uuid>>,
+    pub text: String,
+    pub severity: Severity,
+    // also shown to clients connecting or joining later
+    pub pinned: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Announcement {
+    pub fn new(
+        text: String,
+        severity: Severity,
+        room_ids: Option<Vec<Uuid>>,
+        pinned: bool,
+    ) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            room_ids,
+            text,
+            severity,
+            pinned,
+            created_at: Utc::now(),
+        }
+    }
+
+    /// The message as sent to one of its rooms, or to everyone for `None`.
+    pub fn message(&self, room_id: Option<Uuid>) -> Envelope {
+        Envelope::new(
+            ResponseType::SystemMessage,
+            responses::SystemMessage {
+                id: self.id,
+                room_id,
+                sender: system_user(),
+                text: self.text.to_owned(),
+                severity: self.severity,
+                ephemeral: false,
+                pinned: self.pinned,
+                created_at: self.created_at,
+            },
+        )
+    }
+}
+
+/// Pinned announcements, oldest first.
+#[derive(Default)]
+pub struct Announcements {
+    pinned: VecDeque<Announcement>,
+}
+
+impl Announcements {
+    pub fn new() -> Self {
+        Announcements::default()
+    }
+
+    pub fn pin(&mut self, announcement: Announcement) {
+        self.pinned.push_back(announcement);
+        while self.pinned.len() > MAX_PINNED {
+            self.pinned.pop_front();
+        }
+    }
+
+    pub fn unpin(&mut self, id: &Uuid) -> Result<Announcement, ChatError> {
+        let index = self
+            .pinned
+            .iter()
+            .position(|announcement| announcement.id == *id)
+            .ok_or(ANNOUNCEMENT_NOT_FOUND)?;
+        Ok(self.pinned.remove(index).unwrap())
+    }
+
+    pub fn pinned(&self) -> Vec<Announcement> {
+        self.pinned.iter().cloned().collect()
+    }
+
+    /// The pinned messages a client gets when connecting.
+    pub fn for_everyone(&self) -> Vec<Envelope> {
+        self.pinned
+            .iter()
+            .filter(|announcement| announcement.room_ids.is_none())
+            .map(|announcement| announcement.message(None))
+            .collect()
+    }
+
+    /// The pinned messages a client gets when joining the room.
+    pub fn for_room(&self, room_id: &Uuid) -> Vec<Envelope> {
+        self.pinned
+            .iter()
+            .filter(|announcement| {
+                announcement
+                    .room_ids
+                    .as_ref()
+                    .is_some_and(|room_ids| room_ids.contains(room_id))
+            })
+            .map(|announcement| announcement.message(Some(*room_id)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::server::ServerHandle;
+    use crate::test_support::{self, TestClient};
+
+    const ADMIN_TOKEN: &str = "secret";
+
+    async fn start() -> ServerHandle {
+        test_support::builder()
+            .config(Config {
+                admin_token: Some(ADMIN_TOKEN.to_owned()),
+                ..test_support::config()
+            })
+            .start()
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn oldest_pinned_announcements_are_dropped() {
+        let mut announcements = Announcements::new();
+        let texts: Vec<String> = (0..=MAX_PINNED).map(|i| i.to_string()).collect();
+        for text in &texts {
+            announcements.pin(Announcement::new(
+                text.to_owned(),
+                Severity::Info,
+                None,
+                true,
+            ));
+        }
+
+        let pinned: Vec<String> = announcements
+            .pinned()
+            .into_iter()
+            .map(|announcement| announcement.text)
+            .collect();
+        assert_eq!(pinned, texts[1..]);
+    }
+
+    #[tokio::test]
+    async fn only_admins_announce() {
+        let handle = start().await;
+        let mut admin = TestClient::with_token(handle.local_addr(), ADMIN_TOKEN).await;
+        let mut alice = TestClient::connect(handle.local_addr(), "alice").await;
+
+        alice
+            .send("Announce", json!({ "text": "free pizza" }))
+            .await;
+        assert_eq!(alice.expect("Error").await["message"], ADMINS_ONLY);
+        assert!(admin.drain().await.is_empty());
+
+        admin
+            .send(
+                "Announce",
+                json!({ "text": "restart at noon", "severity": "warning" }),
+            )
+            .await;
+        assert_eq!(admin.expect("Announced").await["recipients"], 2);
+        let message = alice.expect("SystemMessage").await;
+        assert_eq!(message["text"], "restart at noon");
+        assert_eq!(message["severity"], "warning");
+        assert_eq!(message["sender"]["name"], SYSTEM_USER_NAME);
+
+        drop((admin, alice));
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn pinned_announcements_reach_late_clients() {
+        let handle = start().await;
+        let mut admin = TestClient::with_token(handle.local_addr(), ADMIN_TOKEN).await;
+        let mut alice = TestClient::connect(handle.local_addr(), "alice").await;
+        let room_id = alice.create_room("team").await;
+
+        let everyone = json!({ "text": "welcome", "pinned": true });
+        admin.send("Announce", everyone).await;
+        admin.expect("Announced").await;
+        let team = json!({ "text": "standup at ten", "roomIds": [room_id], "pinned": true });
+        admin.send("Announce", team).await;
+        admin.expect("Announced").await;
+
+        let mut bob = TestClient::quiet(handle.local_addr()).await;
+        let greeting: Vec<_> = bob
+            .drain()
+            .await
+            .into_iter()
+            .filter(|(response_type, _)| response_type == "SystemMessage")
+            .map(|(_, message)| message)
+            .collect();
+        assert_eq!(greeting.len(), 1);
+        assert_eq!(greeting[0]["text"], "welcome");
+        assert_eq!(greeting[0]["pinned"], true);
+
+        bob.send("SetNickname", json!({ "name": "bob" })).await;
+        bob.send("JoinRoom", json!({ "id": room_id.to_string() }))
+            .await;
+        let message = bob.expect("SystemMessage").await;
+        assert_eq!(message["text"], "standup at ten");
+        assert_eq!(message["roomId"], room_id.to_string());
+
+        drop((admin, alice, bob));
+        handle.shutdown().await;
+    }
+}
@@ -0,0 +1,65 @@
+use tokio_tungstenite::tungstenite::handshake::server::Request;
+use tokio_tungstenite::tungstenite::http::header::AUTHORIZATION;
+
+use crate::types::ChatError;
+
+pub const UNAUTHORIZED: &str = "Unauthorized";
+
+/// Who a connection belongs to, as established during the handshake.
+#[derive(Debug, Clone, Default)]
+pub struct Identity {
+    // nickname to start with, clients can still change it unless a bot
+    pub name: Option<String>,
+    pub bot: bool,
+    pub admin: bool,
+}
+
+/// The token of an `Authorization: Bearer <token>` header.
+pub fn bearer_token(request: &Request) -> Option<&str> {
+    request
+        .headers()
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+/// Compares secrets in time independent of where they differ.
+pub fn token_matches(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Checks the websocket upgrade request, a rejected handshake gets a 401.
+/// Runs inside the handshake, so implementations must not block for long.
+pub trait Authenticator: Send + Sync {
+    fn authenticate(&self, request: &Request) -> Result<Identity, ChatError>;
+
+    /// Whether clients need credentials, advertised as the `auth` feature.
+    fn required(&self) -> bool {
+        true
+    }
+
+    /// Names nobody can pick with `SetNickname`.
+    fn is_reserved(&self, _name: &str) -> bool {
+        false
+    }
+}
+
+/// Accepts every connection anonymously.
+pub struct AllowAll;
+
+impl Authenticator for AllowAll {
+    fn authenticate(&self, _request: &Request) -> Result<Identity, ChatError> {
+        Ok(Identity::default())
+    }
+
+    fn required(&self) -> bool {
+        false
+    }
+}
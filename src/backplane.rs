@@ -0,0 +1,431 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, mpsc, Mutex};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::codec::Envelope;
+use crate::history::StoredMessage;
+use crate::registry::{RegisteredUser, Registry};
+use crate::responses::ResponseType;
+use crate::room::RoomInfo;
+use crate::types::{serde_error_to_chat_error, ChatError};
+
+// messages a slow subscriber may fall behind by before it misses some
+pub const SUBSCRIBER_BUFFER: usize = 1024;
+// events waiting for a slow backplane before new ones are dropped
+pub const PUBLISH_BUFFER: usize = 1024;
+
+/// A response as it travels between nodes, turned back into an `Envelope`
+/// by the nodes that deliver it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Relayed {
+    pub response_type: ResponseType,
+    pub data: serde_json::Value,
+}
+
+impl Relayed {
+    pub fn new(response: &Envelope) -> Result<Self, ChatError> {
+        Ok(Self {
+            response_type: response.response_type,
+            data: response.data()?,
+        })
+    }
+
+    pub fn envelope(self) -> Envelope {
+        Envelope::new(self.response_type, self.data)
+    }
+}
+
+/// What one node tells the others. Delivery events carry the response,
+/// every node sends it to the connections it holds.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+pub enum ClusterEvent {
+    // to one connection, only the node holding it delivers
+    #[serde(rename_all = "camelCase")]
+    Direct {
+        receiver_id: Uuid,
+        response: Relayed,
+    },
+    // to the members of a room on every node
+    #[serde(rename_all = "camelCase")]
+    Room {
+        room_id: Uuid,
+        // leaves out members who muted the room
+        unmuted_only: bool,
+        response: Relayed,
+    },
+    // to every connection
+    Everyone {
+        response: Relayed,
+    },
+    // about a user, to whoever watches the user or one of its rooms
+    #[serde(rename_all = "camelCase")]
+    Presence {
+        user_id: Uuid,
+        room_ids: Vec<Uuid>,
+        response: Relayed,
+    },
+    // a room was created or its metadata changed
+    RoomChanged {
+        room: RoomInfo,
+    },
+    #[serde(rename_all = "camelCase")]
+    RoomDeleted {
+        room_id: Uuid,
+    },
+    // every node keeps the history, so replies and reactions work on any
+    MessageStored {
+        message: StoredMessage,
+    },
+    #[serde(rename_all = "camelCase")]
+    ReactionChanged {
+        message_id: Uuid,
+        user_id: Uuid,
+        emoji: String,
+        added: bool,
+    },
+    // every user of the node, renews its lease
+    Heartbeat {
+        users: Vec<RegisteredUser>,
+    },
+    // a user connected or changed its name
+    Connected {
+        user: RegisteredUser,
+    },
+    #[serde(rename_all = "camelCase")]
+    Disconnected {
+        user_id: Uuid,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ClusterMessage {
+    // the node that published it
+    pub origin: Uuid,
+    pub event: ClusterEvent,
+}
+
+impl ClusterMessage {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ChatError> {
+        serde_json::to_vec(self).map_err(serde_error_to_chat_error)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ChatError> {
+        serde_json::from_slice(bytes).map_err(serde_error_to_chat_error)
+    }
+}
+
+/// Pub/sub between the nodes of a cluster, set with
+/// `ServerBuilder::backplane`.
+#[async_trait]
+pub trait Backplane: Send + Sync {
+    async fn publish(&self, message: &ClusterMessage) -> Result<(), ChatError>;
+
+    /// Everything published by any node, its own messages included. Called
+    /// once when the server starts.
+    async fn subscribe(&self) -> Result<mpsc::Receiver<ClusterMessage>, ChatError>;
+}
+
+/// Connects servers running in the same process, clone it for each one.
+#[derive(Clone)]
+pub struct InProcessBackplane {
+    sender: broadcast::Sender<ClusterMessage>,
+}
+
+impl InProcessBackplane {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(SUBSCRIBER_BUFFER);
+        Self { sender }
+    }
+}
+
+impl Default for InProcessBackplane {
+    fn default() -> Self {
+        InProcessBackplane::new()
+    }
+}
+
+#[async_trait]
+impl Backplane for InProcessBackplane {
+    async fn publish(&self, message: &ClusterMessage) -> Result<(), ChatError> {
+        // no subscribers is not an error, the other nodes may not run yet
+        let _ = self.sender.send(message.clone());
+        Ok(())
+    }
+
+    async fn subscribe(&self) -> Result<mpsc::Receiver<ClusterMessage>, ChatError> {
+        let mut subscription = self.sender.subscribe();
+        let (sender, receiver) = mpsc::channel(SUBSCRIBER_BUFFER);
+        tokio::spawn(async move {
+            loop {
+                match subscription.recv().await {
+                    Ok(message) => {
+                        if sender.send(message).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!(missed, "backplane subscriber fell behind");
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+        Ok(receiver)
+    }
+}
+
+/// This node's view of the cluster. Without a backplane the server runs
+/// alone and nothing is published.
+///
+/// Room messages, direct messages, their history, presence, announcements
+/// and room metadata reach every node. Member lists in responses and
+/// unread mentions stay with the node that holds the connections.
+pub struct Cluster {
+    pub node_id: Uuid,
+    backplane: Option<Arc<dyn Backplane>>,
+    // events are published by one task in order, callers never wait on
+    // the backplane and may hold locks while publishing
+    outbox: Option<mpsc::Sender<ClusterMessage>>,
+    // users connected to the other nodes
+    pub registry: Mutex<Registry>,
+}
+
+impl Cluster {
+    pub fn local() -> Self {
+        Self {
+            node_id: Uuid::new_v4(),
+            backplane: None,
+            outbox: None,
+            registry: Mutex::new(Registry::new()),
+        }
+    }
+
+    /// Starts the task publishing this node's events, it ends when the
+    /// cluster is dropped.
+    pub fn new(backplane: Arc<dyn Backplane>) -> Self {
+        let (outbox, mut receiver) = mpsc::channel::<ClusterMessage>(PUBLISH_BUFFER);
+        let publisher = Arc::clone(&backplane);
+        tokio::spawn(async move {
+            while let Some(message) = receiver.recv().await {
+                if let Err(err) = publisher.publish(&message).await {
+                    warn!(error = %err, "backplane publish failed");
+                }
+            }
+        });
+        Self {
+            node_id: Uuid::new_v4(),
+            backplane: Some(backplane),
+            outbox: Some(outbox),
+            registry: Mutex::new(Registry::new()),
+        }
+    }
+
+    pub fn backplane(&self) -> Option<&Arc<dyn Backplane>> {
+        self.backplane.as_ref()
+    }
+
+    pub fn is_clustered(&self) -> bool {
+        self.backplane.is_some()
+    }
+
+    /// Publishing is best effort, local delivery already happened and a
+    /// failure only costs the other nodes this event.
+    pub async fn publish(&self, event: ClusterEvent) {
+        let outbox = match &self.outbox {
+            Some(outbox) => outbox,
+            None => return,
+        };
+        let message = ClusterMessage {
+            origin: self.node_id,
+            event,
+        };
+        if outbox.try_send(message).is_err() {
+            warn!("backplane publish queue full, event dropped");
+        }
+    }
+
+    /// Publishes a response, built only when there are other nodes.
+    async fn relay(&self, response: &Envelope, event: impl FnOnce(Relayed) -> ClusterEvent) {
+        if !self.is_clustered() {
+            return;
+        }
+        match Relayed::new(response) {
+            Ok(relayed) => self.publish(event(relayed)).await,
+            Err(err) => warn!(error = %err, "response not relayed"),
+        }
+    }
+
+    pub async fn direct(&self, receiver_id: Uuid, response: &Envelope) {
+        self.relay(response, |response| ClusterEvent::Direct {
+            receiver_id,
+            response,
+        })
+        .await
+    }
+
+    pub async fn room(&self, room_id: Uuid, unmuted_only: bool, response: &Envelope) {
+        self.relay(response, |response| ClusterEvent::Room {
+            room_id,
+            unmuted_only,
+            response,
+        })
+        .await
+    }
+
+    pub async fn everyone(&self, response: &Envelope) {
+        self.relay(response, |response| ClusterEvent::Everyone { response })
+            .await
+    }
+
+    pub async fn presence(&self, user_id: Uuid, room_ids: Vec<Uuid>, response: &Envelope) {
+        self.relay(response, |response| ClusterEvent::Presence {
+            user_id,
+            room_ids,
+            response,
+        })
+        .await
+    }
+
+    pub async fn message_stored(&self, message: &StoredMessage) {
+        if self.is_clustered() {
+            self.publish(ClusterEvent::MessageStored {
+                message: message.clone(),
+            })
+            .await
+        }
+    }
+
+    pub async fn reaction_changed(
+        &self,
+        message_id: Uuid,
+        user_id: Uuid,
+        emoji: &str,
+        added: bool,
+    ) {
+        if self.is_clustered() {
+            self.publish(ClusterEvent::ReactionChanged {
+                message_id,
+                user_id,
+                emoji: emoji.to_owned(),
+                added,
+            })
+            .await
+        }
+    }
+
+    pub async fn room_changed(&self, room: RoomInfo) {
+        if self.is_clustered() {
+            self.publish(ClusterEvent::RoomChanged { room }).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_support::{self, TestClient};
+
+    #[tokio::test]
+    async fn messages_reach_the_other_node() {
+        let backplane = InProcessBackplane::new();
+        let node_a = test_support::builder()
+            .backplane(backplane.clone())
+            .start()
+            .await
+            .unwrap();
+        let node_b = test_support::builder()
+            .backplane(backplane)
+            .start()
+            .await
+            .unwrap();
+        let mut alice = TestClient::connect(node_a.local_addr(), "alice").await;
+        let mut bob = TestClient::connect(node_b.local_addr(), "bob").await;
+
+        let room_id = alice.create_room("team").await;
+        bob.send("JoinRoom", json!({ "id": room_id.to_string() }))
+            .await;
+        bob.expect("RoomJoined").await;
+        bob.drain().await;
+        alice.drain().await;
+
+        let post = json!({ "messageType": "Room", "receiverId": room_id, "message": "hi all" });
+        alice.send("Message", post).await;
+        assert_eq!(bob.expect("Message").await["message"], "hi all");
+
+        let dm = json!({ "messageType": "User", "receiverId": bob.id, "message": "hi bob" });
+        alice.send("Message", dm).await;
+        assert_eq!(bob.expect("Message").await["message"], "hi bob");
+
+        drop((alice, bob));
+        node_a.shutdown().await;
+        node_b.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn mentions_reactions_and_threads_work_across_nodes() {
+        let backplane = InProcessBackplane::new();
+        let node_a = test_support::builder()
+            .backplane(backplane.clone())
+            .start()
+            .await
+            .unwrap();
+        let node_b = test_support::builder()
+            .backplane(backplane)
+            .start()
+            .await
+            .unwrap();
+        let mut alice = TestClient::connect(node_a.local_addr(), "alice").await;
+        let mut bob = TestClient::connect(node_b.local_addr(), "bob").await;
+        let room_id = alice.create_room("team").await;
+        bob.join_room(room_id).await;
+        alice.drain().await;
+
+        let post = json!({ "messageType": "Room", "receiverId": room_id, "message": "hi @bob" });
+        alice.send("Message", post).await;
+        let message_id = alice.expect("Message").await["messageId"].clone();
+        let mentioned = bob.expect("Mentioned").await;
+        assert_eq!(mentioned["message"]["messageId"], message_id);
+        assert_eq!(mentioned["unreadMentions"], 1);
+        alice.drain().await;
+        bob.drain().await;
+
+        // bob's node never received the request that stored the message
+        bob.send(
+            "AddReaction",
+            json!({ "messageId": message_id, "emoji": "👍" }),
+        )
+        .await;
+        for client in [&mut alice, &mut bob] {
+            let updated = client.expect("ReactionsUpdated").await;
+            assert_eq!(updated["reactions"][0]["count"], 1);
+        }
+
+        let reply = json!({
+            "messageType": "Room",
+            "receiverId": room_id,
+            "message": "on it",
+            "replyTo": message_id,
+        });
+        bob.send("Message", reply).await;
+        assert_eq!(alice.expect("ThreadReply").await["replyCount"], 1);
+        alice
+            .send("GetThread", json!({ "messageId": message_id }))
+            .await;
+        let thread = alice.expect("Thread").await;
+        assert_eq!(thread["parent"]["reactions"][0]["emoji"], "👍");
+        assert_eq!(thread["replies"][0]["message"], "on it");
+
+        drop((alice, bob));
+        node_a.shutdown().await;
+        node_b.shutdown().await;
+    }
+}
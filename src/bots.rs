@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+
+use tokio_tungstenite::tungstenite::handshake::server::Request;
+use tokio_tungstenite::tungstenite::http::header::AUTHORIZATION;
+
+use crate::auth::{Authenticator, Identity};
+use crate::types::ChatError;
+
+pub const MAX_COMMAND_LEN: usize = 32;
+
+pub const INVALID_API_KEY: &str = "Invalid API key";
+pub const BOTS_ONLY: &str = "Only bots can do this";
+pub const BOT_NAME_FIXED: &str = "Bot names cannot be changed";
+pub const NAME_RESERVED: &str = "Name is reserved";
+pub const INVALID_COMMAND_NAME: &str = "Invalid command name";
+
+#[derive(Debug, Clone)]
+pub struct BotAccount {
+    pub name: String,
+    pub api_key: String,
+}
+
+/// Lets bots in with `Authorization: Bearer <api key>`, everyone else
+/// connects anonymously as before. Bot names cannot be taken by others.
+pub struct ApiKeyAuth {
+    // api key -> bot name
+    bots: HashMap<String, String>,
+}
+
+impl ApiKeyAuth {
+    pub fn new(accounts: Vec<BotAccount>) -> Self {
+        Self {
+            bots: accounts
+                .into_iter()
+                .map(|account| (account.api_key, account.name))
+                .collect(),
+        }
+    }
+}
+
+impl Authenticator for ApiKeyAuth {
+    fn authenticate(&self, request: &Request) -> Result<Identity, ChatError> {
+        let header = match request.headers().get(AUTHORIZATION) {
+            Some(header) => header,
+            None => return Ok(Identity::default()),
+        };
+        let key = header
+            .to_str()
+            .ok()
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or(INVALID_API_KEY)?;
+        let name = self.bots.get(key.trim()).ok_or(INVALID_API_KEY)?;
+
+        Ok(Identity {
+            name: Some(name.to_owned()),
+            bot: true,
+            admin: false,
+        })
+    }
+
+    fn required(&self) -> bool {
+        false
+    }
+
+    fn is_reserved(&self, name: &str) -> bool {
+        self.bots
+            .values()
+            .any(|bot_name| bot_name.eq_ignore_ascii_case(name))
+    }
+}
+
+pub fn valid_command_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_COMMAND_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-')
+}
+
+/// `/deploy api v2` -> `("deploy", "api v2")`.
+pub fn parse_command(text: &str) -> Option<(String, String)> {
+    let rest = text.trim_start().strip_prefix('/')?;
+    let (name, args) = match rest.split_once(char::is_whitespace) {
+        Some((name, args)) => (name, args.trim()),
+        None => (rest, ""),
+    };
+    let name = name.to_lowercase();
+    if !valid_command_name(&name) {
+        return None;
+    }
+    Some((name, args.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tokio_tungstenite::connect_async;
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+    use tokio_tungstenite::tungstenite::http::HeaderValue;
+
+    use super::*;
+    use crate::server::ServerHandle;
+    use crate::test_support::{self, TestClient};
+
+    const API_KEY: &str = "helper-key";
+
+    async fn start() -> ServerHandle {
+        test_support::builder()
+            .auth(ApiKeyAuth::new(vec![BotAccount {
+                name: "helper".to_owned(),
+                api_key: API_KEY.to_owned(),
+            }]))
+            .start()
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn commands_are_parsed_from_slash_messages() {
+        assert_eq!(
+            parse_command("  /Deploy api  v2 "),
+            Some(("deploy".to_owned(), "api  v2".to_owned()))
+        );
+        assert_eq!(
+            parse_command("/ping"),
+            Some(("ping".to_owned(), "".to_owned()))
+        );
+        assert_eq!(parse_command("deploy now"), None);
+        assert_eq!(parse_command("/"), None);
+        assert_eq!(parse_command("/dé ploy"), None);
+
+        assert!(valid_command_name("build_2-x"));
+        assert!(!valid_command_name(""));
+        assert!(!valid_command_name("Deploy"));
+        assert!(!valid_command_name(&"a".repeat(MAX_COMMAND_LEN + 1)));
+    }
+
+    #[tokio::test]
+    async fn bots_need_a_valid_api_key() {
+        let handle = start().await;
+        let mut request = format!("ws://{}", handle.local_addr())
+            .into_client_request()
+            .unwrap();
+        request
+            .headers_mut()
+            .insert(AUTHORIZATION, HeaderValue::from_static("Bearer guess"));
+        assert!(connect_async(request).await.is_err());
+
+        let mut bot = TestClient::with_token(handle.local_addr(), API_KEY).await;
+        bot.send("SetNickname", json!({ "name": "other" })).await;
+        assert_eq!(bot.expect("Error").await["message"], BOT_NAME_FIXED);
+        // nobody else takes the bot's name
+        let mut alice = TestClient::connect(handle.local_addr(), "alice").await;
+        alice.send("SetNickname", json!({ "name": "Helper" })).await;
+        assert_eq!(alice.expect("Error").await["message"], NAME_RESERVED);
+
+        drop((bot, alice));
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn registered_commands_reach_the_bot() {
+        let handle = start().await;
+        let mut bot = TestClient::with_token(handle.local_addr(), API_KEY).await;
+        let mut alice = TestClient::connect(handle.local_addr(), "alice").await;
+        let room_id = alice.create_room("team").await;
+        bot.join_room(room_id).await;
+        alice.drain().await;
+
+        alice
+            .send("RegisterCommands", json!({ "commands": ["deploy"] }))
+            .await;
+        assert_eq!(alice.expect("Error").await["message"], BOTS_ONLY);
+        bot.send("RegisterCommands", json!({ "commands": ["Bad Name"] }))
+            .await;
+        assert_eq!(bot.expect("Error").await["message"], INVALID_COMMAND_NAME);
+        bot.send("RegisterCommands", json!({ "commands": ["/deploy"] }))
+            .await;
+        assert_eq!(
+            bot.expect("CommandsRegistered").await["commands"],
+            json!(["deploy"])
+        );
+
+        let post =
+            json!({ "messageType": "Room", "receiverId": room_id, "message": "/deploy api v2" });
+        alice.send("Message", post).await;
+        let command = bot.expect("BotCommand").await;
+        assert_eq!(command["command"], "deploy");
+        assert_eq!(command["args"], "api v2");
+        assert_eq!(command["sender"]["name"], "alice");
+        // the room does not see the command
+        assert!(alice.drain().await.is_empty());
+
+        drop((bot, alice));
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn bots_rejoin_their_rooms_after_reconnecting() {
+        let handle = start().await;
+        let mut alice = TestClient::connect(handle.local_addr(), "alice").await;
+        let room_id = alice.create_room("team").await;
+        let mut bot = TestClient::with_token(handle.local_addr(), API_KEY).await;
+        bot.join_room(room_id).await;
+        drop(bot);
+        assert_eq!(alice.expect("MemberLeft").await["user"]["name"], "helper");
+
+        let mut bot = TestClient::with_token(handle.local_addr(), API_KEY).await;
+        let joined = alice.expect("MemberJoined").await;
+        assert_eq!(joined["user"]["name"], "helper");
+        assert_eq!(joined["roomId"], room_id.to_string());
+        // a member again without joining
+        bot.send("GetHistory", json!({ "roomId": room_id })).await;
+        bot.expect("History").await;
+
+        drop((bot, alice));
+        handle.shutdown().await;
+    }
+}
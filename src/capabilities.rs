@@ -0,0 +1,89 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::content::{MAX_ATTACHMENT_SIZE, MAX_TEXT_LEN};
+use crate::history::MAX_ROOM_HISTORY;
+use crate::rate_limit::RateLimit;
+use crate::requests::RequestType;
+use crate::responses::{Limits, ResponseType};
+use crate::uploads::{CHUNK_SIZE, MAX_PENDING_UPLOADS};
+
+pub const SERVER_VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const FEATURE_NOT_DECLARED: &str = "Feature not declared in Hello";
+
+/// Optional parts of the protocol. The server lists the ones it has
+/// enabled, a client lists the ones it understands.
+#[derive(
+    Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(rename_all = "camelCase")]
+pub enum Feature {
+    History,
+    Threads,
+    Reactions,
+    Mentions,
+    Presence,
+    Uploads,
+    RichContent,
+    Auth,
+}
+
+pub const ENABLED_FEATURES: [Feature; 7] = [
+    Feature::History,
+    Feature::Threads,
+    Feature::Reactions,
+    Feature::Mentions,
+    Feature::Presence,
+    Feature::Uploads,
+    Feature::RichContent,
+];
+
+impl Feature {
+    /// The feature a response belongs to, clients that did not declare it
+    /// do not get the response.
+    pub fn of_event(response_type: ResponseType) -> Option<Feature> {
+        match response_type {
+            ResponseType::History => Some(Feature::History),
+            ResponseType::Thread | ResponseType::ThreadReply => Some(Feature::Threads),
+            ResponseType::ReactionsUpdated => Some(Feature::Reactions),
+            ResponseType::Mentioned | ResponseType::UnreadMentions => Some(Feature::Mentions),
+            ResponseType::Online | ResponseType::Offline | ResponseType::PresenceSubscribed => {
+                Some(Feature::Presence)
+            }
+            ResponseType::UploadStarted
+            | ResponseType::UploadCompleted
+            | ResponseType::Download
+            | ResponseType::DownloadChunk => Some(Feature::Uploads),
+            _ => None,
+        }
+    }
+
+    /// The feature a request belongs to. Clients that did not declare it
+    /// get an error instead of a response they would not be sent.
+    pub fn of_request(request_type: RequestType) -> Option<Feature> {
+        match request_type {
+            RequestType::GetHistory => Some(Feature::History),
+            RequestType::GetThread => Some(Feature::Threads),
+            RequestType::AddReaction | RequestType::RemoveReaction => Some(Feature::Reactions),
+            RequestType::GetMentions | RequestType::ClearMentions => Some(Feature::Mentions),
+            RequestType::SubscribePresence | RequestType::UnsubscribePresence => {
+                Some(Feature::Presence)
+            }
+            RequestType::UploadStart | RequestType::UploadChunk | RequestType::Download => {
+                Some(Feature::Uploads)
+            }
+            _ => None,
+        }
+    }
+}
+
+pub fn limits(message_rate: Option<RateLimit>) -> Limits {
+    Limits {
+        max_message_len: MAX_TEXT_LEN,
+        max_attachment_size: MAX_ATTACHMENT_SIZE,
+        upload_chunk_size: CHUNK_SIZE,
+        max_pending_uploads: MAX_PENDING_UPLOADS,
+        max_history: MAX_ROOM_HISTORY,
+        message_rate,
+    }
+}
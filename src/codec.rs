@@ -0,0 +1,430 @@
+use std::sync::{Arc, OnceLock};
+
+use serde::de::{DeserializeOwned, IntoDeserializer};
+use serde::{Deserialize, Serialize};
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::requests::RequestType;
+use crate::responses::{self, ResponseType};
+use crate::types::{serde_error_to_chat_error, ChatError};
+
+pub const JSON_SUBPROTOCOL: &str = "chat.json";
+pub const MSGPACK_SUBPROTOCOL: &str = "chat.msgpack";
+pub const JSON_V2_SUBPROTOCOL: &str = "chat.v2.json";
+pub const MSGPACK_V2_SUBPROTOCOL: &str = "chat.v2.msgpack";
+
+pub const SUPPORTED_PROTOCOL_VERSIONS: [u32; 2] = [1, 2];
+pub const UNSUPPORTED_PROTOCOL_VERSION: &str = "Unsupported protocol version";
+pub const UNKNOWN_REQUEST_TYPE: &str = "Unknown request type";
+
+/// Wire encoding of a connection, picked from the WebSocket subprotocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    // text frames
+    Json,
+    // binary frames
+    MessagePack,
+}
+
+/// Shape of the payload inside a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolVersion {
+    // `{"responseType": "Message", "data": ...}`, json `data` is a json string
+    V1,
+    // `{"type": "message", ...fields}`
+    V2,
+}
+
+impl ProtocolVersion {
+    pub fn from_number(version: u32) -> Result<ProtocolVersion, ChatError> {
+        match version {
+            1 => Ok(ProtocolVersion::V1),
+            2 => Ok(ProtocolVersion::V2),
+            _ => Err(UNSUPPORTED_PROTOCOL_VERSION.to_owned()),
+        }
+    }
+
+    pub fn number(&self) -> u32 {
+        match self {
+            ProtocolVersion::V1 => 1,
+            ProtocolVersion::V2 => 2,
+        }
+    }
+}
+
+impl Codec {
+    pub fn from_subprotocol(name: &str) -> Option<(Codec, ProtocolVersion)> {
+        match name {
+            JSON_SUBPROTOCOL => Some((Codec::Json, ProtocolVersion::V1)),
+            MSGPACK_SUBPROTOCOL => Some((Codec::MessagePack, ProtocolVersion::V1)),
+            JSON_V2_SUBPROTOCOL => Some((Codec::Json, ProtocolVersion::V2)),
+            MSGPACK_V2_SUBPROTOCOL => Some((Codec::MessagePack, ProtocolVersion::V2)),
+            _ => None,
+        }
+    }
+
+    /// Picks the first supported entry of a `Sec-WebSocket-Protocol` header.
+    pub fn negotiate(header: &str) -> Option<(Codec, ProtocolVersion, &str)> {
+        header.split(',').map(str::trim).find_map(|name| {
+            Codec::from_subprotocol(name).map(|(codec, version)| (codec, version, name))
+        })
+    }
+}
+
+/// `GetId` -> `getId`, the v2 `type` tag of a response.
+pub fn response_tag(response_type: ResponseType) -> String {
+    let name = format!("{:?}", response_type);
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) => first.to_lowercase().chain(chars).collect(),
+        None => name,
+    }
+}
+
+/// `getId` -> `GetId`, the request type of a v2 `type` tag.
+pub fn request_type_from_tag(tag: &str) -> Result<RequestType, ChatError> {
+    let mut chars = tag.chars();
+    let name: String = match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => return Err(UNKNOWN_REQUEST_TYPE.to_owned()),
+    };
+    RequestType::deserialize(name.as_str().into_deserializer())
+        .map_err(|_: serde::de::value::Error| UNKNOWN_REQUEST_TYPE.to_owned())
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct NestedResponse<'a, T> {
+    response_type: ResponseType,
+    data: &'a T,
+}
+
+#[derive(Serialize)]
+struct TaggedResponse<'a, T> {
+    #[serde(rename = "type")]
+    tag: String,
+    #[serde(flatten)]
+    data: &'a T,
+}
+
+fn to_msgpack<T: Serialize>(value: &T) -> Result<Vec<u8>, ChatError> {
+    // uuids and dates as strings, the same values json clients see
+    let mut buf = vec![];
+    value
+        .serialize(
+            &mut rmp_serde::Serializer::new(&mut buf)
+                .with_struct_map()
+                .with_human_readable(),
+        )
+        .map_err(|e| e.to_string())?;
+    Ok(buf)
+}
+
+type Encoder = dyn Fn(Codec, ProtocolVersion) -> Result<Message, ChatError> + Send + Sync;
+type ToValue = dyn Fn() -> Result<serde_json::Value, ChatError> + Send + Sync;
+
+/// A response that is encoded lazily, once per codec and protocol version,
+/// so a broadcast serializes the payload at most once per wire format.
+pub struct Envelope {
+    pub response_type: ResponseType,
+    encoder: Box<Encoder>,
+    to_value: Box<ToValue>,
+    encoded: [OnceLock<Message>; 4],
+}
+
+impl Envelope {
+    pub fn new<T: Serialize + Send + Sync + 'static>(response_type: ResponseType, data: T) -> Self {
+        let data = Arc::new(data);
+        let value_data = Arc::clone(&data);
+        let encoder = move |codec: Codec, version: ProtocolVersion| match (codec, version) {
+            (Codec::Json, ProtocolVersion::V1) => Ok(Message::Text(
+                serde_json::to_string(&responses::Response {
+                    response_type,
+                    data: serde_json::to_string(&*data).map_err(serde_error_to_chat_error)?,
+                })
+                .map_err(serde_error_to_chat_error)?,
+            )),
+            (Codec::MessagePack, ProtocolVersion::V1) => {
+                Ok(Message::Binary(to_msgpack(&NestedResponse {
+                    response_type,
+                    data: &*data,
+                })?))
+            }
+            (Codec::Json, ProtocolVersion::V2) => Ok(Message::Text(
+                serde_json::to_string(&TaggedResponse {
+                    tag: response_tag(response_type),
+                    data: &*data,
+                })
+                .map_err(serde_error_to_chat_error)?,
+            )),
+            (Codec::MessagePack, ProtocolVersion::V2) => {
+                Ok(Message::Binary(to_msgpack(&TaggedResponse {
+                    tag: response_tag(response_type),
+                    data: &*data,
+                })?))
+            }
+        };
+
+        Self {
+            response_type,
+            encoder: Box::new(encoder),
+            to_value: Box::new(move || {
+                serde_json::to_value(&*value_data).map_err(serde_error_to_chat_error)
+            }),
+            encoded: Default::default(),
+        }
+    }
+
+    /// The payload as a json value, for passing the response on.
+    pub fn data(&self) -> Result<serde_json::Value, ChatError> {
+        (self.to_value)()
+    }
+
+    pub fn encode(&self, codec: Codec, version: ProtocolVersion) -> Result<Message, ChatError> {
+        let cell = &self.encoded[codec as usize * 2 + version as usize];
+        if let Some(message) = cell.get() {
+            return Ok(message.clone());
+        }
+
+        let message = (self.encoder)(codec, version)?;
+        let _ = cell.set(message.clone());
+        Ok(message)
+    }
+}
+
+/// Payload of a request, still in the encoding it arrived in.
+pub trait RequestData {
+    fn parse<T: DeserializeOwned>(self) -> Result<T, ChatError>;
+}
+
+// a v1 `data` string or a whole v2 frame, both are the json of the payload
+impl RequestData for &str {
+    fn parse<T: DeserializeOwned>(self) -> Result<T, ChatError> {
+        serde_json::from_str::<T>(self).map_err(serde_error_to_chat_error)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(bound = "T: DeserializeOwned")]
+struct NestedData<T> {
+    data: T,
+}
+
+/// A whole messagepack request frame, decoded again once the request type
+/// is known. v1 frames keep the payload under `data`, v2 frames inline it.
+pub struct MsgPackData<'a> {
+    pub bytes: &'a [u8],
+    pub version: ProtocolVersion,
+}
+
+pub fn from_msgpack<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, ChatError> {
+    T::deserialize(&mut rmp_serde::Deserializer::from_read_ref(bytes).with_human_readable())
+        .map_err(|e| e.to_string())
+}
+
+impl RequestData for MsgPackData<'_> {
+    fn parse<T: DeserializeOwned>(self) -> Result<T, ChatError> {
+        match self.version {
+            ProtocolVersion::V1 => Ok(from_msgpack::<NestedData<T>>(self.bytes)?.data),
+            ProtocolVersion::V2 => from_msgpack::<T>(self.bytes),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+    use serde_json::{json, Value};
+    use tokio::net::TcpStream;
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+    use tokio_tungstenite::tungstenite::http::header::{HeaderValue, SEC_WEBSOCKET_PROTOCOL};
+    use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::server::ServerHandle;
+    use crate::test_support;
+
+    type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    /// Connects offering a subprotocol, returns the one the server accepted.
+    async fn open(handle: &ServerHandle, offered: &str) -> (Socket, Option<String>) {
+        let mut request = format!("ws://{}", handle.local_addr())
+            .into_client_request()
+            .unwrap();
+        request.headers_mut().insert(
+            SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_str(offered).unwrap(),
+        );
+        let (socket, response) = connect_async(request).await.unwrap();
+        let accepted = response
+            .headers()
+            .get(SEC_WEBSOCKET_PROTOCOL)
+            .map(|value| value.to_str().unwrap().to_owned());
+        (socket, accepted)
+    }
+
+    /// Sends a request in the codec and protocol version of the connection.
+    async fn send(
+        socket: &mut Socket,
+        codec: Codec,
+        version: ProtocolVersion,
+        request_type: &str,
+        data: Value,
+    ) {
+        let frame = match version {
+            ProtocolVersion::V1 => match codec {
+                Codec::Json => json!({ "requestType": request_type, "data": data.to_string() }),
+                Codec::MessagePack => json!({ "requestType": request_type, "data": data }),
+            },
+            ProtocolVersion::V2 => {
+                let mut frame = data;
+                frame["type"] = json!(tag_of(request_type));
+                frame
+            }
+        };
+        let message = match codec {
+            Codec::Json => Message::Text(frame.to_string()),
+            Codec::MessagePack => Message::Binary(to_msgpack(&frame).unwrap()),
+        };
+        socket.send(message).await.unwrap();
+    }
+
+    /// `SetNickname` -> `setNickname`
+    fn tag_of(type_name: &str) -> String {
+        let mut chars = type_name.chars();
+        let first = chars.next().unwrap();
+        first.to_lowercase().chain(chars).collect()
+    }
+
+    /// Skips responses until one of the type arrives, returns its payload.
+    async fn expect(
+        socket: &mut Socket,
+        codec: Codec,
+        version: ProtocolVersion,
+        response_type: &str,
+    ) -> Value {
+        loop {
+            let frame: Value = match (codec, socket.next().await.unwrap().unwrap()) {
+                (Codec::Json, Message::Text(text)) => serde_json::from_str(&text).unwrap(),
+                (Codec::MessagePack, Message::Binary(bytes)) => from_msgpack(&bytes).unwrap(),
+                (_, message) => panic!("unexpected frame {:?}", message),
+            };
+            match version {
+                ProtocolVersion::V1 if frame["responseType"] == response_type => {
+                    return match &frame["data"] {
+                        // json v1 carries the payload as a json string
+                        Value::String(data) => serde_json::from_str(data).unwrap(),
+                        data => data.clone(),
+                    };
+                }
+                ProtocolVersion::V2 if frame["type"] == tag_of(response_type) => {
+                    return frame;
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    async fn round_trip(offered: &str, codec: Codec, version: ProtocolVersion) -> Option<String> {
+        let handle = test_support::builder().start().await.unwrap();
+        let (mut socket, accepted) = open(&handle, offered).await;
+
+        send(
+            &mut socket,
+            codec,
+            version,
+            "SetNickname",
+            json!({ "name": "alice" }),
+        )
+        .await;
+        send(
+            &mut socket,
+            codec,
+            version,
+            "CreateRoom",
+            json!({ "name": "team" }),
+        )
+        .await;
+        let room = expect(&mut socket, codec, version, "RoomCreated").await;
+        assert_eq!(room["name"], "team");
+        assert!(Uuid::parse_str(room["id"].as_str().unwrap()).is_ok());
+
+        drop(socket);
+        handle.shutdown().await;
+        accepted
+    }
+
+    #[tokio::test]
+    async fn json_and_msgpack_round_trip() {
+        let accepted = round_trip(JSON_SUBPROTOCOL, Codec::Json, ProtocolVersion::V1).await;
+        assert_eq!(accepted.as_deref(), Some(JSON_SUBPROTOCOL));
+        let accepted =
+            round_trip(MSGPACK_SUBPROTOCOL, Codec::MessagePack, ProtocolVersion::V1).await;
+        assert_eq!(accepted.as_deref(), Some(MSGPACK_SUBPROTOCOL));
+    }
+
+    #[tokio::test]
+    async fn type_tagged_round_trip() {
+        let accepted = round_trip(JSON_V2_SUBPROTOCOL, Codec::Json, ProtocolVersion::V2).await;
+        assert_eq!(accepted.as_deref(), Some(JSON_V2_SUBPROTOCOL));
+        let accepted = round_trip(
+            MSGPACK_V2_SUBPROTOCOL,
+            Codec::MessagePack,
+            ProtocolVersion::V2,
+        )
+        .await;
+        assert_eq!(accepted.as_deref(), Some(MSGPACK_V2_SUBPROTOCOL));
+    }
+
+    #[tokio::test]
+    async fn hello_switches_to_type_tagged_frames() {
+        let handle = test_support::builder().start().await.unwrap();
+        let (mut socket, _) = open(&handle, JSON_SUBPROTOCOL).await;
+
+        let hello = json!({ "protocolVersion": 2 });
+        send(
+            &mut socket,
+            Codec::Json,
+            ProtocolVersion::V1,
+            "Hello",
+            hello,
+        )
+        .await;
+        send(
+            &mut socket,
+            Codec::Json,
+            ProtocolVersion::V2,
+            "SetNickname",
+            json!({ "name": "alice" }),
+        )
+        .await;
+        send(
+            &mut socket,
+            Codec::Json,
+            ProtocolVersion::V2,
+            "CreateRoom",
+            json!({ "name": "team" }),
+        )
+        .await;
+        let room = expect(&mut socket, Codec::Json, ProtocolVersion::V2, "RoomCreated").await;
+        assert_eq!(room["name"], "team");
+
+        drop(socket);
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn unknown_subprotocols_fall_back_to_json_v1() {
+        let accepted = round_trip("chat.xml", Codec::Json, ProtocolVersion::V1).await;
+        assert_eq!(accepted, None);
+        // the first supported entry of the list wins
+        let accepted = round_trip(
+            "chat.xml, chat.msgpack, chat.json",
+            Codec::MessagePack,
+            ProtocolVersion::V1,
+        )
+        .await;
+        assert_eq!(accepted.as_deref(), Some(MSGPACK_SUBPROTOCOL));
+    }
+}
@@ -0,0 +1,466 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use uuid::Uuid;
+
+use crate::announcements::system_message;
+use crate::codec::Envelope;
+use crate::requests::{self, MessageType, Request};
+use crate::responses::{self, ResponseType};
+use crate::room_manager::ROOM_NOT_FOUND;
+use crate::server::{ServerState, CLIENT_NOT_FOUND};
+use crate::service::{member_event, room_members, MemberEvent};
+use crate::types::ChatError;
+
+pub const USER_NOT_IN_ROOM: &str = "No such user in this room";
+pub const CANNOT_KICK_SELF: &str = "Use /leave to leave the room";
+pub const CANNOT_KICK_OWNER: &str = "The room owner cannot be removed";
+pub const UNKNOWN_COMMAND: &str =
+    "Unknown command, start with // to send a message beginning with /";
+
+/// Who may run a command, checked before it runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    Member,
+    // the owner or a moderator
+    RoomModerator,
+    RoomOwner,
+}
+
+/// A slash command sent as a room message.
+pub struct CommandContext {
+    pub conn_id: Uuid,
+    pub name: Option<String>,
+    pub room_id: Uuid,
+    // everything after the command name, trimmed
+    pub args: String,
+    pub state: ServerState,
+}
+
+pub enum CommandOutcome {
+    // handled as if the invoker had sent this request
+    Request(Request),
+    // an ephemeral system message only the invoker sees
+    Reply(String),
+    // the command already sent whatever it had to
+    Done,
+}
+
+#[async_trait]
+pub trait Command: Send + Sync {
+    /// Lowercase, without the `/`.
+    fn name(&self) -> &str;
+
+    /// Shown when the command is used wrong, e.g. `/kick <name>`.
+    fn usage(&self) -> &str;
+
+    fn permission(&self) -> Permission {
+        Permission::Member
+    }
+
+    async fn run(&self, ctx: &CommandContext) -> Result<CommandOutcome, ChatError>;
+}
+
+/// Commands by name, the built-in ones plus those added with
+/// `ServerBuilder::command`, which replace built-ins of the same name.
+#[derive(Clone)]
+pub struct CommandRegistry {
+    commands: HashMap<String, Arc<dyn Command>>,
+}
+
+impl CommandRegistry {
+    pub fn new() -> Self {
+        let mut registry = Self {
+            commands: HashMap::new(),
+        };
+        registry.register(Arc::new(Nick));
+        registry.register(Arc::new(Join));
+        registry.register(Arc::new(Leave));
+        registry.register(Arc::new(Me));
+        registry.register(Arc::new(Topic));
+        registry.register(Arc::new(Kick));
+        registry.register(Arc::new(Mod));
+        registry.register(Arc::new(Unmod));
+        registry.register(Arc::new(Who));
+        registry
+    }
+
+    pub fn register(&mut self, command: Arc<dyn Command>) {
+        self.commands.insert(command.name().to_lowercase(), command);
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn Command>> {
+        self.commands.get(name).cloned()
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.commands.keys().cloned().collect();
+        names.sort();
+        names
+    }
+}
+
+impl Default for CommandRegistry {
+    fn default() -> Self {
+        CommandRegistry::new()
+    }
+}
+
+fn usage_error(command: &dyn Command) -> ChatError {
+    format!("Usage: {}", command.usage())
+}
+
+/// The room member called `name`.
+async fn find_member(ctx: &CommandContext, name: &str) -> Result<responses::UserInfo, ChatError> {
+    let members = ctx
+        .state
+        .room_manager
+        .lock()
+        .await
+        .members(&ctx.room_id)
+        .await?;
+    let lock_connections = ctx.state.clients.lock().await;
+    members
+        .iter()
+        .filter_map(|id| lock_connections.get(id))
+        .find(|connection| {
+            connection
+                .name
+                .as_ref()
+                .is_some_and(|member| member.eq_ignore_ascii_case(name))
+        })
+        .map(|connection| connection.user_info())
+        .ok_or_else(|| USER_NOT_IN_ROOM.to_owned())
+}
+
+struct Nick;
+
+#[async_trait]
+impl Command for Nick {
+    fn name(&self) -> &str {
+        "nick"
+    }
+
+    fn usage(&self) -> &str {
+        "/nick <name>"
+    }
+
+    async fn run(&self, ctx: &CommandContext) -> Result<CommandOutcome, ChatError> {
+        if ctx.args.is_empty() {
+            return Err(usage_error(self));
+        }
+        Ok(CommandOutcome::Request(Request::SetNickname(
+            requests::SetNickname {
+                name: ctx.args.to_owned(),
+            },
+        )))
+    }
+}
+
+struct Join;
+
+#[async_trait]
+impl Command for Join {
+    fn name(&self) -> &str {
+        "join"
+    }
+
+    fn usage(&self) -> &str {
+        "/join <room name or id>"
+    }
+
+    async fn run(&self, ctx: &CommandContext) -> Result<CommandOutcome, ChatError> {
+        if ctx.args.is_empty() {
+            return Err(usage_error(self));
+        }
+        let room_id = match Uuid::parse_str(&ctx.args) {
+            Ok(room_id) => room_id,
+            Err(_) => ctx
+                .state
+                .room_manager
+                .lock()
+                .await
+                .find_by_name(&ctx.args)
+                .await
+                .ok_or(ROOM_NOT_FOUND)?,
+        };
+        Ok(CommandOutcome::Request(Request::JoinRoom(
+            requests::JoinRoom {
+                id: room_id.to_string(),
+            },
+        )))
+    }
+}
+
+struct Leave;
+
+#[async_trait]
+impl Command for Leave {
+    fn name(&self) -> &str {
+        "leave"
+    }
+
+    fn usage(&self) -> &str {
+        "/leave"
+    }
+
+    async fn run(&self, ctx: &CommandContext) -> Result<CommandOutcome, ChatError> {
+        Ok(CommandOutcome::Request(Request::LeaveRoom(
+            requests::LeaveRoom {
+                room_id: ctx.room_id,
+            },
+        )))
+    }
+}
+
+struct Me;
+
+#[async_trait]
+impl Command for Me {
+    fn name(&self) -> &str {
+        "me"
+    }
+
+    fn usage(&self) -> &str {
+        "/me <action>"
+    }
+
+    async fn run(&self, ctx: &CommandContext) -> Result<CommandOutcome, ChatError> {
+        if ctx.args.is_empty() {
+            return Err(usage_error(self));
+        }
+        let name = ctx.name.clone().unwrap_or_default();
+        Ok(CommandOutcome::Request(Request::Message(
+            requests::Message {
+                message_type: MessageType::Room,
+                receiver_id: ctx.room_id,
+                message: format!("* {} {}", name, ctx.args),
+                content: None,
+                reply_to: None,
+            },
+        )))
+    }
+}
+
+struct Topic;
+
+#[async_trait]
+impl Command for Topic {
+    fn name(&self) -> &str {
+        "topic"
+    }
+
+    fn usage(&self) -> &str {
+        "/topic [new topic]"
+    }
+
+    // anyone may read the topic, `UpdateRoom` checks who may change it
+    async fn run(&self, ctx: &CommandContext) -> Result<CommandOutcome, ChatError> {
+        if ctx.args.is_empty() {
+            let room = ctx
+                .state
+                .room_manager
+                .lock()
+                .await
+                .info(&ctx.room_id)
+                .await?;
+            return Ok(CommandOutcome::Reply(match room.topic {
+                Some(topic) => format!("Topic: {}", topic),
+                None => "No topic is set".to_owned(),
+            }));
+        }
+
+        Ok(CommandOutcome::Request(Request::UpdateRoom(
+            requests::UpdateRoom {
+                room_id: ctx.room_id,
+                topic: Some(ctx.args.to_owned()),
+                description: None,
+                avatar: None,
+            },
+        )))
+    }
+}
+
+struct Kick;
+
+#[async_trait]
+impl Command for Kick {
+    fn name(&self) -> &str {
+        "kick"
+    }
+
+    fn usage(&self) -> &str {
+        "/kick <name>"
+    }
+
+    fn permission(&self) -> Permission {
+        Permission::RoomModerator
+    }
+
+    async fn run(&self, ctx: &CommandContext) -> Result<CommandOutcome, ChatError> {
+        if ctx.args.is_empty() {
+            return Err(usage_error(self));
+        }
+        let target = find_member(ctx, &ctx.args).await?;
+        if target.id == ctx.conn_id {
+            return Err(CANNOT_KICK_SELF.to_owned());
+        }
+        if ctx
+            .state
+            .room_manager
+            .lock()
+            .await
+            .is_owner(&ctx.room_id, &target.id)
+            .await?
+        {
+            return Err(CANNOT_KICK_OWNER.to_owned());
+        }
+
+        let room_info = ctx
+            .state
+            .room_manager
+            .lock()
+            .await
+            .leave(&ctx.room_id, &target.id)
+            .await?;
+        ctx.state
+            .presence
+            .lock()
+            .await
+            .leave_room(&target.id, &ctx.room_id);
+
+        let by = {
+            let lock_connections = &mut ctx.state.clients.lock().await;
+            let by = lock_connections
+                .get(&ctx.conn_id)
+                .ok_or(CLIENT_NOT_FOUND)?
+                .user_info();
+            if let Some(connection) = lock_connections.get_mut(&target.id) {
+                let notice = format!("You were removed from {} by {}", room_info.name, by.name);
+                let _ = connection
+                    .send(&system_message(Some(ctx.room_id), notice, true))
+                    .await;
+                let _ = connection
+                    .send(&Envelope::new(
+                        ResponseType::RoomLeft,
+                        responses::RoomLeft {
+                            room_id: ctx.room_id,
+                        },
+                    ))
+                    .await;
+            }
+            by
+        };
+
+        member_event(
+            ctx.room_id,
+            target,
+            MemberEvent::Removed(by),
+            Arc::clone(&ctx.state.room_manager),
+            Arc::clone(&ctx.state.history),
+        )
+        .await?;
+        Ok(CommandOutcome::Done)
+    }
+}
+
+/// Appoints or removes a moderator and tells the room.
+async fn set_moderator(ctx: &CommandContext, moderator: bool) -> Result<CommandOutcome, ChatError> {
+    let target = find_member(ctx, &ctx.args).await?;
+    let room_manager = &mut ctx.state.room_manager.lock().await;
+    let room = room_manager
+        .set_moderator(&ctx.room_id, &target.id, moderator)
+        .await?;
+    room_manager
+        .all(
+            &ctx.room_id,
+            &Envelope::new(
+                ResponseType::RoomUpdated,
+                responses::RoomUpdated {
+                    room,
+                    updated_by: ctx.conn_id,
+                },
+            ),
+        )
+        .await?;
+    Ok(CommandOutcome::Done)
+}
+
+struct Mod;
+
+#[async_trait]
+impl Command for Mod {
+    fn name(&self) -> &str {
+        "mod"
+    }
+
+    fn usage(&self) -> &str {
+        "/mod <name>"
+    }
+
+    fn permission(&self) -> Permission {
+        Permission::RoomOwner
+    }
+
+    async fn run(&self, ctx: &CommandContext) -> Result<CommandOutcome, ChatError> {
+        if ctx.args.is_empty() {
+            return Err(usage_error(self));
+        }
+        set_moderator(ctx, true).await
+    }
+}
+
+struct Unmod;
+
+#[async_trait]
+impl Command for Unmod {
+    fn name(&self) -> &str {
+        "unmod"
+    }
+
+    fn usage(&self) -> &str {
+        "/unmod <name>"
+    }
+
+    fn permission(&self) -> Permission {
+        Permission::RoomOwner
+    }
+
+    async fn run(&self, ctx: &CommandContext) -> Result<CommandOutcome, ChatError> {
+        if ctx.args.is_empty() {
+            return Err(usage_error(self));
+        }
+        set_moderator(ctx, false).await
+    }
+}
+
+struct Who;
+
+#[async_trait]
+impl Command for Who {
+    fn name(&self) -> &str {
+        "who"
+    }
+
+    fn usage(&self) -> &str {
+        "/who"
+    }
+
+    async fn run(&self, ctx: &CommandContext) -> Result<CommandOutcome, ChatError> {
+        let members =
+            room_members(&ctx.room_id, &ctx.state.clients, &ctx.state.room_manager).await?;
+        let members_count = members.len();
+        let names: Vec<String> = members
+            .into_iter()
+            .map(|member| member.name)
+            .filter(|name| !name.is_empty())
+            .collect();
+
+        Ok(CommandOutcome::Reply(format!(
+            "In this room ({}): {}",
+            members_count,
+            names.join(", ")
+        )))
+    }
+}
@@ -0,0 +1,54 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::rate_limit::RateLimit;
+
+pub const DEFAULT_UPLOAD_DIR: &str = "uploads";
+pub const DEFAULT_REQUEST_BUFFER: usize = 32;
+// nodes send a heartbeat three times per lease
+pub const DEFAULT_PRESENCE_LEASE: Duration = Duration::from_secs(15);
+pub const DEFAULT_MESSAGE_RATE: RateLimit = RateLimit {
+    burst: 20,
+    per_second: 5.0,
+};
+
+/// Settings of a server that are not pluggable implementations.
+#[derive(Debug, Clone)]
+pub struct Config {
+    // side HTTP endpoints (schema, ...), not served when `None`
+    pub http_addr: Option<String>,
+    // admin HTTP API, needs `admin_token`, not served when `None`
+    pub admin_addr: Option<String>,
+    // used by the default local attachment storage
+    pub upload_dir: PathBuf,
+    // requests queued from all connections before readers wait
+    pub request_buffer: usize,
+    // messages per connection, unlimited when `None`
+    pub message_rate: Option<RateLimit>,
+    pub bot_message_rate: Option<RateLimit>,
+    // websocket and admin API clients send it as a bearer token, there are
+    // no admins when `None`
+    pub admin_token: Option<String>,
+    // message text and other request contents in debug logs, off so chat
+    // content stays out of the logs
+    pub log_request_contents: bool,
+    // how long the users of another node count as connected without a
+    // heartbeat from it
+    pub presence_lease: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            http_addr: None,
+            admin_addr: None,
+            upload_dir: PathBuf::from(DEFAULT_UPLOAD_DIR),
+            request_buffer: DEFAULT_REQUEST_BUFFER,
+            message_rate: Some(DEFAULT_MESSAGE_RATE),
+            bot_message_rate: None,
+            admin_token: None,
+            log_request_contents: false,
+            presence_lease: DEFAULT_PRESENCE_LEASE,
+        }
+    }
+}
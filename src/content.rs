@@ -0,0 +1,232 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::types::ChatError;
+
+pub const MAX_TEXT_LEN: usize = 4000;
+pub const MAX_LANGUAGE_LEN: usize = 32;
+pub const MAX_FILE_NAME_LEN: usize = 255;
+pub const MAX_ATTACHMENT_SIZE: u64 = 25 * 1024 * 1024;
+
+pub const EMPTY_MESSAGE: &str = "Message is empty";
+pub const MESSAGE_TOO_LONG: &str = "Message is too long";
+pub const INVALID_LANGUAGE: &str = "Invalid code language";
+pub const INVALID_FILE_NAME: &str = "Invalid attachment file name";
+pub const INVALID_MIME_TYPE: &str = "Invalid attachment mime type";
+pub const INVALID_ATTACHMENT_SIZE: &str = "Invalid attachment size";
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(tag = "contentType", rename_all = "camelCase")]
+pub enum MessageContent {
+    Plain {
+        text: String,
+    },
+    Markdown {
+        text: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    Code {
+        text: String,
+        language: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    Attachment {
+        attachment_id: Uuid,
+        file_name: String,
+        mime_type: String,
+        size: u64,
+    },
+}
+
+fn validate_text(text: &str) -> Result<(), ChatError> {
+    if text.trim().is_empty() {
+        return Err(EMPTY_MESSAGE.to_owned());
+    }
+    if text.chars().count() > MAX_TEXT_LEN {
+        return Err(MESSAGE_TOO_LONG.to_owned());
+    }
+    Ok(())
+}
+
+fn validate_language(language: &str) -> Result<(), ChatError> {
+    let valid = !language.is_empty()
+        && language.len() <= MAX_LANGUAGE_LEN
+        && language
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+#._-".contains(c));
+    if !valid {
+        return Err(INVALID_LANGUAGE.to_owned());
+    }
+    Ok(())
+}
+
+fn validate_file_name(file_name: &str) -> Result<(), ChatError> {
+    let valid = !file_name.trim().is_empty()
+        && file_name.len() <= MAX_FILE_NAME_LEN
+        && !file_name.contains(|c: char| c == '/' || c == '\\' || c.is_control());
+    if !valid {
+        return Err(INVALID_FILE_NAME.to_owned());
+    }
+    Ok(())
+}
+
+fn validate_mime_type(mime_type: &str) -> Result<(), ChatError> {
+    let is_token = |part: &str| {
+        !part.is_empty()
+            && part
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "!#$&^_.+-".contains(c))
+    };
+    match mime_type.split_once('/') {
+        Some((kind, subtype)) if is_token(kind) && is_token(subtype) => Ok(()),
+        _ => Err(INVALID_MIME_TYPE.to_owned()),
+    }
+}
+
+impl MessageContent {
+    pub fn validate(&self) -> Result<(), ChatError> {
+        match self {
+            MessageContent::Plain { text } | MessageContent::Markdown { text } => {
+                validate_text(text)
+            }
+            MessageContent::Code { text, language } => {
+                validate_text(text)?;
+                validate_language(language)
+            }
+            MessageContent::Attachment {
+                file_name,
+                mime_type,
+                size,
+                ..
+            } => {
+                validate_file_name(file_name)?;
+                validate_mime_type(mime_type)?;
+                if *size == 0 || *size > MAX_ATTACHMENT_SIZE {
+                    return Err(INVALID_ATTACHMENT_SIZE.to_owned());
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Plain-text rendering for clients that ignore `contentType`.
+    pub fn fallback_text(&self) -> String {
+        match self {
+            MessageContent::Plain { text }
+            | MessageContent::Markdown { text }
+            | MessageContent::Code { text, .. } => text.to_owned(),
+            MessageContent::Attachment { file_name, .. } => file_name.to_owned(),
+        }
+    }
+
+    /// Text that may carry `@mentions`, code blocks and attachments never do.
+    pub fn mention_text(&self) -> Option<&str> {
+        match self {
+            MessageContent::Plain { text } | MessageContent::Markdown { text } => Some(text),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_support::{self, TestClient};
+
+    fn code(language: &str) -> MessageContent {
+        MessageContent::Code {
+            text: "fn main() {}".to_owned(),
+            language: language.to_owned(),
+        }
+    }
+
+    fn attachment(file_name: &str, mime_type: &str, size: u64) -> MessageContent {
+        MessageContent::Attachment {
+            attachment_id: Uuid::new_v4(),
+            file_name: file_name.to_owned(),
+            mime_type: mime_type.to_owned(),
+            size,
+        }
+    }
+
+    #[test]
+    fn text_must_be_present_and_short_enough() {
+        let plain = |text: String| MessageContent::Plain { text }.validate();
+        assert_eq!(plain("hi".to_owned()), Ok(()));
+        assert_eq!(plain(" \n ".to_owned()), Err(EMPTY_MESSAGE.to_owned()));
+        assert_eq!(plain("é".repeat(MAX_TEXT_LEN)), Ok(()));
+        assert_eq!(
+            plain("x".repeat(MAX_TEXT_LEN + 1)),
+            Err(MESSAGE_TOO_LONG.to_owned())
+        );
+        let markdown = MessageContent::Markdown {
+            text: "x".repeat(MAX_TEXT_LEN + 1),
+        };
+        assert_eq!(markdown.validate(), Err(MESSAGE_TOO_LONG.to_owned()));
+    }
+
+    #[test]
+    fn code_needs_a_sane_language() {
+        assert_eq!(code("c++").validate(), Ok(()));
+        for language in ["", "rust; rm -rf", &"x".repeat(MAX_LANGUAGE_LEN + 1)] {
+            assert_eq!(code(language).validate(), Err(INVALID_LANGUAGE.to_owned()));
+        }
+    }
+
+    #[test]
+    fn attachments_are_checked_field_by_field() {
+        assert_eq!(attachment("cat.png", "image/png", 10).validate(), Ok(()));
+        for file_name in ["", "../etc/passwd", "a\\b", "a\nb"] {
+            assert_eq!(
+                attachment(file_name, "image/png", 10).validate(),
+                Err(INVALID_FILE_NAME.to_owned())
+            );
+        }
+        for mime_type in ["", "image", "image/", "image/png; x=1"] {
+            assert_eq!(
+                attachment("cat.png", mime_type, 10).validate(),
+                Err(INVALID_MIME_TYPE.to_owned())
+            );
+        }
+        for size in [0, MAX_ATTACHMENT_SIZE + 1] {
+            assert_eq!(
+                attachment("cat.png", "image/png", size).validate(),
+                Err(INVALID_ATTACHMENT_SIZE.to_owned())
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_messages_are_not_delivered() {
+        let handle = test_support::builder().start().await.unwrap();
+        let mut alice = TestClient::connect(handle.local_addr(), "alice").await;
+        let mut bob = TestClient::connect(handle.local_addr(), "bob").await;
+        let room_id = alice.create_room("team").await;
+        bob.join_room(room_id).await;
+        alice.drain().await;
+
+        let too_long = json!({
+            "messageType": "Room",
+            "receiverId": room_id,
+            "message": "x".repeat(MAX_TEXT_LEN + 1),
+        });
+        alice.send("Message", too_long).await;
+        assert_eq!(alice.expect("Error").await["message"], MESSAGE_TOO_LONG);
+
+        let bad_code = json!({
+            "messageType": "Room",
+            "receiverId": room_id,
+            "content": { "contentType": "code", "text": "ls", "language": "sh; ls" },
+        });
+        alice.send("Message", bad_code).await;
+        assert_eq!(alice.expect("Error").await["message"], INVALID_LANGUAGE);
+
+        assert!(bob.drain().await.is_empty());
+
+        drop((alice, bob));
+        handle.shutdown().await;
+    }
+}
@@ -0,0 +1,248 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use serde::Serialize;
+
+use crate::http::HttpResponse;
+use crate::server::ServerState;
+
+/// The long running tasks of a server that liveness and readiness depend on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Task {
+    // the accept loop of `Server::start_listen`
+    Listener,
+    // the request loop of `Server::start_receiver`
+    Receiver,
+    // the subscriber of `Server::start_backplane`, clustered servers only
+    Backplane,
+    // the snapshot loop of `Server::start_room_store`, with a room store only
+    RoomStore,
+}
+
+/// What `/healthz` (or `/livez`) and `/readyz` report on.
+#[derive(Debug, Default)]
+pub struct Health {
+    listener: AtomicBool,
+    receiver: AtomicBool,
+    backplane: AtomicBool,
+    room_store: AtomicBool,
+    draining: AtomicBool,
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Health::default()
+    }
+
+    fn flag(&self, task: Task) -> &AtomicBool {
+        match task {
+            Task::Listener => &self.listener,
+            Task::Receiver => &self.receiver,
+            Task::Backplane => &self.backplane,
+            Task::RoomStore => &self.room_store,
+        }
+    }
+
+    /// Marks the task as running until the guard is dropped, which also
+    /// happens when the task panics or is aborted.
+    pub fn running(self: &Arc<Self>, task: Task) -> TaskGuard {
+        self.flag(task).store(true, Ordering::SeqCst);
+        TaskGuard {
+            health: Arc::clone(self),
+            task,
+        }
+    }
+
+    pub fn is_running(&self, task: Task) -> bool {
+        self.flag(task).load(Ordering::SeqCst)
+    }
+
+    /// Set once shutdown begins, the server stops being ready.
+    pub fn start_draining(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// The accept loop stops on purpose while draining.
+    pub fn is_live(&self) -> bool {
+        self.is_running(Task::Receiver) && (self.is_running(Task::Listener) || self.is_draining())
+    }
+}
+
+pub struct TaskGuard {
+    health: Arc<Health>,
+    task: Task,
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        self.health.flag(self.task).store(false, Ordering::SeqCst);
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Liveness {
+    status: &'static str,
+    listener: bool,
+    receiver: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Readiness {
+    status: &'static str,
+    live: bool,
+    // `ok` or the error of the storage check
+    storage: String,
+    // whether the task runs, `None` when the server has none
+    backplane: Option<bool>,
+    room_store: Option<bool>,
+    draining: bool,
+}
+
+fn json<T: Serialize>(ok: bool, body: &T) -> HttpResponse {
+    let status = if ok { 200 } else { 503 };
+    HttpResponse::json(status, serde_json::to_vec(body).unwrap_or_default())
+}
+
+pub(crate) fn healthz(state: &ServerState) -> HttpResponse {
+    let health = &state.health;
+    let live = health.is_live();
+    json(
+        live,
+        &Liveness {
+            status: if live { "ok" } else { "failing" },
+            listener: health.is_running(Task::Listener),
+            receiver: health.is_running(Task::Receiver),
+        },
+    )
+}
+
+pub(crate) async fn readyz(state: &ServerState) -> HttpResponse {
+    let health = &state.health;
+    let storage = state.uploads.lock().await.check_storage();
+    let live = health.is_live();
+    let draining = health.is_draining();
+    let backplane = state
+        .cluster
+        .is_clustered()
+        .then(|| health.is_running(Task::Backplane));
+    let room_store = state
+        .room_store
+        .is_some()
+        .then(|| health.is_running(Task::RoomStore));
+    let ready = live
+        && !draining
+        && storage.is_ok()
+        && backplane != Some(false)
+        && room_store != Some(false);
+    json(
+        ready,
+        &Readiness {
+            status: if ready { "ready" } else { "not ready" },
+            live,
+            storage: match storage {
+                Ok(()) => "ok".to_owned(),
+                Err(err) => err.to_string(),
+            },
+            backplane,
+            room_store,
+            draining,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use serde_json::Value;
+    use tokio::sync::mpsc;
+    use uuid::Uuid;
+
+    use crate::backplane::{Backplane, ClusterMessage};
+    use crate::config::Config;
+    use crate::room_store::FileRoomStore;
+    use crate::server::{ServerBuilder, ServerHandle};
+    use crate::test_support;
+    use crate::types::ChatError;
+
+    // a backplane whose subscription ends right away
+    struct Gone;
+
+    #[async_trait]
+    impl Backplane for Gone {
+        async fn publish(&self, _message: &ClusterMessage) -> Result<(), ChatError> {
+            Ok(())
+        }
+
+        async fn subscribe(&self) -> Result<mpsc::Receiver<ClusterMessage>, ChatError> {
+            Ok(mpsc::channel(1).1)
+        }
+    }
+
+    async fn start(builder: ServerBuilder) -> ServerHandle {
+        builder
+            .config(Config {
+                http_addr: Some("127.0.0.1:0".to_owned()),
+                ..test_support::config()
+            })
+            .start()
+            .await
+            .unwrap()
+    }
+
+    async fn get(handle: &ServerHandle, path: &str) -> (u16, Value) {
+        let (status, body) =
+            test_support::http(handle.http_addr().unwrap(), "GET", path, None, "").await;
+        (status, serde_json::from_str(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn draining_servers_are_live_but_not_ready() {
+        let handle = start(test_support::builder()).await;
+
+        for path in ["/healthz", "/livez"] {
+            let (status, live) = get(&handle, path).await;
+            assert_eq!(status, 200);
+            assert_eq!(live["listener"], true);
+            assert_eq!(live["receiver"], true);
+        }
+        let (status, ready) = get(&handle, "/readyz").await;
+        assert_eq!(status, 200);
+        assert_eq!(ready["status"], "ready");
+        assert_eq!(ready["backplane"], Value::Null);
+        assert_eq!(ready["roomStore"], Value::Null);
+
+        handle.state().health.start_draining();
+        let (status, ready) = get(&handle, "/readyz").await;
+        assert_eq!(status, 503);
+        assert_eq!(ready["draining"], true);
+        assert_eq!(get(&handle, "/livez").await.0, 200);
+
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn readiness_follows_the_backplane_and_room_store_tasks() {
+        let path = std::env::temp_dir().join(format!("rooms-{}.json", Uuid::new_v4()));
+        let handle = start(test_support::builder().room_store(FileRoomStore::new(&path))).await;
+        let (status, ready) = get(&handle, "/readyz").await;
+        assert_eq!(status, 200);
+        assert_eq!(ready["roomStore"], true);
+        handle.shutdown().await;
+
+        let handle = start(test_support::builder().backplane(Gone)).await;
+        // the subscriber task ends with the subscription
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        let (status, ready) = get(&handle, "/readyz").await;
+        assert_eq!(status, 503);
+        assert_eq!(ready["backplane"], false);
+        assert_eq!(get(&handle, "/livez").await.0, 200);
+        handle.shutdown().await;
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,298 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::content::MessageContent;
+use crate::responses;
+use crate::types::ChatError;
+
+pub const MESSAGE_NOT_FOUND: &str = "Message not found";
+pub const THREAD_ROOM_MISMATCH: &str = "Reply must be sent to the room of the parent message";
+pub const INVALID_EMOJI: &str = "Invalid emoji";
+
+// root messages kept per room, older ones are dropped together with their threads
+pub const MAX_ROOM_HISTORY: usize = 500;
+// messages kept per pair of users talking directly
+pub const MAX_DIRECT_HISTORY: usize = 500;
+pub const MAX_EMOJI_LEN: usize = 32;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct StoredMessage {
+    pub id: Uuid,
+    pub sender_id: Uuid,
+    pub name: String,
+    pub content: MessageContent,
+    pub created_at: DateTime<Utc>,
+    pub room_id: Option<Uuid>,
+    pub receiver_id: Option<Uuid>,
+    pub reply_to: Option<Uuid>,
+    pub reply_count: u32,
+    // emoji -> users who reacted with it
+    pub reactions: BTreeMap<String, BTreeSet<Uuid>>,
+    // added by the server, e.g. for a member joining, about `sender_id`
+    pub system: bool,
+}
+
+impl StoredMessage {
+    pub fn to_response(&self) -> responses::Message {
+        responses::Message {
+            id: self.sender_id,
+            message_id: self.id,
+            name: self.name.to_owned(),
+            message: self.content.fallback_text(),
+            content: self.content.clone(),
+            created_at: self.created_at,
+            room_id: self.room_id,
+            reply_to: self.reply_to,
+            reply_count: self.reply_count,
+            reactions: self.reactions_response(),
+            system: self.system,
+        }
+    }
+
+    pub fn reactions_response(&self) -> Vec<responses::Reaction> {
+        self.reactions
+            .iter()
+            .map(|(emoji, user_ids)| responses::Reaction {
+                emoji: emoji.to_owned(),
+                count: user_ids.len(),
+                user_ids: user_ids.iter().cloned().collect(),
+            })
+            .collect()
+    }
+}
+
+fn is_pictograph(c: char) -> bool {
+    matches!(c as u32,
+        0x00A9 | 0x00AE | 0x203C | 0x2049 | 0x2122 | 0x2139
+        | 0x2194..=0x21AA | 0x231A..=0x23FF | 0x24C2 | 0x25AA..=0x25FE
+        | 0x2600..=0x27BF | 0x2934..=0x2935 | 0x2B05..=0x2B55
+        | 0x3030 | 0x303D | 0x3297 | 0x3299 | 0x1F000..=0x1FAFF)
+}
+
+fn is_regional_indicator(c: char) -> bool {
+    ('\u{1F1E6}'..='\u{1F1FF}').contains(&c)
+}
+
+fn is_skin_tone(c: char) -> bool {
+    ('\u{1F3FB}'..='\u{1F3FF}').contains(&c)
+}
+
+/// Whether the text is one emoji: a pictograph with its presentation
+/// selector, skin tone or tags, a zero width joiner sequence of those, a
+/// flag or a keycap.
+fn is_single_emoji(text: &str) -> bool {
+    let chars: Vec<char> = text.chars().collect();
+    match chars.as_slice() {
+        [a, b] if is_regional_indicator(*a) && is_regional_indicator(*b) => return true,
+        [c, '\u{FE0F}', '\u{20E3}'] | [c, '\u{20E3}']
+            if c.is_ascii_digit() || *c == '#' || *c == '*' =>
+        {
+            return true
+        }
+        _ => {}
+    }
+
+    let mut chars = chars.into_iter().peekable();
+    loop {
+        match chars.next() {
+            Some(c) if is_pictograph(c) && !is_regional_indicator(c) && !is_skin_tone(c) => {}
+            _ => return false,
+        }
+        while let Some(&c) = chars.peek() {
+            if c == '\u{FE0F}' || is_skin_tone(c) || ('\u{E0020}'..='\u{E007F}').contains(&c) {
+                chars.next();
+            } else {
+                break;
+            }
+        }
+        match chars.next() {
+            None => return true,
+            Some('\u{200D}') => continue,
+            Some(_) => return false,
+        }
+    }
+}
+
+fn validate_emoji(emoji: &str) -> Result<(), ChatError> {
+    if emoji.len() > MAX_EMOJI_LEN || !is_single_emoji(emoji) {
+        return Err(INVALID_EMOJI.to_owned());
+    }
+    Ok(())
+}
+
+fn conversation_key(a: Uuid, b: Uuid) -> (Uuid, Uuid) {
+    if a < b {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
+
+pub struct History {
+    messages: HashMap<Uuid, StoredMessage>,
+    // room id -> root messages, oldest first
+    rooms: HashMap<Uuid, VecDeque<Uuid>>,
+    // root message id -> replies, oldest first
+    threads: HashMap<Uuid, Vec<Uuid>>,
+    // ordered pair of user ids -> direct messages, oldest first
+    conversations: HashMap<(Uuid, Uuid), VecDeque<Uuid>>,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History::new()
+    }
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self {
+            messages: HashMap::new(),
+            rooms: HashMap::new(),
+            threads: HashMap::new(),
+            conversations: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, id: &Uuid) -> Result<&StoredMessage, ChatError> {
+        self.messages
+            .get(id)
+            .ok_or_else(|| MESSAGE_NOT_FOUND.to_owned())
+    }
+
+    pub fn add_room_message(&mut self, room_id: Uuid, message: StoredMessage) {
+        let timeline = self.rooms.entry(room_id).or_default();
+        timeline.push_back(message.id);
+        self.messages.insert(message.id, message);
+
+        while timeline.len() > MAX_ROOM_HISTORY {
+            if let Some(evicted_id) = timeline.pop_front() {
+                self.messages.remove(&evicted_id);
+                for reply_id in self.threads.remove(&evicted_id).unwrap_or_default() {
+                    self.messages.remove(&reply_id);
+                }
+            }
+        }
+    }
+
+    pub fn add_direct_message(&mut self, receiver_id: Uuid, message: StoredMessage) {
+        let timeline = self
+            .conversations
+            .entry(conversation_key(message.sender_id, receiver_id))
+            .or_default();
+        timeline.push_back(message.id);
+        self.messages.insert(message.id, message);
+
+        while timeline.len() > MAX_DIRECT_HISTORY {
+            if let Some(evicted_id) = timeline.pop_front() {
+                self.messages.remove(&evicted_id);
+            }
+        }
+    }
+
+    pub fn add_reaction(
+        &mut self,
+        id: &Uuid,
+        user_id: Uuid,
+        emoji: &str,
+    ) -> Result<&StoredMessage, ChatError> {
+        validate_emoji(emoji)?;
+        let message = self.messages.get_mut(id).ok_or(MESSAGE_NOT_FOUND)?;
+        message
+            .reactions
+            .entry(emoji.to_owned())
+            .or_default()
+            .insert(user_id);
+        Ok(message)
+    }
+
+    pub fn remove_reaction(
+        &mut self,
+        id: &Uuid,
+        user_id: &Uuid,
+        emoji: &str,
+    ) -> Result<&StoredMessage, ChatError> {
+        let message = self.messages.get_mut(id).ok_or(MESSAGE_NOT_FOUND)?;
+        if let Some(user_ids) = message.reactions.get_mut(emoji) {
+            user_ids.remove(user_id);
+            if user_ids.is_empty() {
+                message.reactions.remove(emoji);
+            }
+        }
+        Ok(message)
+    }
+
+    /// Stores a reply in the thread of `message.reply_to`. Replies to replies
+    /// are attached to the thread root. Returns the root message.
+    pub fn add_reply(&mut self, mut message: StoredMessage) -> Result<&StoredMessage, ChatError> {
+        let parent_id = message.reply_to.ok_or(MESSAGE_NOT_FOUND)?;
+        let parent = self.get(&parent_id)?;
+        let root_id = parent.reply_to.unwrap_or(parent.id);
+        if parent.room_id.is_none() || parent.room_id != message.room_id {
+            return Err(THREAD_ROOM_MISMATCH.to_owned());
+        }
+
+        message.reply_to = Some(root_id);
+        self.threads.entry(root_id).or_default().push(message.id);
+        self.messages.insert(message.id, message);
+
+        let root = self.messages.get_mut(&root_id).ok_or(MESSAGE_NOT_FOUND)?;
+        root.reply_count += 1;
+        Ok(root)
+    }
+
+    /// Drops every message of a deleted room.
+    pub fn remove_room(&mut self, room_id: &Uuid) {
+        for id in self.rooms.remove(room_id).unwrap_or_default() {
+            self.messages.remove(&id);
+            for reply_id in self.threads.remove(&id).unwrap_or_default() {
+                self.messages.remove(&reply_id);
+            }
+        }
+    }
+
+    pub fn room_history(&self, room_id: &Uuid, limit: usize) -> Vec<&StoredMessage> {
+        let timeline = match self.rooms.get(room_id) {
+            Some(timeline) => timeline,
+            None => return vec![],
+        };
+
+        timeline
+            .iter()
+            .skip(timeline.len().saturating_sub(limit))
+            .filter_map(|id| self.messages.get(id))
+            .collect()
+    }
+
+    pub fn thread(&self, id: &Uuid) -> Result<(&StoredMessage, Vec<&StoredMessage>), ChatError> {
+        let message = self.get(id)?;
+        let root = self.get(&message.reply_to.unwrap_or(message.id))?;
+        let replies = self
+            .threads
+            .get(&root.id)
+            .map(|ids| ids.iter().filter_map(|id| self.messages.get(id)).collect())
+            .unwrap_or_default();
+
+        Ok((root, replies))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reactions_take_a_single_emoji() {
+        for emoji in ["👍", "❤️", "👍🏽", "👩‍💻", "👨‍👩‍👧", "🇫🇷", "1️⃣", "🏴󠁧󠁢󠁳󠁣󠁴󠁿"]
+        {
+            assert!(validate_emoji(emoji).is_ok(), "{} rejected", emoji);
+        }
+        for text in ["", "a", "ok", "👍👍", "👍 ", "🇫", "🏽", "1", "👍a"] {
+            assert!(validate_emoji(text).is_err(), "{:?} accepted", text);
+        }
+    }
+}
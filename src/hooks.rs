@@ -0,0 +1,246 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::codec::Envelope;
+use crate::requests::{self, Request};
+use crate::server::{ServerState, CLIENT_NOT_FOUND};
+use crate::types::ChatError;
+
+/// What a hook sees of the server while it runs. No lock is held, so hooks
+/// may send and read state freely.
+pub struct HookContext {
+    // the connection the event is about
+    pub conn_id: Uuid,
+    pub state: ServerState,
+}
+
+impl HookContext {
+    pub async fn name(&self) -> Option<String> {
+        let lock_connections = self.state.clients.lock().await;
+        lock_connections
+            .get(&self.conn_id)
+            .and_then(|connection| connection.name.clone())
+    }
+
+    pub async fn is_bot(&self) -> bool {
+        let lock_connections = self.state.clients.lock().await;
+        lock_connections
+            .get(&self.conn_id)
+            .is_some_and(|connection| connection.bot)
+    }
+
+    pub async fn send_to(&self, user_id: Uuid, response: &Envelope) -> Result<(), ChatError> {
+        let mut lock_connections = self.state.clients.lock().await;
+        let connection = lock_connections.get_mut(&user_id).ok_or(CLIENT_NOT_FOUND)?;
+        connection.send(response).await
+    }
+
+    /// Sends to every member, muted or not.
+    pub async fn send_to_room(&self, room_id: Uuid, response: &Envelope) -> Result<(), ChatError> {
+        self.state
+            .room_manager
+            .lock()
+            .await
+            .all(&room_id, response)
+            .await
+    }
+
+    pub async fn broadcast(&self, response: &Envelope) {
+        let mut lock_connections = self.state.clients.lock().await;
+        for connection in lock_connections.values_mut() {
+            if let Err(err) = connection.send(response).await {
+                warn!(error = %err, "hook broadcast failed");
+            }
+        }
+        drop(lock_connections);
+        self.state.cluster.everyone(response).await;
+    }
+}
+
+/// Custom logic around requests, registered with `ServerBuilder::hook`.
+/// The `on_*` callbacks run before the request is handled, they may modify
+/// it or reject it with an error that is sent back to the client. Every
+/// callback defaults to doing nothing.
+#[async_trait]
+pub trait ChatHook: Send + Sync {
+    async fn on_connect(&self, _ctx: &HookContext) -> Result<(), ChatError> {
+        Ok(())
+    }
+
+    // the connection is still registered while this runs
+    async fn on_disconnect(&self, _ctx: &HookContext) {}
+
+    async fn on_nickname(
+        &self,
+        _ctx: &HookContext,
+        _req: &mut requests::SetNickname,
+    ) -> Result<(), ChatError> {
+        Ok(())
+    }
+
+    async fn on_room_create(
+        &self,
+        _ctx: &HookContext,
+        _req: &mut requests::CreateRoom,
+    ) -> Result<(), ChatError> {
+        Ok(())
+    }
+
+    async fn on_room_update(
+        &self,
+        _ctx: &HookContext,
+        _req: &mut requests::UpdateRoom,
+    ) -> Result<(), ChatError> {
+        Ok(())
+    }
+
+    async fn on_room_join(
+        &self,
+        _ctx: &HookContext,
+        _req: &requests::JoinRoom,
+    ) -> Result<(), ChatError> {
+        Ok(())
+    }
+
+    async fn on_message(
+        &self,
+        _ctx: &HookContext,
+        _req: &mut requests::Message,
+    ) -> Result<(), ChatError> {
+        Ok(())
+    }
+
+    /// After the message has been delivered, e.g. for bots replying to it.
+    async fn after_message(&self, _ctx: &HookContext, _req: &requests::Message) {}
+}
+
+/// Registered hooks, run in registration order.
+#[derive(Clone, Default)]
+pub struct Hooks {
+    hooks: Vec<Arc<dyn ChatHook>>,
+}
+
+impl Hooks {
+    pub fn new() -> Self {
+        Hooks::default()
+    }
+
+    pub fn push(&mut self, hook: Arc<dyn ChatHook>) {
+        self.hooks.push(hook);
+    }
+
+    pub async fn on_connect(&self, ctx: &HookContext) -> Result<(), ChatError> {
+        for hook in &self.hooks {
+            hook.on_connect(ctx).await?;
+        }
+        Ok(())
+    }
+
+    /// Runs the `on_*` callbacks matching the request, the first rejection wins.
+    pub async fn before(&self, ctx: &HookContext, request: &mut Request) -> Result<(), ChatError> {
+        for hook in &self.hooks {
+            match request {
+                Request::SetNickname(req) => hook.on_nickname(ctx, req).await?,
+                Request::CreateRoom(req) => hook.on_room_create(ctx, req).await?,
+                Request::UpdateRoom(req) => hook.on_room_update(ctx, req).await?,
+                Request::JoinRoom(req) => hook.on_room_join(ctx, req).await?,
+                Request::Message(req) => hook.on_message(ctx, req).await?,
+                Request::Disconnected => hook.on_disconnect(ctx).await,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn after(&self, ctx: &HookContext, request: &Request) {
+        if let Request::Message(req) = request {
+            for hook in &self.hooks {
+                hook.after_message(ctx, req).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_support::{self, TestClient};
+
+    const NO_SPAM: &str = "No spam";
+
+    // shouts every message, refuses spam and writes down what it saw
+    #[derive(Clone, Default)]
+    struct Shout {
+        delivered: Arc<Mutex<Vec<String>>>,
+        disconnected: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl ChatHook for Shout {
+        async fn on_message(
+            &self,
+            _ctx: &HookContext,
+            req: &mut requests::Message,
+        ) -> Result<(), ChatError> {
+            if req.message.contains("spam") {
+                return Err(NO_SPAM.to_owned());
+            }
+            req.message = req.message.to_uppercase();
+            Ok(())
+        }
+
+        async fn after_message(&self, _ctx: &HookContext, req: &requests::Message) {
+            self.delivered.lock().unwrap().push(req.message.clone());
+        }
+
+        async fn on_disconnect(&self, ctx: &HookContext) {
+            let name = ctx.name().await.unwrap_or_default();
+            self.disconnected.lock().unwrap().push(name);
+        }
+    }
+
+    #[tokio::test]
+    async fn hooks_rewrite_reject_and_observe() {
+        let hook = Shout::default();
+        let handle = test_support::builder()
+            .hook(hook.clone())
+            .start()
+            .await
+            .unwrap();
+        let mut alice = TestClient::connect(handle.local_addr(), "alice").await;
+        let mut bob = TestClient::connect(handle.local_addr(), "bob").await;
+        let room_id = alice.create_room("team").await;
+        bob.join_room(room_id).await;
+        alice.drain().await;
+        let post =
+            |text: &str| json!({ "messageType": "Room", "receiverId": room_id, "message": text });
+
+        alice.send("Message", post("hello")).await;
+        assert_eq!(bob.expect("Message").await["message"], "HELLO");
+        alice.send("Message", post("cheap spam")).await;
+        assert_eq!(alice.expect("Error").await["message"], NO_SPAM);
+        assert!(bob.drain().await.is_empty());
+        assert_eq!(*hook.delivered.lock().unwrap(), ["HELLO"]);
+
+        // the connection is still known while the hook runs
+        drop(bob);
+        for _ in 0..50 {
+            if !hook.disconnected.lock().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(*hook.disconnected.lock().unwrap(), ["bob"]);
+
+        drop(alice);
+        handle.shutdown().await;
+    }
+}
@@ -0,0 +1,234 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+pub const MAX_HEADER_LINES: usize = 64;
+pub const MAX_BODY_LEN: usize = 64 * 1024;
+pub const MAX_LINE_LEN: usize = 8 * 1024;
+// a client that sends its request slower than this is cut off
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The parts of an HTTP/1.1 request the side endpoints look at.
+#[derive(Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    // names lowercased
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[derive(Debug)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn new(status: u16, content_type: &'static str, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            status,
+            content_type,
+            body: body.into(),
+        }
+    }
+
+    pub fn json(status: u16, body: impl Into<Vec<u8>>) -> Self {
+        HttpResponse::new(status, "application/json", body)
+    }
+
+    pub fn text(status: u16, body: impl Into<Vec<u8>>) -> Self {
+        HttpResponse::new(status, "text/plain; charset=utf-8", body)
+    }
+
+    pub fn not_found() -> Self {
+        HttpResponse::text(404, "Not Found\n")
+    }
+
+    pub fn method_not_allowed() -> Self {
+        HttpResponse::text(405, "Method Not Allowed\n")
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            201 => "Created",
+            204 => "No Content",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            408 => "Request Timeout",
+            409 => "Conflict",
+            413 => "Payload Too Large",
+            503 => "Service Unavailable",
+            _ => "Internal Server Error",
+        }
+    }
+}
+
+fn bad_request() -> HttpResponse {
+    HttpResponse::text(400, "Bad Request\n")
+}
+
+/// Reads one line into `line`, a longer one than `MAX_LINE_LEN` is refused.
+async fn read_line(
+    reader: &mut BufReader<&mut TcpStream>,
+    line: &mut String,
+) -> Result<(), HttpResponse> {
+    line.clear();
+    reader
+        .take(MAX_LINE_LEN as u64)
+        .read_line(line)
+        .await
+        .map_err(|_| bad_request())?;
+    if line.len() == MAX_LINE_LEN && !line.ends_with('\n') {
+        return Err(bad_request());
+    }
+    Ok(())
+}
+
+async fn read_request(stream: &mut TcpStream) -> Result<HttpRequest, HttpResponse> {
+    let mut reader = BufReader::new(stream);
+
+    let mut line = String::new();
+    read_line(&mut reader, &mut line).await?;
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_owned(), target.to_owned()),
+        _ => return Err(bad_request()),
+    };
+    // the query string is not used by any endpoint
+    let path = target.split('?').next().unwrap_or_default().to_owned();
+
+    let mut headers = vec![];
+    loop {
+        read_line(&mut reader, &mut line).await?;
+        if line.trim_end().is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADER_LINES {
+            return Err(bad_request());
+        }
+        let (name, value) = line.split_once(':').ok_or_else(bad_request)?;
+        headers.push((name.trim().to_lowercase(), value.trim().to_owned()));
+    }
+
+    let mut request = HttpRequest {
+        method,
+        path,
+        headers,
+        body: vec![],
+    };
+    let content_length = match request.header("content-length") {
+        Some(value) => value.parse::<usize>().map_err(|_| bad_request())?,
+        None => 0,
+    };
+    if content_length > MAX_BODY_LEN {
+        return Err(HttpResponse::text(413, "Payload Too Large\n"));
+    }
+    request.body = vec![0; content_length];
+    reader
+        .read_exact(&mut request.body)
+        .await
+        .map_err(|_| bad_request())?;
+
+    Ok(request)
+}
+
+async fn write_response(stream: &mut TcpStream, response: HttpResponse) -> std::io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.reason(),
+        response.content_type,
+        response.body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&response.body).await?;
+    stream.shutdown().await
+}
+
+/// Serves one request per connection, enough for schema, metrics, health
+/// and admin endpoints that sit next to the websocket listener.
+pub fn serve<H, F>(listener: TcpListener, handler: H) -> JoinHandle<()>
+where
+    H: Fn(HttpRequest) -> F + Send + Sync + 'static,
+    F: Future<Output = HttpResponse> + Send,
+{
+    let handler = Arc::new(handler);
+    tokio::spawn(async move {
+        loop {
+            let mut stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    warn!(error = %err, "http accept failed");
+                    continue;
+                }
+            };
+            let handler = Arc::clone(&handler);
+            tokio::spawn(async move {
+                let request = tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream));
+                let response = match request.await {
+                    Ok(Ok(request)) => handler(request).await,
+                    Ok(Err(response)) => response,
+                    Err(_) => HttpResponse::text(408, "Request Timeout\n"),
+                };
+                if let Err(err) = write_response(&mut stream, response).await {
+                    warn!(error = %err, "http write failed");
+                }
+            });
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn request(raw: &[u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = serve(listener, |request: HttpRequest| async move {
+            HttpResponse::text(200, request.path)
+        });
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(raw).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        server.abort();
+        response
+    }
+
+    #[tokio::test]
+    async fn serves_a_request() {
+        let response = request(b"GET /healthz?full HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\n/healthz"));
+    }
+
+    #[tokio::test]
+    async fn refuses_an_overlong_line() {
+        // exactly what the server reads, unread bytes would reset the connection
+        let mut raw = b"GET /".to_vec();
+        raw.resize(MAX_LINE_LEN, b'a');
+        let response = request(&raw).await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+}
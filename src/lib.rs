@@ -0,0 +1,57 @@
+//! A websocket chat server that can run standalone or inside another Tokio
+//! application.
+//!
+//! ```no_run
+//! # async fn run() -> std::io::Result<()> {
+//! let handle = chat_server::Server::builder()
+//!     .bind("127.0.0.1:3012")
+//!     .start()
+//!     .await?;
+//! println!("listening on {}", handle.local_addr());
+//! handle.shutdown().await;
+//! # Ok(())
+//! # }
+//! ```
+
+mod admin;
+pub mod announcements;
+pub mod auth;
+pub mod backplane;
+pub mod bots;
+pub mod capabilities;
+pub mod codec;
+pub mod commands;
+pub mod config;
+pub mod content;
+pub mod health;
+pub mod history;
+pub mod hooks;
+mod http;
+pub mod logging;
+mod mentions;
+pub mod metrics;
+pub mod moderation;
+pub mod presence;
+pub mod rate_limit;
+pub mod redis_backplane;
+pub mod registry;
+pub mod requests;
+pub mod responses;
+pub mod room;
+pub mod room_manager;
+pub mod room_store;
+pub mod schema;
+pub mod server;
+mod service;
+pub mod storage;
+#[cfg(test)]
+mod test_support;
+pub mod types;
+pub mod uploads;
+pub mod ws_client_connection;
+
+pub use auth::{Authenticator, Identity};
+pub use commands::{Command, CommandContext, CommandOutcome};
+pub use config::Config;
+pub use hooks::{ChatHook, HookContext};
+pub use server::{Server, ServerBuilder, ServerHandle, ServerState};
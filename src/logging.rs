@@ -0,0 +1,126 @@
+use std::io::IsTerminal;
+use std::str::FromStr;
+
+use tracing_subscriber::EnvFilter;
+
+pub const DEFAULT_FILTER: &str = "info";
+pub const UNKNOWN_LOG_FORMAT: &str = "Log format must be text or json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    // human readable lines
+    #[default]
+    Text,
+    // one object per line with the span fields, for log shipping
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(UNKNOWN_LOG_FORMAT.to_owned()),
+        }
+    }
+}
+
+/// Installs a global subscriber for the server's diagnostics. `filter` uses
+/// the `RUST_LOG` syntax, e.g. `info,chat_server::service=debug`. Embedding
+/// applications that set up tracing themselves do not call this.
+pub fn init(format: LogFormat, filter: &str) {
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_new(filter).unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER)),
+        )
+        // no color codes in files and log collectors
+        .with_ansi(std::io::stdout().is_terminal());
+    let result = match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .try_init(),
+    };
+    if let Err(err) = result {
+        eprintln!("Logging already set up: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    use serde_json::json;
+    use tracing::Dispatch;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::test_support::{self, TestClient};
+
+    const SECRET: &str = "the vault code is 0451";
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // what the server logs at debug level while alice sends a message
+    async fn logs(format: LogFormat, log_request_contents: bool) -> String {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let builder = tracing_subscriber::fmt()
+            .with_env_filter(EnvFilter::new("debug"))
+            .with_ansi(false)
+            .with_writer(move || writer.clone());
+        let dispatch = match format {
+            LogFormat::Text => Dispatch::new(builder.finish()),
+            LogFormat::Json => Dispatch::new(builder.json().with_current_span(true).finish()),
+        };
+        // the test runtime polls the server on this thread
+        let _guard = tracing::dispatcher::set_default(&dispatch);
+
+        let handle = test_support::builder()
+            .config(Config {
+                log_request_contents,
+                ..test_support::config()
+            })
+            .start()
+            .await
+            .unwrap();
+        let mut alice = TestClient::connect(handle.local_addr(), "alice").await;
+        let dm = json!({ "messageType": "User", "receiverId": alice.id, "message": SECRET });
+        alice.send("Message", dm).await;
+        alice.drain().await;
+        drop(alice);
+        handle.shutdown().await;
+
+        let logs = captured.0.lock().unwrap().clone();
+        String::from_utf8(logs).unwrap()
+    }
+
+    #[tokio::test]
+    async fn request_contents_are_left_out_by_default() {
+        for format in [LogFormat::Text, LogFormat::Json] {
+            let logs = logs(format, false).await;
+            assert!(logs.contains("request received"), "{}", logs);
+            assert!(!logs.contains(SECRET), "{}", logs);
+        }
+
+        let logs = logs(LogFormat::Text, true).await;
+        assert!(logs.contains(SECRET), "{}", logs);
+    }
+}
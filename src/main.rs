@@ -1,16 +1,122 @@
-use server::Server;
+use chat_server::bots::{ApiKeyAuth, BotAccount};
+use chat_server::logging::{self, LogFormat, DEFAULT_FILTER};
+use chat_server::moderation::{ModerationConfig, Moderator};
+use chat_server::redis_backplane::{RedisBackplane, DEFAULT_CHANNEL};
+use chat_server::room_store::FileRoomStore;
+use chat_server::{schema, Config, Server};
+use tracing::error;
 
-mod requests;
-mod responses;
-mod room;
-mod room_manager;
-mod server;
-mod service;
-mod types;
-mod ws_client_connection;
+// `name:key,name:key`
+const BOT_KEYS_VAR: &str = "CHAT_BOT_KEYS";
+const ADMIN_TOKEN_VAR: &str = "CHAT_ADMIN_TOKEN";
+// `text` or `json`
+const LOG_FORMAT_VAR: &str = "CHAT_LOG_FORMAT";
+const LOG_FILTER_VAR: &str = "RUST_LOG";
+// `host:port`, the server joins the cluster on that Redis when set
+const REDIS_ADDR_VAR: &str = "CHAT_REDIS_ADDR";
+const REDIS_CHANNEL_VAR: &str = "CHAT_REDIS_CHANNEL";
+// file the rooms are kept in across restarts, in memory only when unset
+const ROOM_SNAPSHOT_VAR: &str = "CHAT_ROOM_SNAPSHOT";
+// `word,word`, masked in messages, nicknames and room names
+const BLOCKED_WORDS_VAR: &str = "CHAT_BLOCKED_WORDS";
+// `true` rejects messages with links
+const BLOCK_LINKS_VAR: &str = "CHAT_BLOCK_LINKS";
+
+fn bot_accounts(value: &str) -> Vec<BotAccount> {
+    value
+        .split(',')
+        .filter_map(|entry| entry.trim().split_once(':'))
+        .map(|(name, api_key)| BotAccount {
+            name: name.to_owned(),
+            api_key: api_key.to_owned(),
+        })
+        .collect()
+}
+
+/// Moderation is on when words or links are blocked.
+fn moderation_config() -> Option<ModerationConfig> {
+    let words: Vec<String> = std::env::var(BLOCKED_WORDS_VAR)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|word| !word.is_empty())
+        .map(str::to_owned)
+        .collect();
+    let block_links = std::env::var(BLOCK_LINKS_VAR).is_ok_and(|value| value == "true");
+    if words.is_empty() && !block_links {
+        return None;
+    }
+    Some(ModerationConfig {
+        words,
+        block_links,
+        ..ModerationConfig::default()
+    })
+}
+
+/// Startup failures are logged and end the process.
+fn or_exit<T, E: std::fmt::Display>(result: Result<T, E>, what: &str) -> T {
+    result.unwrap_or_else(|err| {
+        error!(error = %err, "{}", what);
+        std::process::exit(1)
+    })
+}
 
 #[tokio::main]
 async fn main() {
-    let mut server = Server::new("127.0.0.1:3012").await.unwrap();
-    server.start().await.unwrap();
+    if std::env::args().nth(1).as_deref() == Some("schema") {
+        print!("{}", schema::protocol_schema_string());
+        return;
+    }
+
+    let log_format = match std::env::var(LOG_FORMAT_VAR) {
+        Ok(value) => match value.parse() {
+            Ok(log_format) => log_format,
+            Err(err) => {
+                // logging is not set up yet
+                eprintln!("{}={}: {}", LOG_FORMAT_VAR, value, err);
+                std::process::exit(1);
+            }
+        },
+        Err(_) => LogFormat::default(),
+    };
+    logging::init(
+        log_format,
+        &std::env::var(LOG_FILTER_VAR).unwrap_or_else(|_| DEFAULT_FILTER.to_owned()),
+    );
+
+    let bots = bot_accounts(&std::env::var(BOT_KEYS_VAR).unwrap_or_default());
+    let admin_token = std::env::var(ADMIN_TOKEN_VAR).ok();
+    // the admin API is only served when there is a token to protect it
+    let admin_addr = admin_token.as_ref().map(|_| "127.0.0.1:3081".to_owned());
+    let mut builder = Server::builder();
+    if let Ok(addr) = std::env::var(REDIS_ADDR_VAR) {
+        let channel =
+            std::env::var(REDIS_CHANNEL_VAR).unwrap_or_else(|_| DEFAULT_CHANNEL.to_owned());
+        let backplane = or_exit(
+            RedisBackplane::connect(addr, channel).await,
+            "connecting to redis failed",
+        );
+        builder = builder.backplane(backplane);
+    }
+    if let Ok(path) = std::env::var(ROOM_SNAPSHOT_VAR) {
+        builder = builder.room_store(FileRoomStore::new(path));
+    }
+    if let Some(config) = moderation_config() {
+        builder = builder.hook(or_exit(Moderator::new(config), "invalid moderation config"));
+    }
+    let handle = builder
+        .bind("127.0.0.1:3012")
+        .config(Config {
+            http_addr: Some("127.0.0.1:3080".to_owned()),
+            admin_addr,
+            admin_token,
+            ..Config::default()
+        })
+        .auth(ApiKeyAuth::new(bots))
+        .start()
+        .await;
+    let handle = or_exit(handle, "starting the server failed");
+
+    or_exit(tokio::signal::ctrl_c().await, "waiting for ctrl-c failed");
+    handle.shutdown().await;
 }
@@ -0,0 +1,97 @@
+use std::collections::HashSet;
+
+pub const ROOM_MENTION: &str = "room";
+
+pub struct Mentions {
+    pub room: bool,
+    // lowercased nicknames
+    pub names: HashSet<String>,
+}
+
+/// The length in bytes of `prefix` at the start of `text`, compared
+/// without case.
+fn prefix_len(text: &str, prefix: &str) -> Option<usize> {
+    let mut chars = text.char_indices();
+    for expected in prefix.chars() {
+        let (_, c) = chars.next()?;
+        if !c.to_lowercase().eq(expected.to_lowercase()) {
+            return None;
+        }
+    }
+    Some(chars.next().map_or(text.len(), |(index, _)| index))
+}
+
+fn ends_mention(rest: &str) -> bool {
+    rest.chars()
+        .next()
+        .is_none_or(|c| c.is_whitespace() || c.is_ascii_punctuation())
+}
+
+/// Collects `@room` and `@nickname` for the nicknames of the room members.
+/// Nicknames may contain spaces, so the text after each `@` is matched
+/// against them, longest first. Trailing punctuation is not part of the
+/// mention, so `@bob,` and `@bob` both target bob but `@bobby` does not.
+pub fn parse(message: &str, member_names: &[String]) -> Mentions {
+    let mut mentions = Mentions {
+        room: false,
+        names: HashSet::new(),
+    };
+
+    let mut candidates: Vec<&str> = member_names
+        .iter()
+        .map(String::as_str)
+        .filter(|name| !name.is_empty())
+        .chain([ROOM_MENTION])
+        .collect();
+    candidates.sort_by_key(|name| std::cmp::Reverse(name.chars().count()));
+
+    for (index, _) in message.match_indices('@') {
+        let rest = &message[index + 1..];
+        let mentioned = candidates
+            .iter()
+            .find(|name| prefix_len(rest, name).is_some_and(|len| ends_mention(&rest[len..])));
+        match mentioned {
+            Some(&ROOM_MENTION) => mentions.room = true,
+            Some(name) => {
+                mentions.names.insert(name.to_lowercase());
+            }
+            None => {}
+        }
+    }
+
+    mentions
+}
+
+impl Mentions {
+    pub fn is_empty(&self) -> bool {
+        !self.room && self.names.is_empty()
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        self.room || self.names.contains(&name.to_lowercase())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn mentions_match_member_names_with_spaces() {
+        let members = names(&["Bob", "Mary Ann", "Mary"]);
+
+        let mentions = parse("hey @mary ann, and @Bob!", &members);
+        assert!(!mentions.room);
+        assert!(mentions.matches("Mary Ann"));
+        assert!(mentions.matches("bob"));
+        assert!(!mentions.matches("Mary"));
+
+        let mentions = parse("@mary: @bobby @room", &members);
+        assert!(mentions.room);
+        assert_eq!(mentions.names, HashSet::from(["mary".to_owned()]));
+    }
+}
@@ -0,0 +1,317 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::requests::{MessageType, RequestType};
+use crate::server::ServerState;
+
+// upper bounds in seconds, there is an implicit +Inf bucket
+pub const FAN_OUT_BUCKETS: [f64; 10] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0,
+];
+
+/// What went wrong, the label of `chat_errors_total`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ErrorKind {
+    // the websocket handshake was refused
+    Handshake,
+    // a frame could not be read as a request
+    Parse,
+    Command,
+    Hook,
+    // a request handler failed
+    Request,
+}
+
+impl ErrorKind {
+    fn label(self) -> &'static str {
+        match self {
+            ErrorKind::Handshake => "handshake",
+            ErrorKind::Parse => "parse",
+            ErrorKind::Command => "command",
+            ErrorKind::Hook => "hook",
+            ErrorKind::Request => "request",
+        }
+    }
+}
+
+struct Histogram {
+    buckets: [AtomicU64; FAN_OUT_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; FAN_OUT_BUCKETS.len()],
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter().zip(FAN_OUT_BUCKETS) {
+            if seconds <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Counters of one server, kept in its state. Gauges such as connected
+/// clients are read from the server state when scraped instead.
+pub struct Metrics {
+    requests: Mutex<BTreeMap<String, u64>>,
+    errors: Mutex<BTreeMap<ErrorKind, u64>>,
+    user_messages: AtomicU64,
+    room_messages: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    send_failures: AtomicU64,
+    fan_out: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            requests: Mutex::new(BTreeMap::new()),
+            errors: Mutex::new(BTreeMap::new()),
+            user_messages: AtomicU64::new(0),
+            room_messages: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            send_failures: AtomicU64::new(0),
+            fan_out: Histogram::new(),
+        }
+    }
+
+    pub fn request(&self, request_type: RequestType) {
+        *self
+            .requests
+            .lock()
+            .unwrap()
+            .entry(format!("{:?}", request_type))
+            .or_default() += 1;
+    }
+
+    pub fn error(&self, kind: ErrorKind) {
+        *self.errors.lock().unwrap().entry(kind).or_default() += 1;
+    }
+
+    pub fn message_relayed(&self, message_type: &MessageType) {
+        match message_type {
+            MessageType::User => &self.user_messages,
+            MessageType::Room => &self.room_messages,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn bytes_in(&self, len: usize) {
+        self.bytes_in.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub fn bytes_out(&self, len: usize) {
+        self.bytes_out.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub fn send_failures(&self, count: usize) {
+        self.send_failures
+            .fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    /// How long sending one response to all its receivers took.
+    pub fn fan_out(&self, elapsed: Duration) {
+        self.fan_out.observe(elapsed);
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// The metrics in the Prometheus text format.
+pub async fn render(state: &ServerState) -> String {
+    let metrics = &state.metrics;
+    let mut out = String::new();
+
+    let clients = state.clients.lock().await.len();
+    header(
+        &mut out,
+        "chat_connected_clients",
+        "gauge",
+        "Open websocket connections.",
+    );
+    let _ = writeln!(out, "chat_connected_clients {}", clients);
+
+    // a series per room would grow without bound, only totals are kept
+    let (rooms, members) = {
+        let room_manager = state.room_manager.lock().await;
+        let rooms = room_manager.list().await;
+        let mut members = 0;
+        for room in &rooms {
+            members += room_manager
+                .members(&room.id)
+                .await
+                .map(|members| members.len())
+                .unwrap_or_default();
+        }
+        (rooms.len(), members)
+    };
+    header(&mut out, "chat_rooms", "gauge", "Rooms that exist.");
+    let _ = writeln!(out, "chat_rooms {}", rooms);
+    header(
+        &mut out,
+        "chat_room_members",
+        "gauge",
+        "Room memberships across all rooms.",
+    );
+    let _ = writeln!(out, "chat_room_members {}", members);
+
+    header(
+        &mut out,
+        "chat_requests_total",
+        "counter",
+        "Requests handled by request type.",
+    );
+    for (request_type, count) in metrics.requests.lock().unwrap().iter() {
+        let _ = writeln!(
+            out,
+            "chat_requests_total{{type=\"{}\"}} {}",
+            request_type, count
+        );
+    }
+
+    header(&mut out, "chat_errors_total", "counter", "Errors by kind.");
+    for (kind, count) in metrics.errors.lock().unwrap().iter() {
+        let _ = writeln!(
+            out,
+            "chat_errors_total{{kind=\"{}\"}} {}",
+            kind.label(),
+            count
+        );
+    }
+
+    header(
+        &mut out,
+        "chat_messages_relayed_total",
+        "counter",
+        "Chat messages relayed to a user or a room.",
+    );
+    for (kind, counter) in [
+        ("user", &metrics.user_messages),
+        ("room", &metrics.room_messages),
+    ] {
+        let _ = writeln!(
+            out,
+            "chat_messages_relayed_total{{kind=\"{}\"}} {}",
+            kind,
+            counter.load(Ordering::Relaxed)
+        );
+    }
+
+    for (name, help, counter) in [
+        (
+            "chat_received_bytes_total",
+            "Websocket payload bytes received from clients.",
+            &metrics.bytes_in,
+        ),
+        (
+            "chat_sent_bytes_total",
+            "Websocket payload bytes sent to clients.",
+            &metrics.bytes_out,
+        ),
+        (
+            "chat_send_failures_total",
+            "Sends that failed and dropped the connection.",
+            &metrics.send_failures,
+        ),
+    ] {
+        header(&mut out, name, "counter", help);
+        let _ = writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed));
+    }
+
+    let fan_out = &metrics.fan_out;
+    header(
+        &mut out,
+        "chat_fan_out_seconds",
+        "histogram",
+        "Time to send one response to all of its receivers.",
+    );
+    for (bucket, bound) in fan_out.buckets.iter().zip(FAN_OUT_BUCKETS) {
+        let _ = writeln!(
+            out,
+            "chat_fan_out_seconds_bucket{{le=\"{}\"}} {}",
+            bound,
+            bucket.load(Ordering::Relaxed)
+        );
+    }
+    let count = fan_out.count.load(Ordering::Relaxed);
+    let _ = writeln!(out, "chat_fan_out_seconds_bucket{{le=\"+Inf\"}} {}", count);
+    let _ = writeln!(
+        out,
+        "chat_fan_out_seconds_sum {}",
+        fan_out.sum_micros.load(Ordering::Relaxed) as f64 / 1e6
+    );
+    let _ = writeln!(out, "chat_fan_out_seconds_count {}", count);
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::test_support::{self, TestClient};
+
+    #[tokio::test]
+    async fn each_server_counts_its_own_requests() {
+        let config = || Config {
+            http_addr: Some("127.0.0.1:0".to_owned()),
+            ..test_support::config()
+        };
+        let busy = test_support::builder()
+            .config(config())
+            .start()
+            .await
+            .unwrap();
+        let idle = test_support::builder()
+            .config(config())
+            .start()
+            .await
+            .unwrap();
+        let mut alice = TestClient::connect(busy.local_addr(), "alice").await;
+        alice.create_room("team").await;
+
+        let (status, body) =
+            test_support::http(busy.http_addr().unwrap(), "GET", "/metrics", None, "").await;
+        assert_eq!(status, 200);
+        assert!(body.contains("chat_connected_clients 1\n"));
+        assert!(body.contains("chat_requests_total{type=\"CreateRoom\"} 1\n"));
+        assert!(body.contains("chat_rooms 1\n"));
+        assert!(body.contains("chat_room_members 1\n"));
+        assert!(!body.contains("room_id="));
+
+        let (_, body) =
+            test_support::http(idle.http_addr().unwrap(), "GET", "/metrics", None, "").await;
+        assert!(body.contains("chat_connected_clients 0\n"));
+        assert!(!body.contains("chat_requests_total{"));
+        assert!(body.contains("chat_sent_bytes_total 0\n"));
+
+        drop(alice);
+        busy.shutdown().await;
+        idle.shutdown().await;
+    }
+}
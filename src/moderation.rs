@@ -0,0 +1,536 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use regex::{Regex, RegexBuilder};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::content::MessageContent;
+use crate::hooks::{ChatHook, HookContext};
+use crate::requests::{self, MessageType};
+use crate::types::ChatError;
+
+pub const MAX_MODERATION_LOG: usize = 1000;
+
+pub const CONTENT_NOT_ALLOWED: &str = "Content not allowed";
+pub const LINKS_NOT_ALLOWED: &str = "Links are not allowed here";
+pub const SPAM_DETECTED: &str = "Message repeated too often";
+
+const LINK_PATTERN: &str = r"(?i)\b(?:https?://|www\.)\S+";
+
+/// What happens to text matching a word list or rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    // matches are replaced with `*`
+    Mask,
+    Reject,
+}
+
+#[derive(Debug, Clone)]
+pub struct RuleConfig {
+    // shown in the moderation log
+    pub name: String,
+    pub pattern: String,
+    pub action: Action,
+}
+
+/// Overrides of the global settings for one room.
+#[derive(Debug, Clone, Default)]
+pub struct RoomPolicy {
+    // nothing is filtered in the room
+    pub exempt: bool,
+    pub block_links: Option<bool>,
+    pub word_action: Option<Action>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ModerationConfig {
+    // matched case-insensitively as whole words
+    pub words: Vec<String>,
+    pub word_action: Action,
+    pub rules: Vec<RuleConfig>,
+    pub block_links: bool,
+    // the same text more than `max_repeats` times within `repeat_window`
+    pub max_repeats: usize,
+    pub repeat_window: Duration,
+    pub rooms: HashMap<Uuid, RoomPolicy>,
+}
+
+impl Default for ModerationConfig {
+    fn default() -> Self {
+        Self {
+            words: vec![],
+            word_action: Action::Mask,
+            rules: vec![],
+            block_links: false,
+            max_repeats: 3,
+            repeat_window: Duration::from_secs(30),
+            rooms: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Message,
+    Nickname,
+    RoomName,
+    RoomTopic,
+    RoomDescription,
+}
+
+/// One filtered piece of text.
+#[derive(Debug, Clone)]
+pub struct ModerationEntry {
+    pub at: DateTime<Utc>,
+    pub conn_id: Uuid,
+    pub room_id: Option<Uuid>,
+    pub target: Target,
+    pub action: Action,
+    // word list, links, spam or the rule name
+    pub rule: String,
+    pub original: String,
+}
+
+struct Rule {
+    name: String,
+    pattern: Regex,
+    action: Action,
+}
+
+struct State {
+    rooms: HashMap<Uuid, RoomPolicy>,
+    // conn id -> recent message texts
+    recent: HashMap<Uuid, VecDeque<(Instant, String)>>,
+    log: VecDeque<ModerationEntry>,
+}
+
+/// Content moderation for messages, nicknames and room names, registered as
+/// a hook. Clones share their room policies and log, so one clone can be
+/// kept to adjust rooms and read the log while the server runs.
+#[derive(Clone)]
+pub struct Moderator {
+    words: Option<Arc<Regex>>,
+    word_action: Action,
+    rules: Arc<Vec<Rule>>,
+    links: Arc<Regex>,
+    block_links: bool,
+    max_repeats: usize,
+    repeat_window: Duration,
+    state: Arc<Mutex<State>>,
+}
+
+fn compile(pattern: &str) -> Result<Regex, ChatError> {
+    RegexBuilder::new(pattern)
+        .case_insensitive(true)
+        .build()
+        .map_err(|e| e.to_string())
+}
+
+fn mask(pattern: &Regex, text: &str) -> String {
+    pattern
+        .replace_all(text, |caps: &regex::Captures| {
+            "*".repeat(caps[0].chars().count())
+        })
+        .into_owned()
+}
+
+impl Moderator {
+    pub fn new(config: ModerationConfig) -> Result<Self, ChatError> {
+        let words = if config.words.is_empty() {
+            None
+        } else {
+            let alternatives: Vec<String> = config.words.iter().map(|w| regex::escape(w)).collect();
+            Some(Arc::new(compile(&format!(
+                r"\b(?:{})\b",
+                alternatives.join("|")
+            ))?))
+        };
+        let rules = config
+            .rules
+            .iter()
+            .map(|rule| {
+                Ok(Rule {
+                    name: rule.name.to_owned(),
+                    pattern: compile(&rule.pattern)?,
+                    action: rule.action,
+                })
+            })
+            .collect::<Result<Vec<Rule>, ChatError>>()?;
+
+        Ok(Self {
+            words,
+            word_action: config.word_action,
+            rules: Arc::new(rules),
+            links: Arc::new(compile(LINK_PATTERN)?),
+            block_links: config.block_links,
+            max_repeats: config.max_repeats,
+            repeat_window: config.repeat_window,
+            state: Arc::new(Mutex::new(State {
+                rooms: config.rooms,
+                recent: HashMap::new(),
+                log: VecDeque::new(),
+            })),
+        })
+    }
+
+    pub fn set_room_policy(&self, room_id: Uuid, policy: RoomPolicy) {
+        self.state.lock().unwrap().rooms.insert(room_id, policy);
+    }
+
+    pub fn clear_room_policy(&self, room_id: &Uuid) {
+        self.state.lock().unwrap().rooms.remove(room_id);
+    }
+
+    /// Most recent entries last.
+    pub fn log(&self) -> Vec<ModerationEntry> {
+        self.state.lock().unwrap().log.iter().cloned().collect()
+    }
+
+    fn record(&self, entry: ModerationEntry) {
+        // the original text stays in the moderation log only
+        info!(
+            conn_id = %entry.conn_id,
+            room_id = ?entry.room_id,
+            action = ?entry.action,
+            target = ?entry.target,
+            rule = %entry.rule,
+            "text moderated"
+        );
+        let mut state = self.state.lock().unwrap();
+        state.log.push_back(entry);
+        if state.log.len() > MAX_MODERATION_LOG {
+            state.log.pop_front();
+        }
+    }
+
+    /// Masks or rejects one text, logging whatever was filtered.
+    fn filter(
+        &self,
+        conn_id: Uuid,
+        room_id: Option<Uuid>,
+        target: Target,
+        text: &str,
+    ) -> Result<String, ChatError> {
+        let policy = room_id
+            .and_then(|room_id| self.state.lock().unwrap().rooms.get(&room_id).cloned())
+            .unwrap_or_default();
+        if policy.exempt {
+            return Ok(text.to_owned());
+        }
+
+        let entry = |action: Action, rule: &str| ModerationEntry {
+            at: Utc::now(),
+            conn_id,
+            room_id,
+            target,
+            action,
+            rule: rule.to_owned(),
+            original: text.to_owned(),
+        };
+
+        if policy.block_links.unwrap_or(self.block_links) && self.links.is_match(text) {
+            self.record(entry(Action::Reject, "links"));
+            return Err(LINKS_NOT_ALLOWED.to_owned());
+        }
+
+        let mut filtered = text.to_owned();
+        let word_action = policy.word_action.unwrap_or(self.word_action);
+        let checks = self
+            .words
+            .iter()
+            .map(|words| ("word list", words.as_ref(), word_action))
+            .chain(
+                self.rules
+                    .iter()
+                    .map(|rule| (rule.name.as_str(), &rule.pattern, rule.action)),
+            );
+        for (name, pattern, action) in checks {
+            if !pattern.is_match(&filtered) {
+                continue;
+            }
+            self.record(entry(action, name));
+            match action {
+                Action::Mask => filtered = mask(pattern, &filtered),
+                Action::Reject => return Err(CONTENT_NOT_ALLOWED.to_owned()),
+            }
+        }
+        Ok(filtered)
+    }
+
+    /// Rejects a text sent more than `max_repeats` times within the window.
+    /// Only accepted messages count, see `remember`.
+    fn check_spam(
+        &self,
+        conn_id: Uuid,
+        room_id: Option<Uuid>,
+        text: &str,
+    ) -> Result<(), ChatError> {
+        let normalized = text.trim().to_lowercase();
+        let now = Instant::now();
+        let repeats = {
+            let mut state = self.state.lock().unwrap();
+            let recent = state.recent.entry(conn_id).or_default();
+            while recent
+                .front()
+                .is_some_and(|(at, _)| now.duration_since(*at) > self.repeat_window)
+            {
+                recent.pop_front();
+            }
+            recent.iter().filter(|(_, t)| *t == normalized).count()
+        };
+
+        if repeats >= self.max_repeats {
+            self.record(ModerationEntry {
+                at: Utc::now(),
+                conn_id,
+                room_id,
+                target: Target::Message,
+                action: Action::Reject,
+                rule: "spam".to_owned(),
+                original: text.to_owned(),
+            });
+            return Err(SPAM_DETECTED.to_owned());
+        }
+        Ok(())
+    }
+
+    fn remember(&self, conn_id: Uuid, text: &str) {
+        let normalized = text.trim().to_lowercase();
+        let mut state = self.state.lock().unwrap();
+        state
+            .recent
+            .entry(conn_id)
+            .or_default()
+            .push_back((Instant::now(), normalized));
+    }
+
+    fn is_exempt(&self, room_id: Option<Uuid>) -> bool {
+        room_id.is_some_and(|room_id| {
+            self.state
+                .lock()
+                .unwrap()
+                .rooms
+                .get(&room_id)
+                .is_some_and(|policy| policy.exempt)
+        })
+    }
+}
+
+fn room_of(req: &requests::Message) -> Option<Uuid> {
+    match req.message_type {
+        MessageType::Room => Some(req.receiver_id),
+        MessageType::User => None,
+    }
+}
+
+/// The text moderation looks at, none for attachments.
+fn text_of(req: &requests::Message) -> Option<&str> {
+    match &req.content {
+        Some(MessageContent::Plain { text })
+        | Some(MessageContent::Markdown { text })
+        | Some(MessageContent::Code { text, .. }) => Some(text),
+        Some(MessageContent::Attachment { .. }) => None,
+        None => Some(&req.message),
+    }
+}
+
+#[async_trait]
+impl ChatHook for Moderator {
+    async fn on_disconnect(&self, ctx: &HookContext) {
+        self.state.lock().unwrap().recent.remove(&ctx.conn_id);
+    }
+
+    async fn on_nickname(
+        &self,
+        ctx: &HookContext,
+        req: &mut requests::SetNickname,
+    ) -> Result<(), ChatError> {
+        req.name = self.filter(ctx.conn_id, None, Target::Nickname, &req.name)?;
+        Ok(())
+    }
+
+    async fn on_room_create(
+        &self,
+        ctx: &HookContext,
+        req: &mut requests::CreateRoom,
+    ) -> Result<(), ChatError> {
+        req.name = self.filter(ctx.conn_id, None, Target::RoomName, &req.name)?;
+        Ok(())
+    }
+
+    async fn on_room_update(
+        &self,
+        ctx: &HookContext,
+        req: &mut requests::UpdateRoom,
+    ) -> Result<(), ChatError> {
+        let room_id = Some(req.room_id);
+        if let Some(topic) = &mut req.topic {
+            *topic = self.filter(ctx.conn_id, room_id, Target::RoomTopic, topic)?;
+        }
+        if let Some(description) = &mut req.description {
+            *description =
+                self.filter(ctx.conn_id, room_id, Target::RoomDescription, description)?;
+        }
+        Ok(())
+    }
+
+    async fn on_message(
+        &self,
+        ctx: &HookContext,
+        req: &mut requests::Message,
+    ) -> Result<(), ChatError> {
+        let room_id = room_of(req);
+        if self.is_exempt(room_id) {
+            return Ok(());
+        }
+
+        // bots post repeated status updates by design
+        let check_spam = !ctx.is_bot().await;
+        // repeats are compared after masking, as they were remembered
+        let filter = |text: &str| {
+            let filtered = self.filter(ctx.conn_id, room_id, Target::Message, text)?;
+            if check_spam {
+                self.check_spam(ctx.conn_id, room_id, &filtered)?;
+            }
+            Ok::<String, ChatError>(filtered)
+        };
+        match &mut req.content {
+            Some(MessageContent::Plain { text })
+            | Some(MessageContent::Markdown { text })
+            | Some(MessageContent::Code { text, .. }) => *text = filter(text)?,
+            Some(MessageContent::Attachment { .. }) => {}
+            None => req.message = filter(&req.message)?,
+        }
+        Ok(())
+    }
+
+    // a message rejected by this or any other check does not count as a repeat
+    async fn after_message(&self, ctx: &HookContext, req: &requests::Message) {
+        if self.is_exempt(room_of(req)) || ctx.is_bot().await {
+            return;
+        }
+        if let Some(text) = text_of(req) {
+            self.remember(ctx.conn_id, text);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use async_trait::async_trait;
+    use serde_json::json;
+
+    use super::*;
+    use crate::hooks::{ChatHook, HookContext};
+    use crate::requests;
+    use crate::test_support::{self, TestClient};
+
+    struct CountMessages(Arc<AtomicUsize>);
+
+    #[async_trait]
+    impl ChatHook for CountMessages {
+        async fn on_message(
+            &self,
+            _ctx: &HookContext,
+            _req: &mut requests::Message,
+        ) -> Result<(), ChatError> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn commands_are_moderated_once() {
+        let config = |word_action| ModerationConfig {
+            words: vec!["darn".to_owned()],
+            word_action,
+            ..ModerationConfig::default()
+        };
+        let masking = Moderator::new(config(Action::Mask)).unwrap();
+        let rejecting = Moderator::new(config(Action::Reject)).unwrap();
+        let count = Arc::new(AtomicUsize::new(0));
+        let handle = test_support::builder()
+            .hook(CountMessages(Arc::clone(&count)))
+            .hook(masking.clone())
+            .start()
+            .await
+            .unwrap();
+        let mut alice = TestClient::connect(handle.local_addr(), "alice").await;
+        let room_id = alice.create_room("team").await;
+        let post =
+            |text: &str| json!({ "messageType": "Room", "receiverId": room_id, "message": text });
+
+        alice.send("Message", post("/me says darn")).await;
+        assert_eq!(
+            alice.expect("Message").await["message"],
+            "* alice says ****"
+        );
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        assert_eq!(masking.log().len(), 1);
+
+        // the escape is undone before moderation sees the text
+        alice.send("Message", post("//darn")).await;
+        assert_eq!(alice.expect("Message").await["message"], "/****");
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        drop(alice);
+        handle.shutdown().await;
+
+        let handle = test_support::builder()
+            .hook(rejecting.clone())
+            .start()
+            .await
+            .unwrap();
+        let mut alice = TestClient::connect(handle.local_addr(), "alice").await;
+        let room_id = alice.create_room("team").await;
+        alice
+            .send(
+                "Message",
+                json!({ "messageType": "Room", "receiverId": room_id, "message": "/me says darn" }),
+            )
+            .await;
+        assert_eq!(alice.expect("Error").await["message"], CONTENT_NOT_ALLOWED);
+        assert!(alice.drain().await.is_empty());
+        assert_eq!(rejecting.log().len(), 1);
+
+        drop(alice);
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn rejected_messages_do_not_count_as_repeats() {
+        let moderator = Moderator::new(ModerationConfig {
+            max_repeats: 1,
+            repeat_window: Duration::from_millis(400),
+            ..ModerationConfig::default()
+        })
+        .unwrap();
+        let handle = test_support::builder()
+            .hook(moderator)
+            .start()
+            .await
+            .unwrap();
+        let mut alice = TestClient::connect(handle.local_addr(), "alice").await;
+        let room_id = alice.create_room("team").await;
+        let post = json!({ "messageType": "Room", "receiverId": room_id, "message": "hi" });
+
+        alice.send("Message", post.clone()).await;
+        alice.expect("Message").await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        alice.send("Message", post.clone()).await;
+        assert_eq!(alice.expect("Error").await["message"], SPAM_DETECTED);
+
+        // the first message has left the window, the rejected one would not have
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        alice.send("Message", post).await;
+        alice.expect("Message").await;
+
+        drop(alice);
+        handle.shutdown().await;
+    }
+}
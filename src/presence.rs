@@ -0,0 +1,132 @@
+use std::collections::{HashMap, HashSet};
+
+use uuid::Uuid;
+
+pub struct PresenceManager {
+    // user id -> connections watching that user
+    user_subscribers: HashMap<Uuid, HashSet<Uuid>>,
+    // room id -> connections watching every member of that room
+    room_subscribers: HashMap<Uuid, HashSet<Uuid>>,
+    // user id -> rooms the user is a member of
+    memberships: HashMap<Uuid, HashSet<Uuid>>,
+}
+
+impl Default for PresenceManager {
+    fn default() -> Self {
+        PresenceManager::new()
+    }
+}
+
+impl PresenceManager {
+    pub fn new() -> Self {
+        Self {
+            user_subscribers: HashMap::new(),
+            room_subscribers: HashMap::new(),
+            memberships: HashMap::new(),
+        }
+    }
+
+    pub fn subscribe_user(&mut self, subscriber_id: Uuid, user_id: Uuid) {
+        self.user_subscribers
+            .entry(user_id)
+            .or_default()
+            .insert(subscriber_id);
+    }
+
+    pub fn unsubscribe_user(&mut self, subscriber_id: &Uuid, user_id: &Uuid) {
+        if let Some(subscribers) = self.user_subscribers.get_mut(user_id) {
+            subscribers.remove(subscriber_id);
+        }
+    }
+
+    pub fn subscribe_room(&mut self, subscriber_id: Uuid, room_id: Uuid) {
+        self.room_subscribers
+            .entry(room_id)
+            .or_default()
+            .insert(subscriber_id);
+    }
+
+    pub fn unsubscribe_room(&mut self, subscriber_id: &Uuid, room_id: &Uuid) {
+        if let Some(subscribers) = self.room_subscribers.get_mut(room_id) {
+            subscribers.remove(subscriber_id);
+        }
+    }
+
+    pub fn join_room(&mut self, user_id: Uuid, room_id: Uuid) {
+        self.memberships
+            .entry(user_id)
+            .or_default()
+            .insert(room_id);
+    }
+
+    pub fn leave_room(&mut self, user_id: &Uuid, room_id: &Uuid) {
+        if let Some(rooms) = self.memberships.get_mut(user_id) {
+            rooms.remove(room_id);
+        }
+    }
+
+    /// Forgets a deleted room.
+    pub fn remove_room(&mut self, room_id: &Uuid) {
+        self.room_subscribers.remove(room_id);
+        for rooms in self.memberships.values_mut() {
+            rooms.remove(room_id);
+        }
+    }
+
+    pub fn is_member(&self, user_id: &Uuid, room_id: &Uuid) -> bool {
+        self.memberships
+            .get(user_id)
+            .is_some_and(|rooms| rooms.contains(room_id))
+    }
+
+    pub fn room_members(&self, room_ids: &[Uuid]) -> HashSet<Uuid> {
+        self.memberships
+            .iter()
+            .filter(|(_, rooms)| room_ids.iter().any(|room_id| rooms.contains(room_id)))
+            .map(|(user_id, _)| *user_id)
+            .collect()
+    }
+
+    /// Rooms the user is a member of.
+    pub fn rooms(&self, user_id: &Uuid) -> Vec<Uuid> {
+        self.memberships
+            .get(user_id)
+            .map(|rooms| rooms.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Connections that should receive presence events about `user_id`.
+    pub fn audience(&self, user_id: &Uuid) -> HashSet<Uuid> {
+        self.audience_in(user_id, &self.rooms(user_id))
+    }
+
+    /// Like `audience` for a user whose rooms are known elsewhere, e.g. a
+    /// user connected to another node.
+    pub fn audience_in(&self, user_id: &Uuid, room_ids: &[Uuid]) -> HashSet<Uuid> {
+        let mut audience = self
+            .user_subscribers
+            .get(user_id)
+            .cloned()
+            .unwrap_or_default();
+
+        for room_id in room_ids {
+            if let Some(subscribers) = self.room_subscribers.get(room_id) {
+                audience.extend(subscribers.iter().filter(|id| *id != user_id));
+            }
+        }
+
+        audience
+    }
+
+    /// Drops every subscription held by or targeting a connection that went away.
+    pub fn remove(&mut self, user_id: &Uuid) {
+        self.user_subscribers.remove(user_id);
+        for subscribers in self.user_subscribers.values_mut() {
+            subscribers.remove(user_id);
+        }
+        for subscribers in self.room_subscribers.values_mut() {
+            subscribers.remove(user_id);
+        }
+        self.memberships.remove(user_id);
+    }
+}
@@ -0,0 +1,374 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Mutex};
+use tracing::{info, warn};
+
+use crate::backplane::{Backplane, ClusterMessage, SUBSCRIBER_BUFFER};
+use crate::types::ChatError;
+
+pub const DEFAULT_CHANNEL: &str = "chat-server";
+pub const UNEXPECTED_REPLY: &str = "Unexpected reply from Redis";
+// waits between attempts to subscribe again after losing the connection
+pub const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+// longest wait for Redis to answer a command, the connection is dropped after
+pub const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+pub const REDIS_TIMEOUT: &str = "Redis did not answer in time";
+
+type Connection = BufReader<TcpStream>;
+
+/// A RESP reply. Only flat arrays are read, which is all `PUBLISH` and
+/// `SUBSCRIBE` answer with.
+#[derive(Debug)]
+enum Reply {
+    Simple,
+    Error(String),
+    // the receiver count of `PUBLISH`, not needed
+    Integer,
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+fn io_error(err: std::io::Error) -> ChatError {
+    err.to_string()
+}
+
+async fn read_line(connection: &mut Connection) -> Result<String, ChatError> {
+    let mut line = String::new();
+    if connection.read_line(&mut line).await.map_err(io_error)? == 0 {
+        return Err("Redis closed the connection".to_owned());
+    }
+    Ok(line.trim_end_matches("\r\n").to_owned())
+}
+
+fn parse_len(value: &str) -> Result<i64, ChatError> {
+    value.parse().map_err(|_| UNEXPECTED_REPLY.to_owned())
+}
+
+async fn read_scalar(line: &str, connection: &mut Connection) -> Result<Reply, ChatError> {
+    let (kind, value) = line.split_at(line.len().min(1));
+    match kind {
+        "+" => Ok(Reply::Simple),
+        "-" => Ok(Reply::Error(value.to_owned())),
+        ":" => parse_len(value).map(|_| Reply::Integer),
+        "$" => {
+            let len = parse_len(value)?;
+            if len < 0 {
+                return Ok(Reply::Bulk(None));
+            }
+            // the payload and its trailing CRLF
+            let mut bulk = vec![0; len as usize + 2];
+            connection.read_exact(&mut bulk).await.map_err(io_error)?;
+            bulk.truncate(len as usize);
+            Ok(Reply::Bulk(Some(bulk)))
+        }
+        _ => Err(UNEXPECTED_REPLY.to_owned()),
+    }
+}
+
+async fn read_reply(connection: &mut Connection) -> Result<Reply, ChatError> {
+    let line = read_line(connection).await?;
+    match line.strip_prefix('*') {
+        Some(len) => {
+            let len = parse_len(len)?.max(0);
+            let mut items = Vec::with_capacity(len as usize);
+            for _ in 0..len {
+                let line = read_line(connection).await?;
+                items.push(read_scalar(&line, connection).await?);
+            }
+            Ok(Reply::Array(items))
+        }
+        None => read_scalar(&line, connection).await,
+    }
+}
+
+async fn write_command(connection: &mut Connection, args: &[&[u8]]) -> Result<(), ChatError> {
+    let mut command = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        command.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        command.extend_from_slice(arg);
+        command.extend_from_slice(b"\r\n");
+    }
+    connection
+        .get_mut()
+        .write_all(&command)
+        .await
+        .map_err(io_error)
+}
+
+async fn connect(addr: &str) -> Result<Connection, ChatError> {
+    let stream = TcpStream::connect(addr).await.map_err(io_error)?;
+    Ok(BufReader::new(stream))
+}
+
+async fn with_timeout<T>(
+    future: impl std::future::Future<Output = Result<T, ChatError>>,
+) -> Result<T, ChatError> {
+    tokio::time::timeout(COMMAND_TIMEOUT, future)
+        .await
+        .unwrap_or_else(|_| Err(REDIS_TIMEOUT.to_owned()))
+}
+
+/// Subscribes and waits for Redis to confirm it.
+async fn subscribe(addr: &str, channel: &str) -> Result<Connection, ChatError> {
+    with_timeout(subscribe_now(addr, channel)).await
+}
+
+async fn subscribe_now(addr: &str, channel: &str) -> Result<Connection, ChatError> {
+    let mut connection = connect(addr).await?;
+    write_command(&mut connection, &[b"SUBSCRIBE", channel.as_bytes()]).await?;
+    match read_reply(&mut connection).await? {
+        Reply::Array(items) => match items.first() {
+            Some(Reply::Bulk(Some(kind))) if kind == b"subscribe" => Ok(connection),
+            _ => Err(UNEXPECTED_REPLY.to_owned()),
+        },
+        Reply::Error(err) => Err(err),
+        _ => Err(UNEXPECTED_REPLY.to_owned()),
+    }
+}
+
+/// Forwards the messages of the channel until the connection fails or the
+/// server stops listening.
+async fn forward(
+    connection: &mut Connection,
+    sender: &mpsc::Sender<ClusterMessage>,
+) -> Result<(), ChatError> {
+    loop {
+        let items = match read_reply(connection).await? {
+            Reply::Array(items) => items,
+            _ => return Err(UNEXPECTED_REPLY.to_owned()),
+        };
+        let payload = match items.as_slice() {
+            [Reply::Bulk(Some(kind)), _, Reply::Bulk(Some(payload))] if kind == b"message" => {
+                payload
+            }
+            // confirmations of other subscriptions
+            _ => continue,
+        };
+        match ClusterMessage::from_bytes(payload) {
+            Ok(message) => {
+                if sender.send(message).await.is_err() {
+                    return Ok(());
+                }
+            }
+            Err(err) => warn!(error = %err, "ignoring unreadable backplane message"),
+        }
+    }
+}
+
+/// A backplane over a Redis pub/sub channel, every node of a cluster uses
+/// the same one. Publishing and subscribing use separate connections since
+/// a subscribed connection takes no other commands.
+pub struct RedisBackplane {
+    addr: String,
+    channel: String,
+    // opened again on the next publish after it failed
+    publisher: Mutex<Option<Connection>>,
+}
+
+impl RedisBackplane {
+    /// Fails when Redis cannot be reached.
+    pub async fn connect(
+        addr: impl Into<String>,
+        channel: impl Into<String>,
+    ) -> Result<Self, ChatError> {
+        let addr = addr.into();
+        let publisher = with_timeout(connect(&addr)).await?;
+        Ok(Self {
+            addr,
+            channel: channel.into(),
+            publisher: Mutex::new(Some(publisher)),
+        })
+    }
+}
+
+#[async_trait]
+impl Backplane for RedisBackplane {
+    async fn publish(&self, message: &ClusterMessage) -> Result<(), ChatError> {
+        let payload = message.to_bytes()?;
+        let mut publisher = self.publisher.lock().await;
+        let connection = match publisher.as_mut() {
+            Some(connection) => connection,
+            None => publisher.insert(with_timeout(connect(&self.addr)).await?),
+        };
+        let command: &[&[u8]] = &[b"PUBLISH", self.channel.as_bytes(), &payload];
+        let result = with_timeout(async {
+            write_command(connection, command).await?;
+            read_reply(connection).await
+        })
+        .await;
+        match result {
+            Ok(Reply::Integer) => Ok(()),
+            Ok(Reply::Error(err)) => Err(err),
+            Ok(_) => Err(UNEXPECTED_REPLY.to_owned()),
+            Err(err) => {
+                *publisher = None;
+                Err(err)
+            }
+        }
+    }
+
+    async fn subscribe(&self) -> Result<mpsc::Receiver<ClusterMessage>, ChatError> {
+        let mut connection = subscribe(&self.addr, &self.channel).await?;
+        let (sender, receiver) = mpsc::channel(SUBSCRIBER_BUFFER);
+        let addr = self.addr.clone();
+        let channel = self.channel.clone();
+        tokio::spawn(async move {
+            loop {
+                match forward(&mut connection, &sender).await {
+                    Ok(()) => break,
+                    Err(err) => warn!(error = %err, "backplane subscription lost"),
+                }
+                // events published in the meantime are missed
+                connection = loop {
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                    if sender.is_closed() {
+                        return;
+                    }
+                    match subscribe(&addr, &channel).await {
+                        Ok(connection) => break connection,
+                        Err(err) => warn!(error = %err, "backplane resubscribe failed"),
+                    }
+                };
+                info!("backplane subscription restored");
+            }
+        });
+        Ok(receiver)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+    use tokio::sync::broadcast;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::backplane::ClusterEvent;
+
+    fn bulk(value: &[u8]) -> Vec<u8> {
+        let mut bulk = format!("${}\r\n", value.len()).into_bytes();
+        bulk.extend_from_slice(value);
+        bulk.extend_from_slice(b"\r\n");
+        bulk
+    }
+
+    /// Answers `SUBSCRIBE` and `PUBLISH` the way Redis does.
+    async fn stand_in(listener: TcpListener) {
+        let (channel, _) = broadcast::channel::<Vec<u8>>(16);
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let channel = channel.clone();
+            tokio::spawn(async move {
+                let mut connection = BufReader::new(stream);
+                while let Ok(Reply::Array(args)) = read_reply(&mut connection).await {
+                    let args: Vec<Vec<u8>> = args
+                        .into_iter()
+                        .filter_map(|arg| match arg {
+                            Reply::Bulk(Some(arg)) => Some(arg),
+                            _ => None,
+                        })
+                        .collect();
+                    match args.as_slice() {
+                        [command, name] if command == b"SUBSCRIBE" => {
+                            let mut messages = channel.subscribe();
+                            let mut reply = b"*3\r\n".to_vec();
+                            reply.extend(bulk(b"subscribe"));
+                            reply.extend(bulk(name));
+                            reply.extend_from_slice(b":1\r\n");
+                            connection.get_mut().write_all(&reply).await.unwrap();
+                            while let Ok(payload) = messages.recv().await {
+                                let mut message = b"*3\r\n".to_vec();
+                                message.extend(bulk(b"message"));
+                                message.extend(bulk(name));
+                                message.extend(bulk(&payload));
+                                connection.get_mut().write_all(&message).await.unwrap();
+                            }
+                        }
+                        [command, _, payload] if command == b"PUBLISH" => {
+                            let receivers = channel.send(payload.clone()).unwrap_or(0);
+                            let reply = format!(":{}\r\n", receivers);
+                            connection
+                                .get_mut()
+                                .write_all(reply.as_bytes())
+                                .await
+                                .unwrap();
+                        }
+                        _ => return,
+                    }
+                }
+            });
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_replies() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = connect(&listener.local_addr().unwrap().to_string())
+            .await
+            .unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        server
+            .write_all(
+                b"+OK\r\n-ERR wrong\r\n:2\r\n$-1\r\n$6\r\nhi\r\nyo\r\n*2\r\n$1\r\na\r\n:1\r\n",
+            )
+            .await
+            .unwrap();
+
+        assert!(matches!(read_reply(&mut client).await, Ok(Reply::Simple)));
+        assert!(
+            matches!(read_reply(&mut client).await, Ok(Reply::Error(err)) if err == "ERR wrong")
+        );
+        assert!(matches!(read_reply(&mut client).await, Ok(Reply::Integer)));
+        assert!(matches!(
+            read_reply(&mut client).await,
+            Ok(Reply::Bulk(None))
+        ));
+        assert!(
+            matches!(read_reply(&mut client).await, Ok(Reply::Bulk(Some(bulk))) if bulk == b"hi\r\nyo")
+        );
+        match read_reply(&mut client).await {
+            Ok(Reply::Array(items)) => assert!(matches!(
+                items.as_slice(),
+                [Reply::Bulk(Some(a)), Reply::Integer] if a == b"a"
+            )),
+            reply => panic!("not an array: {:?}", reply),
+        }
+
+        server.write_all(b"?\r\n").await.unwrap();
+        assert_eq!(read_reply(&mut client).await.unwrap_err(), UNEXPECTED_REPLY);
+        drop(server);
+        assert!(read_reply(&mut client).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn published_messages_reach_subscribers() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let redis = tokio::spawn(stand_in(listener));
+
+        let backplane = RedisBackplane::connect(addr, DEFAULT_CHANNEL)
+            .await
+            .unwrap();
+        let mut messages = backplane.subscribe().await.unwrap();
+        let user_id = Uuid::new_v4();
+        let message = ClusterMessage {
+            origin: Uuid::new_v4(),
+            event: ClusterEvent::Disconnected { user_id },
+        };
+        backplane.publish(&message).await.unwrap();
+
+        let received = tokio::time::timeout(COMMAND_TIMEOUT, messages.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received.origin, message.origin);
+        assert!(matches!(
+            received.event,
+            ClusterEvent::Disconnected { user_id: received } if received == user_id
+        ));
+        redis.abort();
+    }
+}
@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::responses::UserInfo;
+
+/// A user connected to another node, as that node reports it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RegisteredUser {
+    #[serde(flatten)]
+    pub user: UserInfo,
+    // who watches these rooms hears when the user goes offline
+    pub room_ids: Vec<Uuid>,
+}
+
+struct Lease {
+    users: HashMap<Uuid, RegisteredUser>,
+    expires_at: Instant,
+}
+
+/// The users connected to the other nodes of the cluster. Every node renews
+/// a lease on its users with heartbeats, the users of a node that stops
+/// sending them, e.g. because it crashed, go away when the lease runs out.
+#[derive(Default)]
+pub struct Registry {
+    nodes: HashMap<Uuid, Lease>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Registry::default()
+    }
+
+    fn lease(&mut self, node_id: Uuid, lease: Duration) -> &mut Lease {
+        self.nodes.entry(node_id).or_insert_with(|| Lease {
+            users: HashMap::new(),
+            expires_at: Instant::now() + lease,
+        })
+    }
+
+    /// Takes the full list of the node's users and extends its lease.
+    /// Returns the users the node no longer has, their disconnects were
+    /// missed.
+    pub fn renew(
+        &mut self,
+        node_id: Uuid,
+        users: Vec<RegisteredUser>,
+        lease: Duration,
+    ) -> Vec<RegisteredUser> {
+        let node = self.lease(node_id, lease);
+        node.expires_at = Instant::now() + lease;
+        let mut users: HashMap<Uuid, RegisteredUser> =
+            users.into_iter().map(|user| (user.user.id, user)).collect();
+        std::mem::swap(&mut node.users, &mut users);
+        users
+            .into_iter()
+            .filter(|(id, _)| !node.users.contains_key(id))
+            .map(|(_, user)| user)
+            .collect()
+    }
+
+    /// Adds or updates one user between heartbeats.
+    pub fn connected(&mut self, node_id: Uuid, user: RegisteredUser, lease: Duration) {
+        self.lease(node_id, lease).users.insert(user.user.id, user);
+    }
+
+    pub fn disconnected(&mut self, node_id: &Uuid, user_id: &Uuid) -> Option<RegisteredUser> {
+        self.nodes.get_mut(node_id)?.users.remove(user_id)
+    }
+
+    /// Drops the nodes whose lease ran out and returns their users.
+    pub fn expire(&mut self) -> Vec<RegisteredUser> {
+        let now = Instant::now();
+        let expired: Vec<Uuid> = self
+            .nodes
+            .iter()
+            .filter(|(_, lease)| lease.expires_at <= now)
+            .map(|(node_id, _)| *node_id)
+            .collect();
+        expired
+            .iter()
+            .filter_map(|node_id| self.nodes.remove(node_id))
+            .flat_map(|lease| lease.users.into_values())
+            .collect()
+    }
+
+    pub fn get(&self, user_id: &Uuid) -> Option<&RegisteredUser> {
+        self.nodes
+            .values()
+            .find_map(|lease| lease.users.get(user_id))
+    }
+
+    pub fn users(&self) -> impl Iterator<Item = &RegisteredUser> {
+        self.nodes.values().flat_map(|lease| lease.users.values())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEASE: Duration = Duration::from_secs(60);
+
+    fn user(name: &str) -> RegisteredUser {
+        RegisteredUser {
+            user: UserInfo {
+                id: Uuid::new_v4(),
+                name: name.to_owned(),
+                bot: false,
+            },
+            room_ids: vec![],
+        }
+    }
+
+    #[test]
+    fn users_go_away_when_the_lease_runs_out() {
+        let mut registry = Registry::new();
+        let (alice, bob) = (user("alice"), user("bob"));
+        registry.renew(Uuid::new_v4(), vec![alice.clone()], Duration::ZERO);
+        registry.renew(Uuid::new_v4(), vec![bob.clone()], LEASE);
+
+        let expired = registry.expire();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].user.id, alice.user.id);
+        assert!(registry.get(&alice.user.id).is_none());
+        assert!(registry.get(&bob.user.id).is_some());
+    }
+
+    #[test]
+    fn a_renewed_lease_keeps_the_users() {
+        let mut registry = Registry::new();
+        let node_id = Uuid::new_v4();
+        let alice = user("alice");
+        registry.renew(node_id, vec![alice.clone()], Duration::ZERO);
+        registry.renew(node_id, vec![alice.clone()], LEASE);
+
+        assert!(registry.expire().is_empty());
+        assert!(registry.get(&alice.user.id).is_some());
+    }
+
+    #[test]
+    fn renew_returns_the_users_that_left_between_heartbeats() {
+        let mut registry = Registry::new();
+        let node_id = Uuid::new_v4();
+        let (alice, bob, carol) = (user("alice"), user("bob"), user("carol"));
+        registry.renew(node_id, vec![alice.clone(), bob.clone()], LEASE);
+        registry.connected(node_id, carol.clone(), LEASE);
+
+        let gone = registry.renew(node_id, vec![alice.clone()], LEASE);
+        let mut gone: Vec<Uuid> = gone.iter().map(|user| user.user.id).collect();
+        gone.sort();
+        let mut expected = vec![bob.user.id, carol.user.id];
+        expected.sort();
+        assert_eq!(gone, expected);
+        assert_eq!(registry.users().count(), 1);
+    }
+}
@@ -1,40 +1,194 @@
+use std::fmt;
+
+use schemars::JsonSchema;
 use serde::Deserialize;
 use uuid::Uuid;
 
-#[derive(Deserialize, Debug, Clone)]
+use crate::announcements::Severity;
+use crate::capabilities::Feature;
+use crate::content::MessageContent;
+
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct SetNickname {
     pub name: String
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
 pub enum MessageType {
     User,
     Room
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Message {
     pub message_type: MessageType,
     pub receiver_id: Uuid,
-    pub message: String
+    // plain text, used when `content` is omitted
+    #[serde(default)]
+    pub message: String,
+    #[serde(default)]
+    pub content: Option<MessageContent>,
+    #[serde(default)]
+    pub reply_to: Option<Uuid>
+}
+
+impl Message {
+    pub fn content(&self) -> MessageContent {
+        self.content.clone().unwrap_or_else(|| MessageContent::Plain {
+            text: self.message.to_owned(),
+        })
+    }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct CreateRoom {
     pub name: String
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct JoinRoom {
     pub id: String
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PresenceSubscription {
+    #[serde(default)]
+    pub user_ids: Vec<Uuid>,
+    #[serde(default)]
+    pub room_ids: Vec<Uuid>
+}
+
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct GetHistory {
+    pub room_id: Uuid,
+    #[serde(default)]
+    pub limit: Option<usize>
+}
+
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct GetThread {
+    pub message_id: Uuid
+}
+
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Reaction {
+    pub message_id: Uuid,
+    pub emoji: String
+}
+
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct MuteRoom {
+    pub room_id: Uuid,
+    pub muted: bool
+}
+
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ClearMentions {
+    // every room when omitted
+    #[serde(default)]
+    pub room_id: Option<Uuid>
+}
+
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadStart {
+    pub file_name: String,
+    pub mime_type: String,
+    pub size: u64
+}
+
+// json connections send it as a binary frame: 16 byte upload id followed
+// by the chunk bytes, messagepack connections as a regular request
+#[derive(Deserialize, JsonSchema, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadChunk {
+    pub upload_id: Uuid,
+    #[serde(with = "serde_bytes")]
+    #[schemars(with = "Vec<u8>")]
+    pub data: Vec<u8>
+}
+
+impl fmt::Debug for UploadChunk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UploadChunk")
+            .field("upload_id", &self.upload_id)
+            .field("len", &self.data.len())
+            .finish()
+    }
+}
+
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Download {
+    pub attachment_id: Uuid
+}
+
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Hello {
+    pub protocol_version: u32,
+    // events of features left out are not sent, all of them when missing
+    #[serde(default)]
+    pub capabilities: Option<Vec<Feature>>
+}
+
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LeaveRoom {
+    pub room_id: Uuid
+}
+
+// owners and moderators only, fields left out are unchanged and empty
+// strings clear them
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateRoom {
+    pub room_id: Uuid,
+    pub topic: Option<String>,
+    pub description: Option<String>,
+    // id of an uploaded image
+    pub avatar: Option<String>
+}
+
+// admins only, to everyone connected unless rooms are given
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Announce {
+    pub text: String,
+    #[serde(default)]
+    pub severity: Severity,
+    pub room_ids: Option<Vec<Uuid>>,
+    #[serde(default)]
+    pub pinned: bool
+}
+
+// admins only
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Unpin {
+    pub announcement_id: Uuid
+}
+
+// bots only, replaces what the bot registered before
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RegisterCommands {
+    pub commands: Vec<String>
+}
+
+#[derive(Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RequestType {
+    Hello,
     GetId,
     SetNickname,
     Online,
@@ -42,11 +196,29 @@ pub enum RequestType {
     Disconnected,
     GlobalOnline,
     CreateRoom,
-    JoinRoom
+    JoinRoom,
+    SubscribePresence,
+    UnsubscribePresence,
+    GetHistory,
+    GetThread,
+    AddReaction,
+    RemoveReaction,
+    MuteRoom,
+    GetMentions,
+    ClearMentions,
+    UploadStart,
+    UploadChunk,
+    Download,
+    RegisterCommands,
+    LeaveRoom,
+    UpdateRoom,
+    Announce,
+    Unpin
 }
 
 #[derive(Debug, Clone)]
 pub enum Request {
+    Hello(Hello),
     GetId,    
     SetNickname(SetNickname),
     Online,
@@ -54,7 +226,75 @@ pub enum Request {
     CreateRoom(CreateRoom),
     JoinRoom(JoinRoom),
     Disconnected,
-    GlobalOnline
+    GlobalOnline,
+    SubscribePresence(PresenceSubscription),
+    UnsubscribePresence(PresenceSubscription),
+    GetHistory(GetHistory),
+    GetThread(GetThread),
+    AddReaction(Reaction),
+    RemoveReaction(Reaction),
+    MuteRoom(MuteRoom),
+    GetMentions,
+    ClearMentions(ClearMentions),
+    UploadStart(UploadStart),
+    UploadChunk(UploadChunk),
+    Download(Download),
+    RegisterCommands(RegisterCommands),
+    LeaveRoom(LeaveRoom),
+    UpdateRoom(UpdateRoom),
+    Announce(Announce),
+    Unpin(Unpin)
+}
+
+impl Request {
+    pub fn request_type(&self) -> RequestType {
+        match self {
+            Request::Hello(_) => RequestType::Hello,
+            Request::GetId => RequestType::GetId,
+            Request::SetNickname(_) => RequestType::SetNickname,
+            Request::Online => RequestType::Online,
+            Request::Message(_) => RequestType::Message,
+            Request::CreateRoom(_) => RequestType::CreateRoom,
+            Request::JoinRoom(_) => RequestType::JoinRoom,
+            Request::Disconnected => RequestType::Disconnected,
+            Request::GlobalOnline => RequestType::GlobalOnline,
+            Request::SubscribePresence(_) => RequestType::SubscribePresence,
+            Request::UnsubscribePresence(_) => RequestType::UnsubscribePresence,
+            Request::GetHistory(_) => RequestType::GetHistory,
+            Request::GetThread(_) => RequestType::GetThread,
+            Request::AddReaction(_) => RequestType::AddReaction,
+            Request::RemoveReaction(_) => RequestType::RemoveReaction,
+            Request::MuteRoom(_) => RequestType::MuteRoom,
+            Request::GetMentions => RequestType::GetMentions,
+            Request::ClearMentions(_) => RequestType::ClearMentions,
+            Request::UploadStart(_) => RequestType::UploadStart,
+            Request::UploadChunk(_) => RequestType::UploadChunk,
+            Request::Download(_) => RequestType::Download,
+            Request::RegisterCommands(_) => RequestType::RegisterCommands,
+            Request::LeaveRoom(_) => RequestType::LeaveRoom,
+            Request::UpdateRoom(_) => RequestType::UpdateRoom,
+            Request::Announce(_) => RequestType::Announce,
+            Request::Unpin(_) => RequestType::Unpin,
+        }
+    }
+
+    /// The room the request is about, for logging.
+    pub fn room_id(&self) -> Option<Uuid> {
+        match self {
+            Request::Message(Message {
+                message_type: MessageType::Room,
+                receiver_id,
+                ..
+            }) => Some(*receiver_id),
+            Request::JoinRoom(req) => Uuid::parse_str(&req.id).ok(),
+            Request::GetHistory(GetHistory { room_id, .. })
+            | Request::MuteRoom(MuteRoom { room_id, .. })
+            | Request::LeaveRoom(LeaveRoom { room_id })
+            | Request::UpdateRoom(UpdateRoom { room_id, .. }) => Some(*room_id),
+            Request::ClearMentions(req) => req.room_id,
+            _ => None,
+        }
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -62,4 +302,19 @@ pub enum Request {
 pub struct RawRequest {
     pub request_type: RequestType,
     pub data: String
-}
\ No newline at end of file
+}
+
+// v1 messagepack framing, `data` is a nested map instead of a json string
+// and is decoded separately once the type is known
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct NestedRequest {
+    pub request_type: RequestType
+}
+
+// v2 framing, `{"type": "setNickname", "name": "..."}`
+#[derive(Deserialize, Debug)]
+pub struct TaggedRequest {
+    #[serde(rename = "type")]
+    pub tag: String
+}
@@ -1,77 +1,314 @@
 use chrono::{DateTime, Utc};
-use serde::Serialize;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-#[derive(Serialize, Debug)]
+use crate::announcements::Severity;
+use crate::capabilities::Feature;
+use crate::content::MessageContent;
+use crate::rate_limit::RateLimit;
+use crate::room::RoomInfo;
+
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Hello {
+    pub server_version: String,
+    pub connection_id: Uuid,
+    pub protocol_version: u32,
+    pub protocol_versions: Vec<u32>,
+    pub features: Vec<Feature>,
+    pub limits: Limits,
+    // what the client declared, `null` until it sends a `Hello`
+    pub capabilities: Option<Vec<Feature>>
+}
+
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Limits {
+    pub max_message_len: usize,
+    pub max_attachment_size: u64,
+    pub upload_chunk_size: usize,
+    pub max_pending_uploads: usize,
+    pub max_history: usize,
+    // of this connection, `null` when unlimited
+    pub message_rate: Option<RateLimit>
+}
+
+#[derive(Serialize, JsonSchema, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct GetId {
     pub id : Uuid
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, JsonSchema, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct SetNickname {
     pub id : Uuid,
     pub name: String
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, JsonSchema, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Message {
     pub id: Uuid,
+    pub message_id: Uuid,
     pub name: String,
     pub message: String,
-    pub created_at: DateTime<Utc>
+    pub content: MessageContent,
+    pub created_at: DateTime<Utc>,
+    pub room_id: Option<Uuid>,
+    pub reply_to: Option<Uuid>,
+    pub reply_count: u32,
+    pub reactions: Vec<Reaction>,
+    // kept in history by the server, e.g. for a member joining
+    pub system: bool
+}
+
+#[derive(Serialize, JsonSchema, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Reaction {
+    pub emoji: String,
+    pub count: usize,
+    pub user_ids: Vec<Uuid>
+}
+
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ReactionsUpdated {
+    pub message_id: Uuid,
+    pub room_id: Option<Uuid>,
+    pub reactions: Vec<Reaction>
+}
+
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ThreadReply {
+    pub parent_id: Uuid,
+    pub reply_count: u32,
+    pub message: Message
+}
+
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Thread {
+    pub parent: Message,
+    pub replies: Vec<Message>
+}
+
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct History {
+    pub room_id: Uuid,
+    pub messages: Vec<Message>
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, JsonSchema, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct Offline {
     pub id: Uuid
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, JsonSchema, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct Online {
     pub id: Uuid,
     pub name: String
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct UserInfo {
     pub id: Uuid,
-    pub name: String
+    pub name: String,
+    pub bot: bool
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, JsonSchema, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct GlobalOnline {
     pub users: Vec<UserInfo>
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PresenceSubscribed {
+    pub users: Vec<UserInfo>
+}
+
+#[derive(Serialize, JsonSchema, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct RoomCreated {
-    pub id: Uuid,
-    pub name: String
+    #[serde(flatten)]
+    pub room: RoomInfo
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, JsonSchema, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct RoomJoined {
+    #[serde(flatten)]
+    pub room: RoomInfo,
+    pub members: Vec<UserInfo>
+}
+
+// sent to every member after a change to the room's metadata
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomUpdated {
+    #[serde(flatten)]
+    pub room: RoomInfo,
+    pub updated_by: Uuid
+}
+
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomMuted {
+    pub room_id: Uuid,
+    pub muted: bool
+}
+
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Mentioned {
+    pub room_id: Uuid,
+    pub message: Message,
+    pub unread_mentions: u32
+}
+
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomMentions {
+    pub room_id: Uuid,
+    pub count: u32
+}
+
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct UnreadMentions {
+    pub total: u32,
+    pub rooms: Vec<RoomMentions>
+}
+
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadStarted {
+    pub upload_id: Uuid,
+    pub chunk_size: usize
+}
+
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadCompleted {
+    pub attachment_id: Uuid,
+    pub file_name: String,
+    pub mime_type: String,
+    pub size: u64
+}
+
+// followed by binary frames: 16 byte attachment id and the chunk bytes
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Download {
+    pub attachment_id: Uuid,
+    pub file_name: String,
+    pub mime_type: String,
+    pub size: u64,
+    pub chunk_size: usize
+}
+
+// messagepack connections get downloads as regular responses
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadChunk {
+    pub attachment_id: Uuid,
+    pub offset: u64,
+    #[serde(with = "serde_bytes")]
+    #[schemars(with = "Vec<u8>")]
+    pub data: Vec<u8>
+}
+
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MemberJoined {
+    pub room_id: Uuid,
+    pub user: UserInfo
+}
+
+// `removedBy` is set when the member was kicked
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MemberLeft {
+    pub room_id: Uuid,
+    pub user: UserInfo,
+    pub removed_by: Option<UserInfo>
+}
+
+// sent to the members of a room an admin deleted
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomDeleted {
+    pub room_id: Uuid
+}
+
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomLeft {
+    pub room_id: Uuid
+}
+
+// from the system user rather than a connection, `ephemeral` ones went to
+// one user only, `pinned` ones are sent again to clients connecting or
+// joining the room later
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SystemMessage {
     pub id: Uuid,
-    pub name: String
+    pub room_id: Option<Uuid>,
+    pub sender: UserInfo,
+    pub text: String,
+    pub severity: Severity,
+    pub ephemeral: bool,
+    pub pinned: bool,
+    pub created_at: DateTime<Utc>
+}
+
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Announced {
+    pub announcement_id: Uuid,
+    pub recipients: usize
+}
+
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AnnouncementUnpinned {
+    pub announcement_id: Uuid
+}
+
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CommandsRegistered {
+    pub commands: Vec<String>
+}
+
+// a room slash command, sent to the bot that registered it instead of the room
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BotCommand {
+    pub room_id: Uuid,
+    pub sender: UserInfo,
+    pub command: String,
+    pub args: String
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, JsonSchema, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct ResponseError {
     pub message: String
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy)]
 pub enum ResponseType {
+    Hello,
     GetId,
     Online,
     Offline,
@@ -80,10 +317,32 @@ pub enum ResponseType {
     GlobalOnline,
     RoomCreated,
     RoomJoined,
+    PresenceSubscribed,
+    ThreadReply,
+    Thread,
+    History,
+    ReactionsUpdated,
+    RoomMuted,
+    Mentioned,
+    UnreadMentions,
+    UploadStarted,
+    UploadCompleted,
+    Download,
+    DownloadChunk,
+    CommandsRegistered,
+    BotCommand,
+    RoomLeft,
+    SystemMessage,
+    RoomUpdated,
+    MemberJoined,
+    MemberLeft,
+    Announced,
+    AnnouncementUnpinned,
+    RoomDeleted,
     Error
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, JsonSchema, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct Response {
     pub response_type: ResponseType,
@@ -1,69 +1,260 @@
-use std::{collections::{HashMap, HashSet}, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Instant,
+};
 
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use tokio::sync::Mutex;
+use tracing::warn;
 use uuid::Uuid;
 
-use crate::{server::{CLIENT_NOT_FOUND, WsConnections}, types::ChatError, ws_client_connection::WsClientConnection};
+use crate::{
+    codec::Envelope, server::WsConnections, types::ChatError,
+    ws_client_connection::WsClientConnection,
+};
+
+pub const MAX_TOPIC_LEN: usize = 300;
+pub const MAX_DESCRIPTION_LEN: usize = 2000;
+
+pub const TOPIC_TOO_LONG: &str = "Topic is too long";
+pub const DESCRIPTION_TOO_LONG: &str = "Description is too long";
 
 pub struct Room {
     pub id: Uuid,
     pub name: String,
+    // the creator owns the room, moderators were appointed by the owner and
+    // both may update it and remove members
+    pub creator_id: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub moderators: HashSet<Uuid>,
+    pub topic: Option<String>,
+    pub description: Option<String>,
+    // an uploaded image shared with the room
+    pub avatar: Option<Uuid>,
     pub room_clients: Mutex<HashSet<Uuid>>,
+    pub muted_by: Mutex<HashSet<Uuid>>,
+    // bot accounts stay members when they disconnect, until they leave
+    pub bot_accounts: HashSet<String>,
+    // the account of each bot connection in the room
+    pub bot_connections: HashMap<Uuid, String>,
+    // the account of a bot owner, it owns the room again when it reconnects
+    pub owner_account: Option<String>,
     pub clients: Arc<Mutex<WsConnections>>,
 }
 
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
 pub struct RoomInfo {
     pub id: Uuid,
-    pub name: String
+    pub name: String,
+    pub topic: Option<String>,
+    pub description: Option<String>,
+    pub avatar: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+    pub creator_id: Uuid,
+    pub moderators: Vec<Uuid>,
+}
+
+// `None` leaves a field unchanged, `Some(None)` clears it
+#[derive(Debug, Default)]
+pub struct RoomUpdate {
+    pub topic: Option<Option<String>>,
+    pub description: Option<Option<String>>,
+    pub avatar: Option<Option<Uuid>>,
 }
 
 impl Room {
+    /// A room without members, for rooms that were not created here.
+    pub fn from_info(info: RoomInfo, clients: Arc<Mutex<WsConnections>>) -> Self {
+        Self {
+            id: info.id,
+            name: info.name,
+            creator_id: info.creator_id,
+            created_at: info.created_at,
+            moderators: info.moderators.into_iter().collect(),
+            topic: info.topic,
+            description: info.description,
+            avatar: info.avatar,
+            room_clients: Mutex::new(HashSet::new()),
+            muted_by: Mutex::new(HashSet::new()),
+            bot_accounts: HashSet::new(),
+            bot_connections: HashMap::new(),
+            owner_account: None,
+            clients,
+        }
+    }
+
     pub async fn add_client(&mut self, conn_id: &Uuid) {
-        self.room_clients
-            .lock()
-            .await
-            .insert(conn_id.clone());
+        self.room_clients.lock().await.insert(*conn_id);
     }
 
     pub async fn remove_client(&mut self, client_id: &Uuid) {
         self.room_clients.lock().await.remove(client_id);
+        self.muted_by.lock().await.remove(client_id);
+        self.moderators.remove(client_id);
+    }
+
+    pub fn can_moderate(&self, conn_id: &Uuid) -> bool {
+        self.creator_id == *conn_id || self.moderators.contains(conn_id)
+    }
+
+    pub fn update(&mut self, update: RoomUpdate) {
+        if let Some(topic) = update.topic {
+            self.topic = topic;
+        }
+        if let Some(description) = update.description {
+            self.description = description;
+        }
+        if let Some(avatar) = update.avatar {
+            self.avatar = avatar;
+        }
+    }
+
+    pub async fn set_muted(&mut self, client_id: &Uuid, muted: bool) {
+        let mut muted_by_lock = self.muted_by.lock().await;
+        if muted {
+            muted_by_lock.insert(*client_id);
+        } else {
+            muted_by_lock.remove(client_id);
+        }
     }
 
     pub fn room_info(&self) -> RoomInfo {
+        let mut moderators: Vec<Uuid> = self.moderators.iter().copied().collect();
+        moderators.sort();
+
         RoomInfo {
             id: self.id,
             name: self.name.to_owned(),
+            topic: self.topic.clone(),
+            description: self.description.clone(),
+            avatar: self.avatar,
+            created_at: self.created_at,
+            creator_id: self.creator_id,
+            moderators,
         }
     }
 
     pub async fn send(
         &mut self,
         predicate: impl Fn(&WsClientConnection) -> bool,
-        response_str: &str,
+        response: &Envelope,
     ) -> Result<(), ChatError> {
         let mut client_ids_to_remove = vec![];
+        let started = Instant::now();
 
-        let room_clients_lock = self.room_clients.lock().await;
+        let mut room_clients_lock = self.room_clients.lock().await;
         let mut clients_lock = self.clients.lock().await;
+        // every connection of a server shares its metrics
+        let metrics = clients_lock
+            .values()
+            .next()
+            .map(|connection| Arc::clone(&connection.metrics));
         for conn_id in room_clients_lock.iter() {
-            let client_lock = clients_lock.get_mut(&conn_id).ok_or(CLIENT_NOT_FOUND)?;
-            if predicate(&client_lock) {
-                if let Err(err) = client_lock.send(response_str).await {
-                    println!("Error while sending message: {}", err);
-                    client_ids_to_remove.push(conn_id);
+            // a member whose connection is already gone is left out, its
+            // disconnect removes it from the room
+            let client_lock = match clients_lock.get_mut(conn_id) {
+                Some(client_lock) => client_lock,
+                None => continue,
+            };
+            if predicate(client_lock) {
+                if let Err(err) = client_lock.send(response).await {
+                    warn!(conn_id = %conn_id, error = %err, "room send failed");
+                    client_ids_to_remove.push(*conn_id);
                 }
             }
         }
 
-        self.room_clients
-            .lock()
-            .await
-            .retain(|k| !client_ids_to_remove.contains(&k));
+        if let Some(metrics) = metrics {
+            metrics.fan_out(started.elapsed());
+            metrics.send_failures(client_ids_to_remove.len());
+        }
+        room_clients_lock.retain(|k| !client_ids_to_remove.contains(k));
 
         Ok(())
     }
 
-    pub async fn all(&mut self, response_str: &str) -> Result<(), ChatError> {
-        Room::send(self, |_| true, response_str).await
+    pub async fn all(&mut self, response: &Envelope) -> Result<(), ChatError> {
+        Room::send(self, |_| true, response).await
+    }
+
+    pub async fn unmuted(&mut self, response: &Envelope) -> Result<(), ChatError> {
+        let muted_by = self.muted_by.lock().await.clone();
+        Room::send(self, |c| !muted_by.contains(&c.id), response).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::room_manager::NOT_ROOM_MODERATOR;
+    use crate::test_support::{self, TestClient};
+
+    #[tokio::test]
+    async fn moderators_update_rooms_within_the_limits() {
+        let handle = test_support::builder().start().await.unwrap();
+        let mut alice = TestClient::connect(handle.local_addr(), "alice").await;
+        let mut bob = TestClient::connect(handle.local_addr(), "bob").await;
+        let room_id = alice.create_room("team").await;
+        bob.join_room(room_id).await;
+        alice.drain().await;
+
+        bob.send(
+            "UpdateRoom",
+            json!({ "roomId": room_id, "topic": "mine now" }),
+        )
+        .await;
+        assert_eq!(bob.expect("Error").await["message"], NOT_ROOM_MODERATOR);
+
+        let topic = "t".repeat(MAX_TOPIC_LEN + 1);
+        alice
+            .send("UpdateRoom", json!({ "roomId": room_id, "topic": topic }))
+            .await;
+        assert_eq!(alice.expect("Error").await["message"], TOPIC_TOO_LONG);
+        let description = "d".repeat(MAX_DESCRIPTION_LEN + 1);
+        alice
+            .send(
+                "UpdateRoom",
+                json!({ "roomId": room_id, "description": description }),
+            )
+            .await;
+        assert_eq!(alice.expect("Error").await["message"], DESCRIPTION_TOO_LONG);
+        assert!(bob.drain().await.is_empty());
+
+        alice
+            .send(
+                "UpdateRoom",
+                json!({ "roomId": room_id, "topic": "t".repeat(MAX_TOPIC_LEN) }),
+            )
+            .await;
+        let updated = bob.expect("RoomUpdated").await;
+        assert_eq!(updated["topic"], "t".repeat(MAX_TOPIC_LEN));
+        assert_eq!(updated["updatedBy"], alice.id.to_string());
+        alice.drain().await;
+
+        // appointed moderators may update it too
+        let appoint =
+            json!({ "messageType": "Room", "receiverId": room_id, "message": "/mod bob" });
+        alice.send("Message", appoint).await;
+        let updated = alice.expect("RoomUpdated").await;
+        assert_eq!(updated["moderators"], json!([bob.id]));
+        alice.drain().await;
+        bob.drain().await;
+        bob.send(
+            "UpdateRoom",
+            json!({ "roomId": room_id, "description": "release week" }),
+        )
+        .await;
+        let updated = alice.expect("RoomUpdated").await;
+        assert_eq!(updated["description"], "release week");
+        assert_eq!(updated["updatedBy"], bob.id.to_string());
+
+        drop((alice, bob));
+        handle.shutdown().await;
     }
 }
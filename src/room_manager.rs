@@ -3,31 +3,121 @@ use std::{
     sync::Arc,
 };
 
-use tokio::sync::Mutex;
+use chrono::Utc;
+use tokio::sync::{Mutex, Notify};
 use uuid::Uuid;
 
-use crate::{room::{Room, RoomInfo}, server::WsConnections, types::ChatError, ws_client_connection::WsClientConnection};
+use crate::{
+    backplane::{Cluster, ClusterEvent},
+    codec::Envelope,
+    room::{Room, RoomInfo, RoomUpdate},
+    room_store::StoredRoom,
+    server::WsConnections,
+    types::ChatError,
+};
 
 pub type Rooms = HashMap<Uuid, Room>;
 
 pub struct RoomManager {
     pub rooms: Mutex<Rooms>,
+    // rooms and their messages are shared with the other nodes
+    cluster: Arc<Cluster>,
+    // woken when something kept in the room snapshot changed
+    changes: Arc<Notify>,
 }
 
 pub const ROOM_NOT_FOUND: &str = "Room not found";
+pub const NOT_ROOM_MEMBER: &str = "Not a room member";
+pub const NOT_ROOM_OWNER: &str = "Only the room owner can do this";
+pub const NOT_ROOM_MODERATOR: &str = "Only the room owner and moderators can do this";
+
+impl Default for RoomManager {
+    fn default() -> Self {
+        RoomManager::new(Arc::new(Cluster::local()))
+    }
+}
 
 impl RoomManager {
-    pub fn new() -> Self {
+    pub fn new(cluster: Arc<Cluster>) -> Self {
         Self {
             rooms: Mutex::new(HashMap::new()),
+            cluster,
+            changes: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Notified after changes that belong in the next room snapshot.
+    pub fn changes(&self) -> Arc<Notify> {
+        Arc::clone(&self.changes)
+    }
+
+    fn changed(&self) {
+        self.changes.notify_one();
+    }
+
+    /// The rooms as a snapshot keeps them.
+    pub async fn snapshot(&self) -> Vec<StoredRoom> {
+        let mut rooms: Vec<StoredRoom> = self
+            .rooms
+            .lock()
+            .await
+            .values()
+            .map(|room| {
+                let mut bot_accounts: Vec<String> = room.bot_accounts.iter().cloned().collect();
+                bot_accounts.sort();
+                StoredRoom {
+                    room: room.room_info(),
+                    bot_accounts,
+                    owner_account: room.owner_account.clone(),
+                }
+            })
+            .collect();
+        rooms.sort_by_key(|stored| stored.room.created_at);
+        rooms
+    }
+
+    /// Brings back the rooms of a snapshot, their bot accounts rejoin when
+    /// they connect. A bot owner gets its rooms back, other rooms have no
+    /// owner until an admin appoints one.
+    pub async fn restore(&mut self, rooms: Vec<StoredRoom>, clients: Arc<Mutex<WsConnections>>) {
+        let mut room_lock = self.rooms.lock().await;
+        for stored in rooms {
+            let mut room = Room::from_info(stored.room, Arc::clone(&clients));
+            room.bot_accounts = stored.bot_accounts.into_iter().collect();
+            // the connections they were appointed by are gone
+            room.moderators.clear();
+            room.creator_id = Uuid::nil();
+            room.owner_account = stored.owner_account;
+            room_lock.insert(room.id, room);
         }
     }
 
-    pub async fn all(&self, id: &Uuid, response_str: &str) -> Result<(), ChatError> {
+    /// Sends to the members on this node and on every other node.
+    pub async fn all(&self, id: &Uuid, response: &Envelope) -> Result<(), ChatError> {
+        self.deliver(id, false, response).await?;
+        self.cluster.room(*id, false, response).await;
+        Ok(())
+    }
+
+    pub async fn unmuted(&self, id: &Uuid, response: &Envelope) -> Result<(), ChatError> {
+        self.deliver(id, true, response).await?;
+        self.cluster.room(*id, true, response).await;
+        Ok(())
+    }
+
+    /// Sends to the members on this node only.
+    pub async fn deliver(
+        &self,
+        id: &Uuid,
+        unmuted_only: bool,
+        response: &Envelope,
+    ) -> Result<(), ChatError> {
         let mut locked_rooms = self.rooms.lock().await;
         let room = locked_rooms.get_mut(id).ok_or(ROOM_NOT_FOUND.to_owned())?;
-        room.all(response_str).await?;
-        Ok(())
+        match unmuted_only {
+            true => room.unmuted(response).await,
+            false => room.all(response).await,
+        }
     }
 
     pub async fn create(
@@ -35,30 +125,318 @@ impl RoomManager {
         conn_id: &Uuid,
         id: &Uuid,
         name: &str,
+        bot_account: Option<&str>,
         clients: Arc<Mutex<WsConnections>>,
-    ) -> Result<(), ChatError> {
+    ) -> Result<RoomInfo, ChatError> {
         let mut clients_map = HashSet::new();
-        clients_map.insert(conn_id.clone());
-
-        self.rooms.lock().await.insert(
-            id.clone(),
-            Room {
-                id: id.clone(),
-                name: name.to_owned(),
-                room_clients: Mutex::new(clients_map),
-                clients,
-            },
-        );
+        clients_map.insert(*conn_id);
 
-        Ok(())
+        let mut room = Room {
+            id: *id,
+            name: name.to_owned(),
+            creator_id: *conn_id,
+            created_at: Utc::now(),
+            moderators: HashSet::new(),
+            topic: None,
+            description: None,
+            avatar: None,
+            room_clients: Mutex::new(clients_map),
+            muted_by: Mutex::new(HashSet::new()),
+            bot_accounts: HashSet::new(),
+            bot_connections: HashMap::new(),
+            owner_account: bot_account.map(str::to_owned),
+            clients,
+        };
+        if let Some(account) = bot_account {
+            room.bot_accounts.insert(account.to_owned());
+            room.bot_connections.insert(*conn_id, account.to_owned());
+        }
+        self.rooms.lock().await.insert(*id, room);
+        self.changed();
+
+        let room = self.info(id).await?;
+        self.cluster.room_changed(room.clone()).await;
+        Ok(room)
     }
 
-    pub async fn join(&mut self, room_id: &Uuid, conn_id: &Uuid) -> Result<RoomInfo, ChatError> {
+    /// Takes a room created or changed on another node, its members there
+    /// are not known here.
+    pub async fn replicate(&mut self, info: RoomInfo, clients: Arc<Mutex<WsConnections>>) {
         let mut room_lock = self.rooms.lock().await;
-        let room = room_lock.get_mut(&room_id).ok_or(ROOM_NOT_FOUND)?;
+        match room_lock.get_mut(&info.id) {
+            Some(room) => {
+                room.creator_id = info.creator_id;
+                room.moderators = info.moderators.into_iter().collect();
+                room.topic = info.topic;
+                room.description = info.description;
+                room.avatar = info.avatar;
+            }
+            None => {
+                room_lock.insert(info.id, Room::from_info(info, clients));
+            }
+        }
+        self.changed();
+    }
+
+    /// A bot joining with its account stays a member across reconnects.
+    pub async fn join(
+        &mut self,
+        room_id: &Uuid,
+        conn_id: &Uuid,
+        bot_account: Option<&str>,
+    ) -> Result<RoomInfo, ChatError> {
+        let mut room_lock = self.rooms.lock().await;
+        let room = room_lock.get_mut(room_id).ok_or(ROOM_NOT_FOUND)?;
 
         room.add_client(conn_id).await;
+        if let Some(account) = bot_account {
+            room.bot_connections.insert(*conn_id, account.to_owned());
+            if room.bot_accounts.insert(account.to_owned()) {
+                self.changed();
+            }
+        }
+
+        Ok(room.room_info())
+    }
+
+    /// Puts a bot connection back into the rooms its account is a member
+    /// of, returns them.
+    pub async fn rejoin(&mut self, conn_id: &Uuid, bot_account: &str) -> Vec<Uuid> {
+        let mut rejoined = vec![];
+        let mut owned = vec![];
+        for room in self.rooms.lock().await.values_mut() {
+            if room.bot_accounts.contains(bot_account) {
+                room.add_client(conn_id).await;
+                room.bot_connections
+                    .insert(*conn_id, bot_account.to_owned());
+                rejoined.push(room.id);
+            }
+            if room.owner_account.as_deref() == Some(bot_account) {
+                room.creator_id = *conn_id;
+                owned.push(room.room_info());
+            }
+        }
+
+        if !owned.is_empty() {
+            self.changed();
+        }
+        for info in owned {
+            self.cluster.room_changed(info).await;
+        }
+        rejoined
+    }
+
+    pub async fn leave(&mut self, room_id: &Uuid, conn_id: &Uuid) -> Result<RoomInfo, ChatError> {
+        let mut room_lock = self.rooms.lock().await;
+        let room = room_lock.get_mut(room_id).ok_or(ROOM_NOT_FOUND)?;
+        if !room.room_clients.lock().await.contains(conn_id) {
+            return Err(NOT_ROOM_MEMBER.to_owned());
+        }
+
+        let was_moderator = room.moderators.contains(conn_id);
+        room.remove_client(conn_id).await;
+        // leaving on purpose ends the membership of the account
+        let account_left = match room.bot_connections.remove(conn_id) {
+            Some(account) => room.bot_accounts.remove(&account),
+            None => false,
+        };
+        let info = room.room_info();
+        drop(room_lock);
+
+        if was_moderator || account_left {
+            self.changed();
+        }
+        if was_moderator {
+            self.cluster.room_changed(info.clone()).await;
+        }
+        Ok(info)
+    }
+
+    pub async fn find_by_name(&self, name: &str) -> Option<Uuid> {
+        let room_lock = self.rooms.lock().await;
+        room_lock
+            .values()
+            .find(|room| room.name.eq_ignore_ascii_case(name))
+            .map(|room| room.id)
+    }
+
+    pub async fn info(&self, room_id: &Uuid) -> Result<RoomInfo, ChatError> {
+        let room_lock = self.rooms.lock().await;
+        let room = room_lock.get(room_id).ok_or(ROOM_NOT_FOUND)?;
 
         Ok(room.room_info())
     }
+
+    pub async fn is_owner(&self, room_id: &Uuid, conn_id: &Uuid) -> Result<bool, ChatError> {
+        let room_lock = self.rooms.lock().await;
+        let room = room_lock.get(room_id).ok_or(ROOM_NOT_FOUND)?;
+
+        Ok(room.creator_id == *conn_id)
+    }
+
+    pub async fn can_moderate(&self, room_id: &Uuid, conn_id: &Uuid) -> Result<bool, ChatError> {
+        let room_lock = self.rooms.lock().await;
+        let room = room_lock.get(room_id).ok_or(ROOM_NOT_FOUND)?;
+
+        Ok(room.can_moderate(conn_id))
+    }
+
+    pub async fn update(
+        &mut self,
+        room_id: &Uuid,
+        update: RoomUpdate,
+    ) -> Result<RoomInfo, ChatError> {
+        let mut room_lock = self.rooms.lock().await;
+        let room = room_lock.get_mut(room_id).ok_or(ROOM_NOT_FOUND)?;
+
+        room.update(update);
+        let info = room.room_info();
+        drop(room_lock);
+        self.changed();
+
+        self.cluster.room_changed(info.clone()).await;
+        Ok(info)
+    }
+
+    /// Makes a member the owner, for rooms whose owner is gone for good.
+    pub async fn set_owner(
+        &mut self,
+        room_id: &Uuid,
+        conn_id: &Uuid,
+        bot_account: Option<&str>,
+    ) -> Result<RoomInfo, ChatError> {
+        let mut room_lock = self.rooms.lock().await;
+        let room = room_lock.get_mut(room_id).ok_or(ROOM_NOT_FOUND)?;
+        if !room.room_clients.lock().await.contains(conn_id) {
+            return Err(NOT_ROOM_MEMBER.to_owned());
+        }
+
+        room.creator_id = *conn_id;
+        room.owner_account = bot_account.map(str::to_owned);
+        room.moderators.remove(conn_id);
+        let info = room.room_info();
+        drop(room_lock);
+        self.changed();
+
+        self.cluster.room_changed(info.clone()).await;
+        Ok(info)
+    }
+
+    pub async fn set_moderator(
+        &mut self,
+        room_id: &Uuid,
+        conn_id: &Uuid,
+        moderator: bool,
+    ) -> Result<RoomInfo, ChatError> {
+        let mut room_lock = self.rooms.lock().await;
+        let room = room_lock.get_mut(room_id).ok_or(ROOM_NOT_FOUND)?;
+        if !room.room_clients.lock().await.contains(conn_id) {
+            return Err(NOT_ROOM_MEMBER.to_owned());
+        }
+
+        if moderator {
+            room.moderators.insert(*conn_id);
+        } else {
+            room.moderators.remove(conn_id);
+        }
+        let info = room.room_info();
+        drop(room_lock);
+        self.changed();
+
+        self.cluster.room_changed(info.clone()).await;
+        Ok(info)
+    }
+
+    pub async fn is_member(&self, room_id: &Uuid, conn_id: &Uuid) -> Result<bool, ChatError> {
+        let room_lock = self.rooms.lock().await;
+        let room = room_lock.get(room_id).ok_or(ROOM_NOT_FOUND)?;
+
+        let is_member = room.room_clients.lock().await.contains(conn_id);
+        Ok(is_member)
+    }
+
+    pub async fn members(&self, room_id: &Uuid) -> Result<HashSet<Uuid>, ChatError> {
+        let room_lock = self.rooms.lock().await;
+        let room = room_lock.get(room_id).ok_or(ROOM_NOT_FOUND)?;
+
+        let members = room.room_clients.lock().await.clone();
+        Ok(members)
+    }
+
+    pub async fn set_muted(
+        &mut self,
+        room_id: &Uuid,
+        conn_id: &Uuid,
+        muted: bool,
+    ) -> Result<(), ChatError> {
+        let mut room_lock = self.rooms.lock().await;
+        let room = room_lock.get_mut(room_id).ok_or(ROOM_NOT_FOUND)?;
+        if !room.room_clients.lock().await.contains(conn_id) {
+            return Err(NOT_ROOM_MEMBER.to_owned());
+        }
+
+        room.set_muted(conn_id, muted).await;
+        Ok(())
+    }
+
+    /// Deletes the room on every node. Returns the members it had here.
+    pub async fn delete(&mut self, room_id: &Uuid) -> Result<HashSet<Uuid>, ChatError> {
+        let members = self.forget(room_id).await?;
+        self.cluster
+            .publish(ClusterEvent::RoomDeleted { room_id: *room_id })
+            .await;
+        Ok(members)
+    }
+
+    /// Deletes the room on this node only.
+    pub async fn forget(&mut self, room_id: &Uuid) -> Result<HashSet<Uuid>, ChatError> {
+        let room = self
+            .rooms
+            .lock()
+            .await
+            .remove(room_id)
+            .ok_or(ROOM_NOT_FOUND)?;
+        self.changed();
+
+        let members = room.room_clients.lock().await.clone();
+        Ok(members)
+    }
+
+    pub async fn list(&self) -> Vec<RoomInfo> {
+        let mut rooms: Vec<RoomInfo> = self
+            .rooms
+            .lock()
+            .await
+            .values()
+            .map(|room| room.room_info())
+            .collect();
+        rooms.sort_by_key(|room| room.created_at);
+        rooms
+    }
+
+    /// Returns the rooms the client was a member of.
+    pub async fn leave_all(&mut self, conn_id: &Uuid) -> Vec<Uuid> {
+        let mut left = vec![];
+        let mut changed = vec![];
+        for room in self.rooms.lock().await.values_mut() {
+            if room.room_clients.lock().await.contains(conn_id) {
+                left.push(room.id);
+            }
+            let was_moderator = room.moderators.contains(conn_id);
+            room.remove_client(conn_id).await;
+            // the account of a bot stays a member
+            room.bot_connections.remove(conn_id);
+            if was_moderator {
+                changed.push(room.room_info());
+            }
+        }
+
+        if !changed.is_empty() {
+            self.changed();
+        }
+        for info in changed {
+            self.cluster.room_changed(info).await;
+        }
+        left
+    }
 }
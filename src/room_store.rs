@@ -0,0 +1,150 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::room::RoomInfo;
+
+// changes made within this time go into the same snapshot
+pub const SNAPSHOT_DELAY: Duration = Duration::from_millis(500);
+
+/// A room as it is kept across restarts. Connections do not survive a
+/// restart, so only bot accounts, which reconnect under the same name, stay
+/// members. A bot owner is kept by its account, rooms of other owners stay
+/// without one until an admin appoints a new owner, moderators are
+/// appointed again.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct StoredRoom {
+    #[serde(flatten)]
+    pub room: RoomInfo,
+    pub bot_accounts: Vec<String>,
+    #[serde(default)]
+    pub owner_account: Option<String>,
+}
+
+/// Where rooms are kept across restarts, set with `ServerBuilder::room_store`.
+/// Without one rooms live in memory only.
+pub trait RoomStore: Send + Sync {
+    /// The rooms of the last snapshot, none when there is no snapshot yet.
+    fn load(&self) -> std::io::Result<Vec<StoredRoom>>;
+    /// Replaces the snapshot with every room there is.
+    fn save(&self, rooms: &[StoredRoom]) -> std::io::Result<()>;
+}
+
+/// Keeps the snapshot in a JSON file.
+pub struct FileRoomStore {
+    path: PathBuf,
+}
+
+impl FileRoomStore {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl RoomStore for FileRoomStore {
+    fn load(&self) -> std::io::Result<Vec<StoredRoom>> {
+        match fs::read(&self.path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn save(&self, rooms: &[StoredRoom]) -> std::io::Result<()> {
+        let bytes = serde_json::to_vec_pretty(rooms)?;
+        // a crash while writing leaves the previous snapshot in place
+        let temp = self.path.with_extension("tmp");
+        let mut file = File::create(&temp)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        fs::rename(&temp, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use chrono::Utc;
+    use tokio::sync::Mutex;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::room_manager::RoomManager;
+
+    fn stored(name: &str, owner_account: Option<&str>) -> StoredRoom {
+        StoredRoom {
+            room: RoomInfo {
+                id: Uuid::new_v4(),
+                name: name.to_owned(),
+                topic: Some("plans".to_owned()),
+                description: None,
+                avatar: None,
+                created_at: Utc::now(),
+                // connection ids of the previous run
+                creator_id: Uuid::new_v4(),
+                moderators: vec![Uuid::new_v4()],
+            },
+            bot_accounts: owner_account.into_iter().map(str::to_owned).collect(),
+            owner_account: owner_account.map(str::to_owned),
+        }
+    }
+
+    #[tokio::test]
+    async fn restored_rooms_keep_bot_owners_only() {
+        let path = std::env::temp_dir().join(format!("rooms-{}.json", Uuid::new_v4()));
+        let store = FileRoomStore::new(&path);
+        assert!(store.load().unwrap().is_empty());
+        let (bot_room, team_room) = (stored("builds", Some("ci")), stored("team", None));
+        store.save(&[bot_room.clone(), team_room.clone()]).unwrap();
+
+        let rooms = store.load().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(rooms.len(), 2);
+        assert_eq!(rooms[0].room.name, "builds");
+        assert_eq!(rooms[0].room.topic.as_deref(), Some("plans"));
+        assert_eq!(rooms[0].bot_accounts, vec!["ci".to_owned()]);
+        assert_eq!(rooms[0].owner_account.as_deref(), Some("ci"));
+        assert_eq!(rooms[1].owner_account, None);
+
+        let mut room_manager = RoomManager::default();
+        room_manager
+            .restore(rooms, Arc::new(Mutex::new(HashMap::new())))
+            .await;
+        let (bot_room, team_room) = (bot_room.room.id, team_room.room.id);
+        assert!(room_manager
+            .info(&team_room)
+            .await
+            .unwrap()
+            .moderators
+            .is_empty());
+
+        // the bot owner reconnects under its account
+        let ci = Uuid::new_v4();
+        assert_eq!(room_manager.rejoin(&ci, "ci").await, vec![bot_room]);
+        assert!(room_manager.is_owner(&bot_room, &ci).await.unwrap());
+
+        // nobody takes over a room whose owner cannot come back by joining
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        room_manager.join(&team_room, &alice, None).await.unwrap();
+        room_manager.join(&team_room, &bob, None).await.unwrap();
+        assert!(!room_manager.is_owner(&team_room, &alice).await.unwrap());
+        assert!(!room_manager.can_moderate(&team_room, &bob).await.unwrap());
+        room_manager.join(&bot_room, &alice, None).await.unwrap();
+        assert!(!room_manager.is_owner(&bot_room, &alice).await.unwrap());
+
+        // until an admin appoints a member
+        room_manager
+            .set_owner(&team_room, &bob, None)
+            .await
+            .unwrap();
+        assert!(room_manager.is_owner(&team_room, &bob).await.unwrap());
+        assert!(!room_manager.is_owner(&team_room, &alice).await.unwrap());
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::BTreeMap;
+
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde_json::{json, Value};
+
+use crate::{requests, responses};
+
+fn payload<T: JsonSchema>(gen: &mut SchemaGenerator) -> Option<Schema> {
+    Some(gen.subschema_for::<T>())
+}
+
+fn no_payload(_: &mut SchemaGenerator) -> Option<Schema> {
+    None
+}
+
+type PayloadFn = fn(&mut SchemaGenerator) -> Option<Schema>;
+
+// keyed by `RequestType`, `None` for requests without data
+const REQUESTS: &[(&str, PayloadFn)] = &[
+    ("Hello", payload::<requests::Hello>),
+    ("GetId", no_payload),
+    ("SetNickname", payload::<requests::SetNickname>),
+    ("Online", no_payload),
+    ("Message", payload::<requests::Message>),
+    ("Disconnected", no_payload),
+    ("GlobalOnline", no_payload),
+    ("CreateRoom", payload::<requests::CreateRoom>),
+    ("JoinRoom", payload::<requests::JoinRoom>),
+    (
+        "SubscribePresence",
+        payload::<requests::PresenceSubscription>,
+    ),
+    (
+        "UnsubscribePresence",
+        payload::<requests::PresenceSubscription>,
+    ),
+    ("GetHistory", payload::<requests::GetHistory>),
+    ("GetThread", payload::<requests::GetThread>),
+    ("AddReaction", payload::<requests::Reaction>),
+    ("RemoveReaction", payload::<requests::Reaction>),
+    ("MuteRoom", payload::<requests::MuteRoom>),
+    ("GetMentions", no_payload),
+    ("ClearMentions", payload::<requests::ClearMentions>),
+    ("UploadStart", payload::<requests::UploadStart>),
+    ("UploadChunk", payload::<requests::UploadChunk>),
+    ("Download", payload::<requests::Download>),
+    ("RegisterCommands", payload::<requests::RegisterCommands>),
+    ("LeaveRoom", payload::<requests::LeaveRoom>),
+    ("UpdateRoom", payload::<requests::UpdateRoom>),
+    ("Announce", payload::<requests::Announce>),
+    ("Unpin", payload::<requests::Unpin>),
+];
+
+// keyed by `ResponseType`
+const RESPONSES: &[(&str, PayloadFn)] = &[
+    ("Hello", payload::<responses::Hello>),
+    ("GetId", payload::<responses::GetId>),
+    ("Online", payload::<responses::Online>),
+    ("Offline", payload::<responses::Offline>),
+    ("SetNickname", payload::<responses::SetNickname>),
+    ("Message", payload::<responses::Message>),
+    ("GlobalOnline", payload::<responses::GlobalOnline>),
+    ("RoomCreated", payload::<responses::RoomCreated>),
+    ("RoomJoined", payload::<responses::RoomJoined>),
+    (
+        "PresenceSubscribed",
+        payload::<responses::PresenceSubscribed>,
+    ),
+    ("ThreadReply", payload::<responses::ThreadReply>),
+    ("Thread", payload::<responses::Thread>),
+    ("History", payload::<responses::History>),
+    ("ReactionsUpdated", payload::<responses::ReactionsUpdated>),
+    ("RoomMuted", payload::<responses::RoomMuted>),
+    ("Mentioned", payload::<responses::Mentioned>),
+    ("UnreadMentions", payload::<responses::UnreadMentions>),
+    ("UploadStarted", payload::<responses::UploadStarted>),
+    ("UploadCompleted", payload::<responses::UploadCompleted>),
+    ("Download", payload::<responses::Download>),
+    ("DownloadChunk", payload::<responses::DownloadChunk>),
+    (
+        "CommandsRegistered",
+        payload::<responses::CommandsRegistered>,
+    ),
+    ("BotCommand", payload::<responses::BotCommand>),
+    ("RoomLeft", payload::<responses::RoomLeft>),
+    ("SystemMessage", payload::<responses::SystemMessage>),
+    ("RoomUpdated", payload::<responses::RoomUpdated>),
+    ("MemberJoined", payload::<responses::MemberJoined>),
+    ("MemberLeft", payload::<responses::MemberLeft>),
+    ("Announced", payload::<responses::Announced>),
+    (
+        "AnnouncementUnpinned",
+        payload::<responses::AnnouncementUnpinned>,
+    ),
+    ("RoomDeleted", payload::<responses::RoomDeleted>),
+    ("Error", payload::<responses::ResponseError>),
+];
+
+fn payloads(gen: &mut SchemaGenerator, entries: &[(&str, PayloadFn)]) -> BTreeMap<String, Value> {
+    entries
+        .iter()
+        .map(|(name, schema)| (name.to_string(), json!(schema(gen))))
+        .collect()
+}
+
+fn generator(definitions_path: &str) -> SchemaGenerator {
+    let mut settings = SchemaSettings::draft07();
+    settings.definitions_path = definitions_path.to_owned();
+    settings.into_generator()
+}
+
+/// JSON Schema of every request and response payload. In v1 frames the
+/// payload is the `data` field, in v2 frames it is inlined next to a `type`
+/// tag holding the camelCase name (`SetNickname` -> `setNickname`).
+// requests and responses share type names, so each side keeps its own definitions
+pub fn protocol_schema() -> Value {
+    let mut request_gen = generator("#/requestDefinitions/");
+    let mut response_gen = generator("#/responseDefinitions/");
+    let requests = payloads(&mut request_gen, REQUESTS);
+    let responses = payloads(&mut response_gen, RESPONSES);
+
+    json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "title": "chat-server protocol",
+        "requests": requests,
+        "responses": responses,
+        "requestDefinitions": request_gen.definitions(),
+        "responseDefinitions": response_gen.definitions(),
+    })
+}
+
+pub fn protocol_schema_string() -> String {
+    serde_json::to_string_pretty(&protocol_schema()).unwrap() + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use schemars::schema_for;
+
+    use super::*;
+
+    fn enum_names<T: JsonSchema>() -> BTreeSet<String> {
+        let schema = serde_json::to_value(schema_for!(T)).unwrap();
+        schema["enum"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|name| name.as_str().unwrap().to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn every_type_has_a_payload_entry() {
+        let request_names: BTreeSet<String> =
+            REQUESTS.iter().map(|(name, _)| name.to_string()).collect();
+        let response_names: BTreeSet<String> =
+            RESPONSES.iter().map(|(name, _)| name.to_string()).collect();
+
+        assert_eq!(request_names, enum_names::<requests::RequestType>());
+        assert_eq!(response_names, enum_names::<responses::ResponseType>());
+    }
+
+    #[test]
+    fn checked_in_schema_is_up_to_date() {
+        let checked_in: Value =
+            serde_json::from_str(include_str!("../schema/protocol.json")).unwrap();
+
+        assert!(
+            checked_in == protocol_schema(),
+            "schema/protocol.json is stale, run `cargo run -- schema > schema/protocol.json`"
+        );
+    }
+}
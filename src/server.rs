@@ -1,64 +1,366 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use std::time::Duration;
 
-use tokio::net::{TcpListener, ToSocketAddrs};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
 use tokio::sync::mpsc::{self, Receiver, Sender};
-use tokio::sync::Mutex;
+use tokio::sync::{watch, Mutex};
 use tokio::task::JoinHandle;
-use tokio_tungstenite::accept_async;
+use tokio_tungstenite::tungstenite::handshake;
+use tokio_tungstenite::tungstenite::http::header::{HeaderValue, SEC_WEBSOCKET_PROTOCOL};
+use tokio_tungstenite::tungstenite::http::{Response, StatusCode};
+use tokio_tungstenite::{accept_hdr_async, tungstenite, WebSocketStream};
+use tracing::{debug, info, warn};
 use uuid::Uuid;
 
+use crate::admin;
+use crate::announcements::Announcements;
+use crate::auth::{self, AllowAll, Authenticator, Identity};
+use crate::backplane::{Backplane, Cluster, ClusterMessage};
+use crate::capabilities::{Feature, ENABLED_FEATURES};
+use crate::codec::{Codec, ProtocolVersion};
+use crate::commands::{Command, CommandRegistry};
+use crate::config::Config;
+use crate::health::{self, Health, Task};
+use crate::history::History;
+use crate::hooks::{ChatHook, Hooks};
+use crate::http::{self, HttpRequest, HttpResponse};
+use crate::metrics::{self, ErrorKind, Metrics};
+use crate::presence::PresenceManager;
+use crate::rate_limit::TokenBucket;
 use crate::requests::Request;
 use crate::room_manager::RoomManager;
+use crate::room_store::{RoomStore, SNAPSHOT_DELAY};
+use crate::schema;
 use crate::service;
+use crate::storage::{AttachmentStorage, LocalStorage};
+use crate::uploads::UploadManager;
 use crate::ws_client_connection::WsClientConnection;
 
 pub type WsConnections = HashMap<Uuid, WsClientConnection>;
 
 pub const CLIENT_NOT_FOUND: &str = "Client not found";
+pub const NO_LISTEN_ADDRESS: &str = "No address or listener to serve on";
+pub const ADMIN_TOKEN_REQUIRED: &str = "The admin API needs an admin token";
+// how long connections get to finish their close handshake
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Completes the websocket handshake, picking the codec and protocol version
+/// from the subprotocols the client offered. Plain v1 json when none is
+/// supported, v2 can still be requested later with a `Hello`.
+// the handshake callback signature is dictated by tungstenite
+#[allow(clippy::result_large_err)]
+async fn accept(
+    stream: TcpStream,
+    auth: &dyn Authenticator,
+    admin_token: Option<&str>,
+) -> Result<(WebSocketStream<TcpStream>, Codec, ProtocolVersion, Identity), tungstenite::Error> {
+    let mut codec = Codec::Json;
+    let mut protocol = ProtocolVersion::V1;
+    let mut identity = Identity::default();
+    let callback = |request: &handshake::server::Request,
+                    mut response: handshake::server::Response| {
+        let admin = admin_token.is_some_and(|token| {
+            auth::bearer_token(request).is_some_and(|given| auth::token_matches(token, given))
+        });
+        identity = if admin {
+            Identity {
+                admin: true,
+                ..Identity::default()
+            }
+        } else {
+            auth.authenticate(request).map_err(|err| {
+                let mut response = Response::new(Some(err));
+                *response.status_mut() = StatusCode::UNAUTHORIZED;
+                response
+            })?
+        };
+
+        let offered = request
+            .headers()
+            .get(SEC_WEBSOCKET_PROTOCOL)
+            .and_then(|value| value.to_str().ok())
+            .and_then(Codec::negotiate);
+        if let Some((negotiated_codec, negotiated_protocol, name)) = offered {
+            codec = negotiated_codec;
+            protocol = negotiated_protocol;
+            if let Ok(value) = HeaderValue::from_str(name) {
+                response.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, value);
+            }
+        }
+        Ok(response)
+    };
+
+    let web_socket = accept_hdr_async(stream, callback).await?;
+    Ok((web_socket, codec, protocol, identity))
+}
+
+/// Everything the request handlers work on, shared by all tasks of a server.
+#[derive(Clone)]
+pub struct ServerState {
+    pub clients: Arc<Mutex<WsConnections>>,
+    pub room_manager: Arc<Mutex<RoomManager>>,
+    pub presence: Arc<Mutex<PresenceManager>>,
+    pub history: Arc<Mutex<History>>,
+    pub uploads: Arc<Mutex<UploadManager>>,
+    pub config: Arc<Config>,
+    pub auth: Arc<dyn Authenticator>,
+    pub hooks: Arc<Hooks>,
+    pub commands: Arc<CommandRegistry>,
+    pub announcements: Arc<Mutex<Announcements>>,
+    pub bans: Arc<Mutex<HashSet<IpAddr>>>,
+    pub health: Arc<Health>,
+    // counters of this server only, several may run in one process
+    pub metrics: Arc<Metrics>,
+    pub cluster: Arc<Cluster>,
+    pub room_store: Option<Arc<dyn RoomStore>>,
+}
+
+impl ServerState {
+    pub fn features(&self) -> Vec<Feature> {
+        let mut features = ENABLED_FEATURES.to_vec();
+        if self.auth.required() {
+            features.push(Feature::Auth);
+        }
+        features
+    }
+}
+
+/// Configures a [`Server`], every part but the address has a default.
+pub struct ServerBuilder {
+    addr: Option<String>,
+    listener: Option<TcpListener>,
+    config: Config,
+    storage: Option<Box<dyn AttachmentStorage>>,
+    auth: Arc<dyn Authenticator>,
+    hooks: Hooks,
+    commands: CommandRegistry,
+    backplane: Option<Arc<dyn Backplane>>,
+    room_store: Option<Arc<dyn RoomStore>>,
+}
+
+impl ServerBuilder {
+    pub fn new() -> Self {
+        Self {
+            addr: None,
+            listener: None,
+            config: Config::default(),
+            storage: None,
+            auth: Arc::new(AllowAll),
+            hooks: Hooks::new(),
+            commands: CommandRegistry::new(),
+            backplane: None,
+            room_store: None,
+        }
+    }
+
+    pub fn bind(mut self, addr: impl Into<String>) -> Self {
+        self.addr = Some(addr.into());
+        self
+    }
+
+    /// Serves on an already bound listener, takes precedence over `bind`.
+    pub fn listener(mut self, listener: TcpListener) -> Self {
+        self.listener = Some(listener);
+        self
+    }
+
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Attachment storage, local files under `Config::upload_dir` by default.
+    pub fn storage(mut self, storage: impl AttachmentStorage + 'static) -> Self {
+        self.storage = Some(Box::new(storage));
+        self
+    }
+
+    pub fn auth(mut self, auth: impl Authenticator + 'static) -> Self {
+        self.auth = Arc::new(auth);
+        self
+    }
+
+    /// Adds a hook, hooks run in the order they were added.
+    pub fn hook(mut self, hook: impl ChatHook + 'static) -> Self {
+        self.hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Adds a slash command, replacing a built-in one of the same name.
+    pub fn command(mut self, command: impl Command + 'static) -> Self {
+        self.commands.register(Arc::new(command));
+        self
+    }
+
+    /// Joins a cluster, the server runs alone without one.
+    pub fn backplane(mut self, backplane: impl Backplane + 'static) -> Self {
+        self.backplane = Some(Arc::new(backplane));
+        self
+    }
+
+    /// Keeps rooms across restarts, they live in memory only without one.
+    pub fn room_store(mut self, store: impl RoomStore + 'static) -> Self {
+        self.room_store = Some(Arc::new(store));
+        self
+    }
+
+    pub async fn build(self) -> Result<Server, std::io::Error> {
+        let tcp_listener = match (self.listener, self.addr) {
+            (Some(listener), _) => listener,
+            (None, Some(addr)) => TcpListener::bind(addr).await?,
+            (None, None) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    NO_LISTEN_ADDRESS,
+                ))
+            }
+        };
+        let storage = match self.storage {
+            Some(storage) => storage,
+            None => Box::new(LocalStorage::new(&self.config.upload_dir)?),
+        };
+        let cluster = Arc::new(match self.backplane {
+            Some(backplane) => Cluster::new(backplane),
+            None => Cluster::local(),
+        });
+        let clients = Arc::new(Mutex::new(HashMap::new()));
+        let mut room_manager = RoomManager::new(Arc::clone(&cluster));
+        if let Some(store) = &self.room_store {
+            let rooms = store.load()?;
+            info!(rooms = rooms.len(), "rooms restored");
+            room_manager.restore(rooms, Arc::clone(&clients)).await;
+        }
+
+        Ok(Server {
+            state: ServerState {
+                clients,
+                room_manager: Arc::new(Mutex::new(room_manager)),
+                presence: Arc::new(Mutex::new(PresenceManager::new())),
+                history: Arc::new(Mutex::new(History::new())),
+                uploads: Arc::new(Mutex::new(UploadManager::new(storage))),
+                config: Arc::new(self.config),
+                auth: self.auth,
+                hooks: Arc::new(self.hooks),
+                commands: Arc::new(self.commands),
+                announcements: Arc::new(Mutex::new(Announcements::new())),
+                bans: Arc::new(Mutex::new(HashSet::new())),
+                health: Arc::new(Health::new()),
+                metrics: Arc::new(Metrics::new()),
+                cluster,
+                room_store: self.room_store,
+            },
+            tcp_listener,
+        })
+    }
+
+    pub async fn start(self) -> Result<ServerHandle, std::io::Error> {
+        self.build().await?.start().await
+    }
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        ServerBuilder::new()
+    }
+}
 
 pub struct Server {
-    clients: Arc<Mutex<WsConnections>>,
-    tcp_listener: Arc<Mutex<TcpListener>>,
-    room_manager: Arc<Mutex<RoomManager>>,
+    state: ServerState,
+    tcp_listener: TcpListener,
 }
 
 impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::new()
+    }
+
+    /// A server with the default configuration.
     pub async fn new<A: ToSocketAddrs>(addr: A) -> Result<Self, std::io::Error> {
         let tcp_listener = TcpListener::bind(addr).await?;
+        Server::builder().listener(tcp_listener).build().await
+    }
 
-        Ok(Self {
-            clients: Arc::new(Mutex::new(HashMap::new())),
-            tcp_listener: Arc::new(Mutex::new(tcp_listener)),
-            room_manager: Arc::new(Mutex::new(RoomManager::new())),
-        })
+    pub fn state(&self) -> &ServerState {
+        &self.state
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, std::io::Error> {
+        self.tcp_listener.local_addr()
     }
 
     pub fn start_listen(
-        tcp_listener: Arc<Mutex<TcpListener>>,
-        clients: Arc<Mutex<HashMap<Uuid, WsClientConnection>>>,
+        tcp_listener: TcpListener,
+        state: ServerState,
         sender: Sender<(Uuid, Request)>,
+        mut shutdown: watch::Receiver<bool>,
     ) -> JoinHandle<()> {
+        let running = state.health.running(Task::Listener);
         tokio::spawn(async move {
+            let _running = running;
             loop {
-                let stream_result = tcp_listener.lock().await.accept().await;
+                let stream_result = tokio::select! {
+                    stream_result = tcp_listener.accept() => stream_result,
+                    _ = shutdown.changed() => break,
+                };
+                // banned addresses are dropped before the handshake
+                if let Ok((_, remote_addr)) = &stream_result {
+                    if state.bans.lock().await.contains(&remote_addr.ip()) {
+                        info!(address = %remote_addr.ip(), "refused banned address");
+                        continue;
+                    }
+                }
                 match stream_result {
-                    Ok((stream, _)) => match accept_async(stream).await {
-                        Ok(web_socket) => {
-                            let mut clients = clients.lock().await;
+                    Ok((stream, remote_addr)) => match accept(
+                        stream,
+                        state.auth.as_ref(),
+                        state.config.admin_token.as_deref(),
+                    )
+                    .await
+                    {
+                        Ok((web_socket, codec, protocol, identity)) => {
+                            let mut clients = state.clients.lock().await;
                             let client_id = Uuid::new_v4();
-                            clients.insert(
+                            let mut connection = WsClientConnection::new(
                                 client_id,
-                                WsClientConnection::new(client_id, web_socket, sender.clone()),
+                                web_socket,
+                                codec,
+                                protocol,
+                                sender.clone(),
+                                Arc::clone(&state.metrics),
+                            );
+                            connection.name = identity.name;
+                            connection.bot = identity.bot;
+                            connection.admin = identity.admin;
+                            connection.set_remote_addr(remote_addr);
+                            let message_rate = match identity.bot {
+                                true => state.config.bot_message_rate,
+                                false => state.config.message_rate,
+                            };
+                            connection.message_rate = message_rate.map(TokenBucket::new);
+                            if let Err(err) =
+                                service::greet(&mut connection, state.features()).await
+                            {
+                                warn!(parent: &connection.span, error = %err, "hello send failed");
+                            }
+                            info!(
+                                parent: &connection.span,
+                                bot = connection.bot,
+                                admin = connection.admin,
+                                clients = clients.len() + 1,
+                                "client connected"
                             );
-                            println!("Client connected: Count: {}", clients.len());
+                            clients.insert(client_id, connection);
+                            tokio::spawn(service::connected(client_id, state.clone()));
                         }
                         Err(err) => {
-                            println!("Accept websocket: {}", err)
+                            state.metrics.error(ErrorKind::Handshake);
+                            info!(%remote_addr, error = %err, "websocket handshake failed")
                         }
                     },
                     Err(err) => {
-                        println!("Stream error: {}", err)
+                        warn!(error = %err, "accept failed")
                     }
                 }
             }
@@ -66,35 +368,254 @@ impl Server {
     }
 
     pub fn start_receiver(
-        clients: Arc<Mutex<WsConnections>>,
-        room_manager: Arc<Mutex<RoomManager>>,
+        state: ServerState,
         mut receiver: Receiver<(Uuid, Request)>,
     ) -> JoinHandle<()> {
+        let running = state.health.running(Task::Receiver);
         tokio::spawn(async move {
+            let _running = running;
             while let Some((client_id, request)) = receiver.recv().await {
-                service::switch_request(
-                    client_id,
-                    request,
-                    Arc::clone(&clients),
-                    Arc::clone(&room_manager),
-                )
-                .await;
+                service::switch_request(client_id, request, state.clone()).await;
             }
         })
     }
 
-    pub fn start(&mut self) -> JoinHandle<()> {
-        let (sender, receiver) = mpsc::channel::<(Uuid, Request)>(32);
+    /// Handles what the other nodes of the cluster publish.
+    pub fn start_backplane(
+        state: ServerState,
+        mut receiver: Receiver<ClusterMessage>,
+    ) -> JoinHandle<()> {
+        let running = state.health.running(Task::Backplane);
+        tokio::spawn(async move {
+            let _running = running;
+            while let Some(message) = receiver.recv().await {
+                service::cluster_event(message, &state).await;
+            }
+            warn!("backplane subscription ended");
+        })
+    }
 
-        Server::start_receiver(
-            Arc::clone(&self.clients),
-            Arc::clone(&self.room_manager),
-            receiver,
-        );
-        Server::start_listen(
-            Arc::clone(&self.tcp_listener),
-            Arc::clone(&self.clients),
+    /// Renews the lease on this node's users three times per lease.
+    pub fn start_heartbeat(state: ServerState) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(state.config.presence_lease / 3);
+            loop {
+                interval.tick().await;
+                service::heartbeat(&state).await;
+            }
+        })
+    }
+
+    /// Saves a room snapshot shortly after rooms change.
+    pub fn start_room_store(state: ServerState, store: Arc<dyn RoomStore>) -> JoinHandle<()> {
+        let running = state.health.running(Task::RoomStore);
+        tokio::spawn(async move {
+            let _running = running;
+            let changes = state.room_manager.lock().await.changes();
+            loop {
+                changes.notified().await;
+                tokio::time::sleep(SNAPSHOT_DELAY).await;
+                Server::save_rooms(&state, Arc::clone(&store)).await;
+            }
+        })
+    }
+
+    pub async fn save_rooms(state: &ServerState, store: Arc<dyn RoomStore>) {
+        let rooms = state.room_manager.lock().await.snapshot().await;
+        let count = rooms.len();
+        match tokio::task::spawn_blocking(move || store.save(&rooms)).await {
+            Ok(Ok(())) => debug!(rooms = count, "room snapshot saved"),
+            Ok(Err(err)) => warn!(error = %err, "room snapshot failed"),
+            Err(err) => warn!(error = %err, "room snapshot failed"),
+        }
+    }
+
+    async fn http_route(request: HttpRequest, state: ServerState) -> HttpResponse {
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/schema") => HttpResponse::json(200, schema::protocol_schema_string()),
+            ("GET", "/metrics") => HttpResponse::new(
+                200,
+                "text/plain; version=0.0.4; charset=utf-8",
+                metrics::render(&state).await,
+            ),
+            ("GET", "/healthz" | "/livez") => health::healthz(&state),
+            ("GET", "/readyz") => health::readyz(&state).await,
+            (_, "/schema" | "/metrics" | "/healthz" | "/livez" | "/readyz") => {
+                HttpResponse::method_not_allowed()
+            }
+            _ => HttpResponse::not_found(),
+        }
+    }
+
+    /// Serves the admin API on its own address.
+    pub async fn start_admin(
+        addr: &str,
+        state: ServerState,
+    ) -> Result<(JoinHandle<()>, SocketAddr), std::io::Error> {
+        let listener = TcpListener::bind(addr).await?;
+        let admin_addr = listener.local_addr()?;
+        let admin = http::serve(listener, move |request| {
+            admin::route(request, state.clone())
+        });
+        Ok((admin, admin_addr))
+    }
+
+    /// Serves the side HTTP endpoints on their own address.
+    pub async fn start_http(
+        addr: &str,
+        state: ServerState,
+    ) -> Result<(JoinHandle<()>, SocketAddr), std::io::Error> {
+        let listener = TcpListener::bind(addr).await?;
+        let http_addr = listener.local_addr()?;
+        let http = http::serve(listener, move |request| {
+            Server::http_route(request, state.clone())
+        });
+        Ok((http, http_addr))
+    }
+
+    pub async fn start(self) -> Result<ServerHandle, std::io::Error> {
+        let local_addr = self.tcp_listener.local_addr()?;
+        let (http, http_addr) = match &self.state.config.http_addr {
+            Some(addr) => {
+                let (http, http_addr) = Server::start_http(addr, self.state.clone()).await?;
+                (Some(http), Some(http_addr))
+            }
+            None => (None, None),
+        };
+        let (admin, admin_addr) = match &self.state.config.admin_addr {
+            Some(_) if self.state.config.admin_token.is_none() => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    ADMIN_TOKEN_REQUIRED,
+                ))
+            }
+            Some(addr) => {
+                let (admin, admin_addr) = Server::start_admin(addr, self.state.clone()).await?;
+                (Some(admin), Some(admin_addr))
+            }
+            None => (None, None),
+        };
+
+        let (backplane, heartbeat) = match self.state.cluster.backplane() {
+            Some(backplane) => {
+                let subscription = backplane.subscribe().await.map_err(|err| {
+                    std::io::Error::new(std::io::ErrorKind::ConnectionRefused, err)
+                })?;
+                info!(node_id = %self.state.cluster.node_id, "joined cluster");
+                (
+                    Some(Server::start_backplane(self.state.clone(), subscription)),
+                    Some(Server::start_heartbeat(self.state.clone())),
+                )
+            }
+            None => (None, None),
+        };
+
+        let room_store = self
+            .state
+            .room_store
+            .clone()
+            .map(|store| Server::start_room_store(self.state.clone(), store));
+
+        let (sender, receiver) = mpsc::channel::<(Uuid, Request)>(self.state.config.request_buffer);
+        let (shutdown, shutdown_receiver) = watch::channel(false);
+
+        let receiver = Server::start_receiver(self.state.clone(), receiver);
+        let listener = Server::start_listen(
+            self.tcp_listener,
+            self.state.clone(),
             sender,
-        )
+            shutdown_receiver,
+        );
+
+        Ok(ServerHandle {
+            state: self.state,
+            local_addr,
+            http_addr,
+            admin_addr,
+            shutdown,
+            listener,
+            receiver,
+            http,
+            admin,
+            backplane,
+            heartbeat,
+            room_store,
+        })
+    }
+}
+
+/// A running server, dropping it leaves the server running.
+pub struct ServerHandle {
+    state: ServerState,
+    local_addr: SocketAddr,
+    http_addr: Option<SocketAddr>,
+    admin_addr: Option<SocketAddr>,
+    shutdown: watch::Sender<bool>,
+    listener: JoinHandle<()>,
+    receiver: JoinHandle<()>,
+    http: Option<JoinHandle<()>>,
+    admin: Option<JoinHandle<()>>,
+    backplane: Option<JoinHandle<()>>,
+    heartbeat: Option<JoinHandle<()>>,
+    room_store: Option<JoinHandle<()>>,
+}
+
+impl ServerHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn http_addr(&self) -> Option<SocketAddr> {
+        self.http_addr
+    }
+
+    pub fn admin_addr(&self) -> Option<SocketAddr> {
+        self.admin_addr
+    }
+
+    pub fn state(&self) -> &ServerState {
+        &self.state
+    }
+
+    /// Stops accepting, closes every connection and waits for their
+    /// disconnects to be handled, up to `SHUTDOWN_TIMEOUT`.
+    pub async fn shutdown(self) {
+        self.state.health.start_draining();
+        let _ = self.shutdown.send(true);
+        let _ = self.listener.await;
+
+        for connection in self.state.clients.lock().await.values_mut() {
+            if let Err(err) = connection.close().await {
+                warn!(parent: &connection.span, error = %err, "close failed");
+            }
+        }
+
+        // the receiver ends once every connection reader has dropped its sender
+        let mut receiver = self.receiver;
+        if tokio::time::timeout(SHUTDOWN_TIMEOUT, &mut receiver)
+            .await
+            .is_err()
+        {
+            receiver.abort();
+        }
+        if let Some(http) = self.http {
+            http.abort();
+        }
+        if let Some(admin) = self.admin {
+            admin.abort();
+        }
+        if let Some(backplane) = self.backplane {
+            backplane.abort();
+        }
+        if let Some(heartbeat) = self.heartbeat {
+            heartbeat.abort();
+        }
+        // changes since the last snapshot are not lost
+        if let Some(room_store) = self.room_store {
+            room_store.abort();
+        }
+        if let Some(store) = self.state.room_store.clone() {
+            Server::save_rooms(&self.state, store).await;
+        }
     }
 }
@@ -1,122 +1,197 @@
-use std::{collections::HashMap, sync::Arc};
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+use std::time::Instant;
 
+use crate::announcements::{
+    system_message, Announcement, Announcements, ADMINS_ONLY, NO_ROOMS, SYSTEM_USER_NAME,
+};
+use crate::auth::Authenticator;
+use crate::backplane::{Cluster, ClusterEvent, ClusterMessage};
+use crate::bots::{self, BOTS_ONLY, BOT_NAME_FIXED, INVALID_COMMAND_NAME, NAME_RESERVED};
+use crate::capabilities::{self, Feature, FEATURE_NOT_DECLARED, SERVER_VERSION};
+use crate::codec::{Codec, Envelope, ProtocolVersion, SUPPORTED_PROTOCOL_VERSIONS};
+use crate::commands::{CommandContext, CommandOutcome, Permission, UNKNOWN_COMMAND};
+use crate::content::{MessageContent, EMPTY_MESSAGE, MAX_TEXT_LEN, MESSAGE_TOO_LONG};
+use crate::history::{History, StoredMessage, MAX_ROOM_HISTORY, MESSAGE_NOT_FOUND};
+use crate::hooks::HookContext;
+use crate::mentions::{self, Mentions};
+use crate::metrics::ErrorKind;
+use crate::presence::PresenceManager;
+use crate::rate_limit::{TokenBucket, RATE_LIMITED};
+use crate::registry::RegisteredUser;
 use crate::responses;
-use crate::server::WsConnections;
+use crate::room::{
+    RoomUpdate, DESCRIPTION_TOO_LONG, MAX_DESCRIPTION_LEN, MAX_TOPIC_LEN, TOPIC_TOO_LONG,
+};
+use crate::room_manager::{NOT_ROOM_MEMBER, NOT_ROOM_MODERATOR, NOT_ROOM_OWNER, ROOM_NOT_FOUND};
+use crate::server::{ServerState, WsConnections};
+use crate::uploads::{
+    append_chunk, read_chunk, ShareTarget, UploadManager, ATTACHMENT_NOT_FOUND, CHUNK_SIZE,
+};
+use crate::ws_client_connection::binary_frame;
 use crate::{requests, server::CLIENT_NOT_FOUND};
 use chrono::Utc;
 use futures::future;
 use serde::Serialize;
 use tokio::sync::Mutex;
+use tracing::{debug, field, info, info_span, warn, Instrument};
 use uuid::Uuid;
 
 use crate::{
-    room_manager::RoomManager,
-    types::{serde_error_to_chat_error, ChatError},
-    ws_client_connection::WsClientConnection,
+    room_manager::RoomManager, types::ChatError, ws_client_connection::WsClientConnection,
 };
 
-fn create_response_str<T: Serialize>(
+pub const THREADS_IN_ROOMS_ONLY: &str = "Only room messages can be replied to";
+pub const AVATAR_NOT_IMAGE: &str = "Avatar must be an image";
+pub const NICKNAME_REQUIRED: &str = "Set a nickname first";
+
+fn create_response<T: Serialize + Send + Sync + 'static>(
     response_type: responses::ResponseType,
     value: T,
-) -> Result<String, ChatError> {
-    serde_json::to_string(&responses::Response {
-        response_type,
-        data: serde_json::to_string(&value).unwrap(),
-    })
-    .map_err(serde_error_to_chat_error)
+) -> Result<Envelope, ChatError> {
+    Ok(Envelope::new(response_type, value))
 }
 
 async fn send(
     ws_connections: Arc<Mutex<WsConnections>>,
     predicate: impl Fn(&WsClientConnection) -> bool,
-    response_str: &str,
+    response: &Envelope,
 ) -> Result<(), ChatError> {
     // send reponses
-    let results = {
+    let started = Instant::now();
+    let (results, metrics) = {
         let mut lock_ws_connections = ws_connections.lock().await;
-        future::join_all(
+        // every connection of a server shares its metrics
+        let metrics = lock_ws_connections
+            .values()
+            .next()
+            .map(|c| Arc::clone(&c.metrics));
+        let results = future::join_all(
             lock_ws_connections
                 .iter_mut()
                 .filter(|(_, c)| predicate(c))
-                .map(|(conn_id, c)| async move { (conn_id.clone(), c.send(&response_str).await) }),
+                .map(|(conn_id, c)| async move { (*conn_id, c.send(response).await) }),
         )
         .await
         .into_iter()
-        .collect::<Vec<(Uuid, Result<(), ChatError>)>>()
+        .collect::<Vec<(Uuid, Result<(), ChatError>)>>();
+        (results, metrics)
     };
+    if let Some(metrics) = metrics {
+        metrics.fan_out(started.elapsed());
+    }
+
+    remove_failed(ws_connections, results).await;
+
+    Ok(())
+}
+
+async fn multicast(
+    ws_connections: Arc<Mutex<WsConnections>>,
+    receiver_ids: &HashSet<Uuid>,
+    response: &Envelope,
+) -> Result<(), ChatError> {
+    // look receivers up directly instead of scanning every connection
+    let started = Instant::now();
+    let mut results = vec![];
+    let mut metrics = None;
+    {
+        let mut lock_ws_connections = ws_connections.lock().await;
+        for receiver_id in receiver_ids {
+            if let Some(c) = lock_ws_connections.get_mut(receiver_id) {
+                results.push((*receiver_id, c.send(response).await));
+                metrics.get_or_insert_with(|| Arc::clone(&c.metrics));
+            }
+        }
+    }
+    if let Some(metrics) = metrics {
+        metrics.fan_out(started.elapsed());
+    }
+
+    remove_failed(ws_connections, results).await;
+
+    Ok(())
+}
 
+async fn remove_failed(
+    ws_connections: Arc<Mutex<WsConnections>>,
+    results: Vec<(Uuid, Result<(), ChatError>)>,
+) {
     // connections that no longer active
     let mut connections_to_remove = vec![];
     // collect failed connections
     for (client_id, result) in results {
         if let Err(error) = result {
-            println!("Send error: {}", error);
+            warn!(conn_id = %client_id, %error, "send failed, dropping connection");
             connections_to_remove.push(client_id);
         }
     }
 
+    // remove dead connections, their reader task reports Disconnected
+    // and the offline status goes out to presence subscribers from there
     if !connections_to_remove.is_empty() {
-        // remove dead connections
-        {
-            ws_connections
-                .lock()
-                .await
-                .retain(|id, _| !connections_to_remove.contains(&id));
-        }
-
-        // sent to each alive connectin offline status
-        let mut lock_ws_connections_to_remove = ws_connections.lock().await;
-        for conn_to_remove_id in connections_to_remove {
-            let offline_response = &create_response_str(
-                responses::ResponseType::Offline,
-                responses::Offline {
-                    id: conn_to_remove_id.clone(),
-                },
-            )?;
-
-            future::join_all(
-                lock_ws_connections_to_remove
-                    .iter_mut()
-                    .map(|(_, c)| c.send(&offline_response)),
-            )
-            .await;
+        let mut lock_ws_connections = ws_connections.lock().await;
+        for id in &connections_to_remove {
+            if let Some(connection) = lock_ws_connections.remove(id) {
+                connection.metrics.send_failures(1);
+            }
         }
     }
-
-    Ok(())
-}
-
-async fn all(
-    ws_connections: Arc<Mutex<WsConnections>>,
-    response_str: &str,
-) -> Result<(), ChatError> {
-    send(Arc::clone(&ws_connections), |_| true, response_str).await
 }
 
 async fn direct(
     ws_connections: Arc<Mutex<WsConnections>>,
     receiver_id: Uuid,
-    response_str: &str,
+    response: &Envelope,
 ) -> Result<(), ChatError> {
     send(
         Arc::clone(&ws_connections),
         |c| c.id == receiver_id,
-        response_str,
+        response,
     )
     .await
 }
 
-async fn other(
-    client_id: Uuid,
+/// Sends the server `Hello`, on connect and as the reply to a client `Hello`.
+pub async fn greet(
+    connection: &mut WsClientConnection,
+    features: Vec<Feature>,
+) -> Result<(), ChatError> {
+    let response = create_response(
+        responses::ResponseType::Hello,
+        responses::Hello {
+            server_version: SERVER_VERSION.to_owned(),
+            connection_id: connection.id,
+            protocol_version: connection.protocol.number(),
+            protocol_versions: SUPPORTED_PROTOCOL_VERSIONS.to_vec(),
+            features,
+            limits: capabilities::limits(connection.message_rate.as_ref().map(TokenBucket::limit)),
+            capabilities: connection
+                .capabilities
+                .as_ref()
+                .map(|capabilities| capabilities.iter().copied().collect()),
+        },
+    )?;
+    connection.send(&response).await
+}
+
+async fn hello(
+    conn_id: Uuid,
+    req: &requests::Hello,
+    features: Vec<Feature>,
     ws_connections: Arc<Mutex<WsConnections>>,
-    response_str: &str,
 ) -> Result<(), ChatError> {
-    send(
-        Arc::clone(&ws_connections),
-        |conn| conn.id != client_id,
-        response_str,
-    )
-    .await
+    let protocol = ProtocolVersion::from_number(req.protocol_version)?;
+
+    let lock_connections = &mut ws_connections.lock().await;
+    let connection = lock_connections.get_mut(&conn_id).ok_or(CLIENT_NOT_FOUND)?;
+    // the reply already goes out in the requested version
+    connection.protocol = protocol;
+    connection.capabilities = req
+        .capabilities
+        .as_ref()
+        .map(|capabilities| capabilities.iter().copied().collect());
+    greet(connection, features).await
 }
 
 async fn get_id(conn_id: Uuid, ws_connections: Arc<Mutex<WsConnections>>) -> Result<(), ChatError> {
@@ -125,7 +200,7 @@ async fn get_id(conn_id: Uuid, ws_connections: Arc<Mutex<WsConnections>>) -> Res
 
     // Send client id
     connection
-        .send(&create_response_str(
+        .send(&create_response(
             responses::ResponseType::GetId,
             responses::GetId { id: conn_id },
         )?)
@@ -134,20 +209,51 @@ async fn get_id(conn_id: Uuid, ws_connections: Arc<Mutex<WsConnections>>) -> Res
     Ok(())
 }
 
-async fn online(conn_id: Uuid, ws_connections: Arc<Mutex<WsConnections>>) -> Result<(), ChatError> {
+/// Sends an event about `conn_id` to the connection itself and to whoever
+/// watches it, here and on the other nodes.
+async fn presence_event(
+    conn_id: Uuid,
+    response: &Envelope,
+    ws_connections: Arc<Mutex<WsConnections>>,
+    presence: Arc<Mutex<PresenceManager>>,
+    cluster: &Cluster,
+) -> Result<(), ChatError> {
+    let (mut audience, rooms) = {
+        let lock_presence = presence.lock().await;
+        (
+            lock_presence.audience(&conn_id),
+            lock_presence.rooms(&conn_id),
+        )
+    };
+    audience.insert(conn_id);
+
+    multicast(ws_connections, &audience, response).await?;
+    cluster.presence(conn_id, rooms, response).await;
+    Ok(())
+}
+
+async fn online(
+    conn_id: Uuid,
+    ws_connections: Arc<Mutex<WsConnections>>,
+    presence: Arc<Mutex<PresenceManager>>,
+    cluster: &Cluster,
+) -> Result<(), ChatError> {
     let name: String;
     {
         let lock_ws_connections = ws_connections.lock().await;
         let conn = lock_ws_connections.get(&conn_id).ok_or(CLIENT_NOT_FOUND)?;
-        name = conn.name.to_owned().unwrap();
+        name = conn.name.clone().ok_or(NICKNAME_REQUIRED)?;
     }
 
-    all(
-        Arc::clone(&ws_connections),
-        &create_response_str(
+    presence_event(
+        conn_id,
+        &create_response(
             responses::ResponseType::Online,
             responses::Online { id: conn_id, name },
         )?,
+        ws_connections,
+        presence,
+        cluster,
     )
     .await?;
 
@@ -157,108 +263,543 @@ async fn online(conn_id: Uuid, ws_connections: Arc<Mutex<WsConnections>>) -> Res
 async fn set_nickname(
     client_id: Uuid,
     req: &requests::SetNickname,
+    auth: &dyn Authenticator,
     ws_connections: Arc<Mutex<WsConnections>>,
+    presence: Arc<Mutex<PresenceManager>>,
+    cluster: &Cluster,
 ) -> Result<(), ChatError> {
     {
         let lock_connections = &mut ws_connections.lock().await;
         let connection = lock_connections
             .get_mut(&client_id)
             .ok_or(CLIENT_NOT_FOUND)?;
+        if connection.bot {
+            return Err(BOT_NAME_FIXED.to_owned());
+        }
+        if auth.is_reserved(&req.name) || req.name.eq_ignore_ascii_case(SYSTEM_USER_NAME) {
+            return Err(NAME_RESERVED.to_owned());
+        }
         connection.name = Some(req.name.to_owned());
     }
 
-    all(
-        Arc::clone(&ws_connections),
-        &create_response_str(
+    presence_event(
+        client_id,
+        &create_response(
             responses::ResponseType::SetNickname,
             responses::SetNickname {
                 id: client_id,
                 name: req.name.to_owned(),
             },
         )?,
+        Arc::clone(&ws_connections),
+        Arc::clone(&presence),
+        cluster,
     )
     .await?;
+    register(client_id, &ws_connections, &presence, cluster).await;
+
+    Ok(())
+}
 
+/// Takes a token from the sender's message rate, commands included.
+async fn take_message_token(
+    conn_id: Uuid,
+    ws_connections: &Mutex<WsConnections>,
+) -> Result<(), ChatError> {
+    let mut lock_connections = ws_connections.lock().await;
+    let connection = lock_connections.get_mut(&conn_id).ok_or(CLIENT_NOT_FOUND)?;
+    if let Some(message_rate) = connection.message_rate.as_mut() {
+        if !message_rate.try_take() {
+            return Err(RATE_LIMITED.to_owned());
+        }
+    }
     Ok(())
 }
 
+async fn message(
+    conn_id: Uuid,
+    req: &requests::Message,
+    ws_connections: Arc<Mutex<WsConnections>>,
+    room_manager: Arc<Mutex<RoomManager>>,
+    history: Arc<Mutex<History>>,
+    uploads: Arc<Mutex<UploadManager>>,
+    cluster: &Cluster,
+) -> Result<(), ChatError> {
+    let mut content = req.content();
+    content.validate()?;
+
+    // attachments are described by the server, not by what the client claims
+    if let MessageContent::Attachment { attachment_id, .. } = &content {
+        let target = match req.message_type {
+            requests::MessageType::User => ShareTarget::User(req.receiver_id),
+            requests::MessageType::Room => ShareTarget::Room(req.receiver_id),
+        };
+        content = uploads
+            .lock()
+            .await
+            .share(attachment_id, &conn_id, target)?;
+    }
+
+    match req.message_type {
+        requests::MessageType::User => match req.reply_to {
+            Some(_) => Err(THREADS_IN_ROOMS_ONLY.to_owned()),
+            None => {
+                user_message(
+                    conn_id,
+                    req.receiver_id,
+                    content,
+                    ws_connections,
+                    history,
+                    cluster,
+                )
+                .await
+            }
+        },
+        requests::MessageType::Room => {
+            room_message(
+                conn_id,
+                req,
+                content,
+                ws_connections,
+                room_manager,
+                history,
+                cluster,
+            )
+            .await
+        }
+    }
+}
+
 async fn user_message(
     conn_id: Uuid,
     receiver_id: Uuid,
-    message: &str,
+    content: MessageContent,
     ws_connections: Arc<Mutex<WsConnections>>,
+    history: Arc<Mutex<History>>,
+    cluster: &Cluster,
 ) -> Result<(), ChatError> {
     let name: String;
     {
         let lock_clients = &mut ws_connections.lock().await;
         let connection = lock_clients.get(&conn_id).ok_or(CLIENT_NOT_FOUND)?;
-        name = connection.name.clone().unwrap();
+        name = connection.name.clone().ok_or(NICKNAME_REQUIRED)?;
+    }
+    // a receiver that is not connected here may be on another node
+    let local = ws_connections.lock().await.contains_key(&receiver_id);
+    if !local && cluster.registry.lock().await.get(&receiver_id).is_none() {
+        return Err(CLIENT_NOT_FOUND.to_owned());
+    }
+
+    let stored_message = StoredMessage {
+        id: Uuid::new_v4(),
+        sender_id: conn_id,
+        name,
+        content,
+        created_at: Utc::now(),
+        room_id: None,
+        receiver_id: Some(receiver_id),
+        reply_to: None,
+        reply_count: 0,
+        reactions: BTreeMap::new(),
+        system: false,
+    };
+    let response = create_response(
+        responses::ResponseType::Message,
+        stored_message.to_response(),
+    )?;
+    cluster.message_stored(&stored_message).await;
+    history
+        .lock()
+        .await
+        .add_direct_message(receiver_id, stored_message);
+
+    if local {
+        direct(Arc::clone(&ws_connections), receiver_id, &response).await?;
+    } else {
+        cluster.direct(receiver_id, &response).await;
+    }
+    // the sender gets the stored message too, with the id to react with
+    if receiver_id != conn_id {
+        direct(Arc::clone(&ws_connections), conn_id, &response).await?;
     }
-    direct(
-        Arc::clone(&ws_connections),
-        receiver_id,
-        &create_response_str(
-            responses::ResponseType::Message,
-            responses::Message {
-                id: conn_id,
-                name,
-                message: message.to_owned(),
-                created_at: Utc::now(),
-            },
-        )?,
-    )
-    .await?;
     Ok(())
 }
 
 async fn room_message(
     conn_id: Uuid,
-    room_id: Uuid,
-    message: &str,
+    req: &requests::Message,
+    content: MessageContent,
     ws_connections: Arc<Mutex<WsConnections>>,
     room_manager: Arc<Mutex<RoomManager>>,
+    history: Arc<Mutex<History>>,
+    cluster: &Cluster,
 ) -> Result<(), ChatError> {
+    let (room_id, reply_to) = (req.receiver_id, req.reply_to);
     let name: String;
     {
         let lock_clients = &mut ws_connections.lock().await;
         let connection = lock_clients.get(&conn_id).ok_or(CLIENT_NOT_FOUND)?;
-        name = connection.name.clone().unwrap();
+        name = connection.name.clone().ok_or(NICKNAME_REQUIRED)?;
+    }
+    // fails early so nothing is stored for unknown rooms and non-members
+    if !room_manager
+        .lock()
+        .await
+        .is_member(&room_id, &conn_id)
+        .await?
+    {
+        return Err(NOT_ROOM_MEMBER.to_owned());
     }
 
+    let message_id = Uuid::new_v4();
+    let stored_message = StoredMessage {
+        id: message_id,
+        sender_id: conn_id,
+        name,
+        content,
+        created_at: Utc::now(),
+        room_id: Some(room_id),
+        receiver_id: None,
+        reply_to,
+        reply_count: 0,
+        reactions: BTreeMap::new(),
+        system: false,
+    };
+
+    let response = {
+        let mut lock_history = history.lock().await;
+        let mut message = stored_message.to_response();
+        if reply_to.is_some() {
+            let root = lock_history.add_reply(stored_message)?;
+            message.reply_to = Some(root.id);
+            create_response(
+                responses::ResponseType::ThreadReply,
+                responses::ThreadReply {
+                    parent_id: root.id,
+                    reply_count: root.reply_count,
+                    message,
+                },
+            )?
+        } else {
+            lock_history.add_room_message(room_id, stored_message);
+            create_response(responses::ResponseType::Message, message)?
+        }
+    };
+
     room_manager
         .lock()
         .await
-        .all(
-            &room_id,
-            &create_response_str(
-                responses::ResponseType::Message,
-                responses::Message {
-                    id: conn_id,
-                    name,
-                    message: message.to_owned(),
-                    created_at: Utc::now(),
-                },
-            )?,
+        .unmuted(&room_id, &response)
+        .await?;
+
+    let message = {
+        let lock_history = history.lock().await;
+        let stored_message = lock_history.get(&message_id)?;
+        cluster.message_stored(stored_message).await;
+        stored_message.to_response()
+    };
+    mention_members(conn_id, room_id, message, ws_connections, room_manager).await
+}
+
+/// Tells the members connected here who are mentioned in a room message.
+async fn mention_members(
+    sender_id: Uuid,
+    room_id: Uuid,
+    message: responses::Message,
+    ws_connections: Arc<Mutex<WsConnections>>,
+    room_manager: Arc<Mutex<RoomManager>>,
+) -> Result<(), ChatError> {
+    let text = message.content.mention_text().unwrap_or_default();
+    let member_names: Vec<String> = match text.contains('@') {
+        true => room_members(&room_id, &ws_connections, &room_manager)
+            .await?
+            .into_iter()
+            .map(|member| member.name)
+            .collect(),
+        false => vec![],
+    };
+    let mentions = mentions::parse(text, &member_names);
+    if !mentions.is_empty() {
+        notify_mentions(
+            sender_id,
+            room_id,
+            message,
+            &mentions,
+            ws_connections,
+            room_manager,
         )
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Hands a slash command to the bot in the room that registered it, the
+/// room does not see it. Returns whether a bot took the command.
+async fn bot_command(
+    conn_id: Uuid,
+    room_id: Uuid,
+    command: String,
+    args: String,
+    ws_connections: Arc<Mutex<WsConnections>>,
+    room_manager: Arc<Mutex<RoomManager>>,
+) -> Result<bool, ChatError> {
+    let members = room_manager.lock().await.members(&room_id).await?;
+
+    let mut lock_connections = ws_connections.lock().await;
+    let sender = lock_connections.get(&conn_id).ok_or(CLIENT_NOT_FOUND)?;
+    let sender = responses::UserInfo {
+        id: conn_id,
+        name: sender.name.clone().unwrap_or_default(),
+        bot: sender.bot,
+    };
+    let bot = lock_connections.values_mut().find(|connection| {
+        connection.bot
+            && connection.id != conn_id
+            && members.contains(&connection.id)
+            && connection.commands.contains(&command)
+    });
+    let bot = match bot {
+        Some(bot) => bot,
+        None => return Ok(false),
+    };
+
+    bot.send(&create_response(
+        responses::ResponseType::BotCommand,
+        responses::BotCommand {
+            room_id,
+            sender,
+            command,
+            args,
+        },
+    )?)
+    .await?;
+    Ok(true)
+}
+
+/// Runs a slash command sent as a room message. Returns the request to
+/// handle in its place, `None` once the command has been fully handled.
+async fn room_command(
+    conn_id: Uuid,
+    request: requests::Request,
+    state: &ServerState,
+) -> Result<Option<requests::Request>, ChatError> {
+    let (room_id, text) = match &request {
+        requests::Request::Message(req)
+            if matches!(req.message_type, requests::MessageType::Room)
+                && req.reply_to.is_none() =>
+        {
+            match req.content() {
+                MessageContent::Plain { text } => (req.receiver_id, text),
+                _ => return Ok(Some(request)),
+            }
+        }
+        _ => return Ok(Some(request)),
+    };
+
+    // `//text` sends `/text` as a regular message
+    if let Some(escaped) = text.trim_start().strip_prefix("//") {
+        if let requests::Request::Message(mut req) = request {
+            req.message = format!("/{}", escaped);
+            req.content = None;
+            return Ok(Some(requests::Request::Message(req)));
+        }
+    }
+    let (name, args) = match bots::parse_command(&text) {
+        Some(command) => command,
+        None => return Ok(Some(request)),
+    };
+
+    let room_manager = Arc::clone(&state.room_manager);
+    if !room_manager
+        .lock()
         .await
+        .is_member(&room_id, &conn_id)
+        .await?
+    {
+        return Err(NOT_ROOM_MEMBER.to_owned());
+    }
+    let command = match state.commands.get(&name) {
+        Some(command) => command,
+        None => {
+            let ws_connections = Arc::clone(&state.clients);
+            if !bot_command(conn_id, room_id, name, args, ws_connections, room_manager).await? {
+                return Err(UNKNOWN_COMMAND.to_owned());
+            }
+            return Ok(None);
+        }
+    };
+    match command.permission() {
+        Permission::Member => {}
+        Permission::RoomModerator => {
+            if !room_manager
+                .lock()
+                .await
+                .can_moderate(&room_id, &conn_id)
+                .await?
+            {
+                return Err(NOT_ROOM_MODERATOR.to_owned());
+            }
+        }
+        Permission::RoomOwner => {
+            if !room_manager
+                .lock()
+                .await
+                .is_owner(&room_id, &conn_id)
+                .await?
+            {
+                return Err(NOT_ROOM_OWNER.to_owned());
+            }
+        }
+    }
+
+    let name = {
+        let lock_connections = state.clients.lock().await;
+        let connection = lock_connections.get(&conn_id).ok_or(CLIENT_NOT_FOUND)?;
+        connection.name.clone()
+    };
+    let ctx = CommandContext {
+        conn_id,
+        name,
+        room_id,
+        args,
+        state: state.clone(),
+    };
+    match command.run(&ctx).await? {
+        CommandOutcome::Request(request) => Ok(Some(request)),
+        CommandOutcome::Reply(text) => {
+            direct(
+                Arc::clone(&state.clients),
+                conn_id,
+                &system_message(Some(room_id), text, true),
+            )
+            .await?;
+            Ok(None)
+        }
+        CommandOutcome::Done => Ok(None),
+    }
 }
 
-async fn disconnected(
+async fn register_commands(
     conn_id: Uuid,
+    req: &requests::RegisterCommands,
     ws_connections: Arc<Mutex<WsConnections>>,
 ) -> Result<(), ChatError> {
+    let commands: HashSet<String> = req
+        .commands
+        .iter()
+        .map(|command| command.trim_start_matches('/').to_lowercase())
+        .collect();
+    if !commands
+        .iter()
+        .all(|command| bots::valid_command_name(command))
     {
-        ws_connections.lock().await.remove(&conn_id);
+        return Err(INVALID_COMMAND_NAME.to_owned());
     }
-    other(
-        conn_id,
-        Arc::clone(&ws_connections),
-        &create_response_str(
-            responses::ResponseType::Offline,
-            responses::Offline { id: conn_id },
-        )?,
-    )
-    .await?;
+
+    let lock_connections = &mut ws_connections.lock().await;
+    let connection = lock_connections.get_mut(&conn_id).ok_or(CLIENT_NOT_FOUND)?;
+    if !connection.bot {
+        return Err(BOTS_ONLY.to_owned());
+    }
+    let mut registered: Vec<String> = commands.iter().cloned().collect();
+    registered.sort();
+    connection.commands = commands;
+    connection
+        .send(&create_response(
+            responses::ResponseType::CommandsRegistered,
+            responses::CommandsRegistered {
+                commands: registered,
+            },
+        )?)
+        .await
+}
+
+// delivered to mentioned members regardless of whether they muted the room
+async fn notify_mentions(
+    conn_id: Uuid,
+    room_id: Uuid,
+    message: responses::Message,
+    mentions: &Mentions,
+    ws_connections: Arc<Mutex<WsConnections>>,
+    room_manager: Arc<Mutex<RoomManager>>,
+) -> Result<(), ChatError> {
+    let members = room_manager.lock().await.members(&room_id).await?;
+
+    let mut lock_connections = ws_connections.lock().await;
+    for member_id in members.iter().filter(|id| **id != conn_id) {
+        let connection = match lock_connections.get_mut(member_id) {
+            Some(connection) => connection,
+            None => continue,
+        };
+        if !connection
+            .name
+            .as_ref()
+            .is_some_and(|name| mentions.matches(name))
+        {
+            continue;
+        }
+
+        *connection.unread_mentions.entry(room_id).or_default() += 1;
+        let response = create_response(
+            responses::ResponseType::Mentioned,
+            responses::Mentioned {
+                room_id,
+                message: message.clone(),
+                unread_mentions: connection.unread_mentions.values().sum(),
+            },
+        )?;
+        if let Err(err) = connection.send(&response).await {
+            warn!(conn_id = %connection.id, error = %err, "mention send failed");
+        }
+    }
+
+    Ok(())
+}
+
+async fn disconnected(
+    conn_id: Uuid,
+    ws_connections: Arc<Mutex<WsConnections>>,
+    room_manager: Arc<Mutex<RoomManager>>,
+    presence: Arc<Mutex<PresenceManager>>,
+    uploads: Arc<Mutex<UploadManager>>,
+    history: Arc<Mutex<History>>,
+    cluster: &Cluster,
+) -> Result<(), ChatError> {
+    let connection = ws_connections.lock().await.remove(&conn_id);
+    let rooms = room_manager.lock().await.leave_all(&conn_id).await;
+    uploads.lock().await.abort_all(&conn_id);
+
+    if let Some(connection) = connection {
+        for room_id in rooms {
+            let user = connection.user_info();
+            let room_manager = Arc::clone(&room_manager);
+            let history = Arc::clone(&history);
+            if let Err(err) =
+                member_event(room_id, user, MemberEvent::Left, room_manager, history).await
+            {
+                warn!(%room_id, error = %err, "announcing departure failed");
+            }
+        }
+    }
+
+    let (audience, rooms) = {
+        let mut lock_presence = presence.lock().await;
+        let audience = lock_presence.audience(&conn_id);
+        let rooms = lock_presence.rooms(&conn_id);
+        lock_presence.remove(&conn_id);
+        (audience, rooms)
+    };
+
+    let response = create_response(
+        responses::ResponseType::Offline,
+        responses::Offline { id: conn_id },
+    )?;
+    multicast(Arc::clone(&ws_connections), &audience, &response).await?;
+    cluster.presence(conn_id, rooms, &response).await;
+    cluster
+        .publish(ClusterEvent::Disconnected { user_id: conn_id })
+        .await;
 
     Ok(())
 }
@@ -266,19 +807,29 @@ async fn disconnected(
 async fn global_online(
     conn_id: Uuid,
     ws_connections: Arc<Mutex<WsConnections>>,
+    cluster: &Cluster,
 ) -> Result<(), ChatError> {
+    let mut user_infos: Vec<responses::UserInfo> = cluster
+        .registry
+        .lock()
+        .await
+        .users()
+        .map(|registered| registered.user.clone())
+        .collect();
+
     let lock_connections = &mut ws_connections.lock().await;
-    let user_infos: Vec<responses::UserInfo> = lock_connections
-        .iter_mut()
-        .map(|(_, connection)| responses::UserInfo {
+    // connections that have not picked a name yet are not listed
+    user_infos.extend(lock_connections.values().filter_map(|connection| {
+        Some(responses::UserInfo {
             id: connection.id,
-            name: connection.name.clone().unwrap(),
+            name: connection.name.clone()?,
+            bot: connection.bot,
         })
-        .collect();
+    }));
 
     let connection = lock_connections.get_mut(&conn_id).ok_or(CLIENT_NOT_FOUND)?;
     connection
-        .send(&create_response_str(
+        .send(&create_response(
             responses::ResponseType::GlobalOnline,
             responses::GlobalOnline { users: user_infos },
         )?)
@@ -292,23 +843,29 @@ async fn create_room(
     req: &requests::CreateRoom,
     ws_connections: Arc<Mutex<WsConnections>>,
     room_manager: Arc<Mutex<RoomManager>>,
+    presence: Arc<Mutex<PresenceManager>>,
 ) -> Result<(), ChatError> {
     let room_id = Uuid::new_v4();
-    room_manager
+    let bot_account = bot_account(conn_id, &ws_connections).await;
+    let room = room_manager
         .lock()
         .await
-        .create(&conn_id, &room_id, &req.name, Arc::clone(&ws_connections))
+        .create(
+            &conn_id,
+            &room_id,
+            &req.name,
+            bot_account.as_deref(),
+            Arc::clone(&ws_connections),
+        )
         .await?;
+    presence.lock().await.join_room(conn_id, room_id);
 
     direct(
         Arc::clone(&ws_connections),
         conn_id,
-        &create_response_str(
+        &create_response(
             responses::ResponseType::RoomCreated,
-            responses::RoomCreated {
-                id: room_id.clone(),
-                name: req.name.to_owned(),
-            },
+            responses::RoomCreated { room },
         )?,
     )
     .await?;
@@ -321,82 +878,1598 @@ async fn join_room(
     req: &requests::JoinRoom,
     ws_connections: Arc<Mutex<WsConnections>>,
     room_manager: Arc<Mutex<RoomManager>>,
+    presence: Arc<Mutex<PresenceManager>>,
+    history: Arc<Mutex<History>>,
+    announcements: Arc<Mutex<Announcements>>,
 ) -> Result<(), ChatError> {
     let uuid = Uuid::parse_str(&req.id).map_err(|e| e.to_string())?;
-    let room_info = room_manager.lock().await.join(&uuid, &conn_id).await?;
+    let bot_account = bot_account(conn_id, &ws_connections).await;
+    let (room, rejoined) = {
+        let lock_room_manager = &mut room_manager.lock().await;
+        let rejoined = lock_room_manager.is_member(&uuid, &conn_id).await?;
+        (
+            lock_room_manager
+                .join(&uuid, &conn_id, bot_account.as_deref())
+                .await?,
+            rejoined,
+        )
+    };
+    presence.lock().await.join_room(conn_id, uuid);
 
+    let members = room_members(&uuid, &ws_connections, &room_manager).await?;
     direct(
         Arc::clone(&ws_connections),
         conn_id,
-        &create_response_str(
+        &create_response(
             responses::ResponseType::RoomJoined,
-            responses::RoomJoined {
-                id: room_info.id,
-                name: room_info.name,
-            },
+            responses::RoomJoined { room, members },
         )?,
     )
-    .await
+    .await?;
+    for message in announcements.lock().await.for_room(&uuid) {
+        direct(Arc::clone(&ws_connections), conn_id, &message).await?;
+    }
+
+    if rejoined {
+        return Ok(());
+    }
+    let user = user_info(conn_id, &ws_connections).await?;
+    member_event(uuid, user, MemberEvent::Joined, room_manager, history).await
 }
 
-pub async fn send_error(
+/// The account of a bot connection, its name is fixed by its api key.
+pub(crate) async fn bot_account(
     conn_id: Uuid,
-    error_message: &str,
-    ws_connections: Arc<Mutex<WsConnections>>,
+    ws_connections: &Mutex<WsConnections>,
+) -> Option<String> {
+    let lock_connections = ws_connections.lock().await;
+    let connection = lock_connections.get(&conn_id)?;
+    match connection.bot {
+        true => connection.name.clone(),
+        false => None,
+    }
+}
+
+/// Puts a bot back into the rooms its account is a member of.
+async fn rejoin_rooms(conn_id: Uuid, state: &ServerState) -> Result<(), ChatError> {
+    let account = match bot_account(conn_id, &state.clients).await {
+        Some(account) => account,
+        None => return Ok(()),
+    };
+    let room_ids = state
+        .room_manager
+        .lock()
+        .await
+        .rejoin(&conn_id, &account)
+        .await;
+
+    for room_id in room_ids {
+        state.presence.lock().await.join_room(conn_id, room_id);
+        let room = state.room_manager.lock().await.info(&room_id).await?;
+        let members = room_members(&room_id, &state.clients, &state.room_manager).await?;
+        direct(
+            Arc::clone(&state.clients),
+            conn_id,
+            &create_response(
+                responses::ResponseType::RoomJoined,
+                responses::RoomJoined { room, members },
+            )?,
+        )
+        .await?;
+        let user = user_info(conn_id, &state.clients).await?;
+        member_event(
+            room_id,
+            user,
+            MemberEvent::Joined,
+            Arc::clone(&state.room_manager),
+            Arc::clone(&state.history),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+async fn user_info(
+    conn_id: Uuid,
+    ws_connections: &Mutex<WsConnections>,
+) -> Result<responses::UserInfo, ChatError> {
+    let lock_connections = ws_connections.lock().await;
+    let connection = lock_connections.get(&conn_id).ok_or(CLIENT_NOT_FOUND)?;
+    Ok(connection.user_info())
+}
+
+/// Current members of a room, sorted by name.
+pub(crate) async fn room_members(
+    room_id: &Uuid,
+    ws_connections: &Mutex<WsConnections>,
+    room_manager: &Mutex<RoomManager>,
+) -> Result<Vec<responses::UserInfo>, ChatError> {
+    let members = room_manager.lock().await.members(room_id).await?;
+    let lock_connections = ws_connections.lock().await;
+    let mut users: Vec<responses::UserInfo> = members
+        .iter()
+        .filter_map(|id| lock_connections.get(id))
+        .map(|connection| connection.user_info())
+        .collect();
+    users.sort_by_key(|user| user.name.to_lowercase());
+    Ok(users)
+}
+
+pub(crate) enum MemberEvent {
+    Joined,
+    Left,
+    // kicked by this member
+    Removed(responses::UserInfo),
+}
+
+/// Tells the members of a room that someone joined or left and keeps a
+/// system entry of it in the room's history.
+pub(crate) async fn member_event(
+    room_id: Uuid,
+    user: responses::UserInfo,
+    event: MemberEvent,
+    room_manager: Arc<Mutex<RoomManager>>,
+    history: Arc<Mutex<History>>,
+) -> Result<(), ChatError> {
+    let (text, response) = match event {
+        MemberEvent::Joined => (
+            format!("{} joined the room", user.name),
+            create_response(
+                responses::ResponseType::MemberJoined,
+                responses::MemberJoined {
+                    room_id,
+                    user: user.clone(),
+                },
+            )?,
+        ),
+        MemberEvent::Left => (
+            format!("{} left the room", user.name),
+            create_response(
+                responses::ResponseType::MemberLeft,
+                responses::MemberLeft {
+                    room_id,
+                    user: user.clone(),
+                    removed_by: None,
+                },
+            )?,
+        ),
+        MemberEvent::Removed(by) => (
+            format!("{} was removed by {}", user.name, by.name),
+            create_response(
+                responses::ResponseType::MemberLeft,
+                responses::MemberLeft {
+                    room_id,
+                    user: user.clone(),
+                    removed_by: Some(by),
+                },
+            )?,
+        ),
+    };
+
+    history.lock().await.add_room_message(
+        room_id,
+        StoredMessage {
+            id: Uuid::new_v4(),
+            sender_id: user.id,
+            name: user.name,
+            content: MessageContent::Plain { text },
+            created_at: Utc::now(),
+            room_id: Some(room_id),
+            receiver_id: None,
+            reply_to: None,
+            reply_count: 0,
+            reactions: BTreeMap::new(),
+            system: true,
+        },
+    );
+    room_manager.lock().await.all(&room_id, &response).await
+}
+
+fn optional_text(text: &str, max_len: usize, too_long: &str) -> Result<Option<String>, ChatError> {
+    let text = text.trim();
+    if text.chars().count() > max_len {
+        return Err(too_long.to_owned());
+    }
+    Ok(Some(text.to_owned()).filter(|text| !text.is_empty()))
+}
+
+async fn update_room(
+    conn_id: Uuid,
+    req: &requests::UpdateRoom,
+    room_manager: Arc<Mutex<RoomManager>>,
+    uploads: Arc<Mutex<UploadManager>>,
+) -> Result<(), ChatError> {
+    if !room_manager
+        .lock()
+        .await
+        .can_moderate(&req.room_id, &conn_id)
+        .await?
+    {
+        return Err(NOT_ROOM_MODERATOR.to_owned());
+    }
+
+    let mut update = RoomUpdate::default();
+    if let Some(topic) = &req.topic {
+        update.topic = Some(optional_text(topic, MAX_TOPIC_LEN, TOPIC_TOO_LONG)?);
+    }
+    if let Some(description) = &req.description {
+        update.description = Some(optional_text(
+            description,
+            MAX_DESCRIPTION_LEN,
+            DESCRIPTION_TOO_LONG,
+        )?);
+    }
+    if let Some(avatar) = req.avatar.as_deref().map(str::trim) {
+        update.avatar = Some(if avatar.is_empty() {
+            None
+        } else {
+            let attachment_id = Uuid::parse_str(avatar).map_err(|e| e.to_string())?;
+            let mut lock_uploads = uploads.lock().await;
+            if !lock_uploads
+                .get(&attachment_id)?
+                .mime_type
+                .starts_with("image/")
+            {
+                return Err(AVATAR_NOT_IMAGE.to_owned());
+            }
+            // lets every member download it
+            lock_uploads.share(&attachment_id, &conn_id, ShareTarget::Room(req.room_id))?;
+            Some(attachment_id)
+        });
+    }
+
+    let lock_room_manager = &mut room_manager.lock().await;
+    let room = lock_room_manager.update(&req.room_id, update).await?;
+    lock_room_manager
+        .all(
+            &req.room_id,
+            &create_response(
+                responses::ResponseType::RoomUpdated,
+                responses::RoomUpdated {
+                    room,
+                    updated_by: conn_id,
+                },
+            )?,
+        )
+        .await
+}
+
+async fn require_admin(
+    conn_id: Uuid,
+    ws_connections: &Mutex<WsConnections>,
+) -> Result<(), ChatError> {
+    let lock_connections = ws_connections.lock().await;
+    let connection = lock_connections.get(&conn_id).ok_or(CLIENT_NOT_FOUND)?;
+    if !connection.admin {
+        return Err(ADMINS_ONLY.to_owned());
+    }
+    Ok(())
+}
+
+/// Sends an announcement to everyone connected or to the members of its
+/// rooms, pinning it if asked to. Returns how many connections got it.
+pub(crate) async fn announce(
+    announcement: Announcement,
+    state: &ServerState,
+) -> Result<usize, ChatError> {
+    let text = announcement.text.trim();
+    if text.is_empty() {
+        return Err(EMPTY_MESSAGE.to_owned());
+    }
+    if text.chars().count() > MAX_TEXT_LEN {
+        return Err(MESSAGE_TOO_LONG.to_owned());
+    }
+
+    let recipients = match &announcement.room_ids {
+        None => {
+            let message = announcement.message(None);
+            let mut recipients = 0;
+            for connection in state.clients.lock().await.values_mut() {
+                match connection.send(&message).await {
+                    Ok(()) => recipients += 1,
+                    Err(err) => {
+                        warn!(conn_id = %connection.id, error = %err, "announcement send failed")
+                    }
+                }
+            }
+            state.cluster.everyone(&message).await;
+            recipients
+        }
+        Some(room_ids) => {
+            if room_ids.is_empty() {
+                return Err(NO_ROOMS.to_owned());
+            }
+            let lock_room_manager = state.room_manager.lock().await;
+            // every room must exist before anything is sent
+            let mut recipients = 0;
+            for room_id in room_ids {
+                recipients += lock_room_manager.members(room_id).await?.len();
+            }
+            for room_id in room_ids {
+                lock_room_manager
+                    .all(room_id, &announcement.message(Some(*room_id)))
+                    .await?;
+            }
+            recipients
+        }
+    };
+
+    info!(
+        announcement_id = %announcement.id,
+        severity = ?announcement.severity,
+        pinned = announcement.pinned,
+        recipients,
+        "announcement sent"
+    );
+    if announcement.pinned {
+        state.announcements.lock().await.pin(announcement);
+    }
+    Ok(recipients)
+}
+
+async fn announce_request(
+    conn_id: Uuid,
+    req: &requests::Announce,
+    state: &ServerState,
 ) -> Result<(), ChatError> {
+    require_admin(conn_id, &state.clients).await?;
+
+    let announcement = Announcement::new(
+        req.text.trim().to_owned(),
+        req.severity,
+        req.room_ids.clone(),
+        req.pinned,
+    );
+    let announcement_id = announcement.id;
+    let recipients = announce(announcement, state).await?;
+
     direct(
-        Arc::clone(&ws_connections),
+        Arc::clone(&state.clients),
         conn_id,
-        &create_response_str(
-            responses::ResponseType::Error,
-            responses::ResponseError {
-                message: error_message.to_owned(),
+        &create_response(
+            responses::ResponseType::Announced,
+            responses::Announced {
+                announcement_id,
+                recipients,
             },
         )?,
     )
     .await
 }
 
-pub async fn switch_request(
+/// Unpins an announcement and tells everyone connected, so clients can
+/// drop it from view.
+pub(crate) async fn unpin_announcement(
+    announcement_id: &Uuid,
+    state: &ServerState,
+) -> Result<(), ChatError> {
+    state.announcements.lock().await.unpin(announcement_id)?;
+
+    let response = create_response(
+        responses::ResponseType::AnnouncementUnpinned,
+        responses::AnnouncementUnpinned {
+            announcement_id: *announcement_id,
+        },
+    )?;
+    for connection in state.clients.lock().await.values_mut() {
+        if let Err(err) = connection.send(&response).await {
+            warn!(conn_id = %connection.id, error = %err, "unpin send failed");
+        }
+    }
+    state.cluster.everyone(&response).await;
+    Ok(())
+}
+
+async fn unpin(conn_id: Uuid, req: &requests::Unpin, state: &ServerState) -> Result<(), ChatError> {
+    require_admin(conn_id, &state.clients).await?;
+    unpin_announcement(&req.announcement_id, state).await
+}
+
+async fn leave_room(
     conn_id: Uuid,
-    request: requests::Request,
+    req: &requests::LeaveRoom,
     ws_connections: Arc<Mutex<WsConnections>>,
     room_manager: Arc<Mutex<RoomManager>>,
-) {
-    let ws_connections = Arc::clone(&ws_connections);
-    let error_ws_connections = Arc::clone(&ws_connections);
-    let room_manager = Arc::clone(&room_manager);
-    println!("{:?}", request);
-    if let Err(error) = match &request {
-        requests::Request::SetNickname(req) => set_nickname(conn_id, req, ws_connections).await,
-        requests::Request::Message(req) => match req.message_type {
-            requests::MessageType::User => {
-                user_message(conn_id, req.receiver_id, &req.message, ws_connections).await
+    presence: Arc<Mutex<PresenceManager>>,
+    history: Arc<Mutex<History>>,
+) -> Result<(), ChatError> {
+    room_manager
+        .lock()
+        .await
+        .leave(&req.room_id, &conn_id)
+        .await?;
+    presence.lock().await.leave_room(&conn_id, &req.room_id);
+
+    direct(
+        Arc::clone(&ws_connections),
+        conn_id,
+        &create_response(
+            responses::ResponseType::RoomLeft,
+            responses::RoomLeft {
+                room_id: req.room_id,
+            },
+        )?,
+    )
+    .await?;
+
+    let user = user_info(conn_id, &ws_connections).await?;
+    member_event(req.room_id, user, MemberEvent::Left, room_manager, history).await
+}
+
+async fn subscribe_presence(
+    conn_id: Uuid,
+    req: &requests::PresenceSubscription,
+    ws_connections: Arc<Mutex<WsConnections>>,
+    presence: Arc<Mutex<PresenceManager>>,
+    cluster: &Cluster,
+) -> Result<(), ChatError> {
+    let watched = {
+        let mut lock_presence = presence.lock().await;
+        for room_id in &req.room_ids {
+            if !lock_presence.is_member(&conn_id, room_id) {
+                return Err(NOT_ROOM_MEMBER.to_owned());
             }
-            requests::MessageType::Room => {
-                room_message(
-                    conn_id,
-                    req.receiver_id,
-                    &req.message,
-                    ws_connections,
-                    room_manager,
-                )
+        }
+
+        for user_id in &req.user_ids {
+            lock_presence.subscribe_user(conn_id, *user_id);
+        }
+        for room_id in &req.room_ids {
+            lock_presence.subscribe_room(conn_id, *room_id);
+        }
+
+        let mut watched: HashSet<Uuid> = req.user_ids.iter().cloned().collect();
+        watched.extend(lock_presence.room_members(&req.room_ids));
+        watched.remove(&conn_id);
+        watched
+    };
+
+    // users on other nodes, watched by id or through a shared room
+    let mut user_infos: Vec<responses::UserInfo> = cluster
+        .registry
+        .lock()
+        .await
+        .users()
+        .filter(|registered| {
+            watched.contains(&registered.user.id)
+                || registered
+                    .room_ids
+                    .iter()
+                    .any(|room_id| req.room_ids.contains(room_id))
+        })
+        .filter(|registered| !registered.user.name.is_empty())
+        .map(|registered| registered.user.clone())
+        .collect();
+
+    // reply with who is online right now among the watched users
+    let lock_connections = &mut ws_connections.lock().await;
+    user_infos.extend(
+        watched
+            .iter()
+            .filter_map(|id| lock_connections.get(id))
+            .filter_map(|connection| {
+                connection.name.clone().map(|name| responses::UserInfo {
+                    id: connection.id,
+                    name,
+                    bot: connection.bot,
+                })
+            }),
+    );
+
+    let connection = lock_connections.get_mut(&conn_id).ok_or(CLIENT_NOT_FOUND)?;
+    connection
+        .send(&create_response(
+            responses::ResponseType::PresenceSubscribed,
+            responses::PresenceSubscribed { users: user_infos },
+        )?)
+        .await
+}
+
+async fn unsubscribe_presence(
+    conn_id: Uuid,
+    req: &requests::PresenceSubscription,
+    presence: Arc<Mutex<PresenceManager>>,
+) -> Result<(), ChatError> {
+    let mut lock_presence = presence.lock().await;
+    for user_id in &req.user_ids {
+        lock_presence.unsubscribe_user(&conn_id, user_id);
+    }
+    for room_id in &req.room_ids {
+        lock_presence.unsubscribe_room(&conn_id, room_id);
+    }
+
+    Ok(())
+}
+
+async fn get_history(
+    conn_id: Uuid,
+    req: &requests::GetHistory,
+    ws_connections: Arc<Mutex<WsConnections>>,
+    room_manager: Arc<Mutex<RoomManager>>,
+    history: Arc<Mutex<History>>,
+) -> Result<(), ChatError> {
+    if !room_manager
+        .lock()
+        .await
+        .is_member(&req.room_id, &conn_id)
+        .await?
+    {
+        return Err(NOT_ROOM_MEMBER.to_owned());
+    }
+
+    let response = {
+        let lock_history = history.lock().await;
+        create_response(
+            responses::ResponseType::History,
+            responses::History {
+                room_id: req.room_id,
+                messages: lock_history
+                    .room_history(&req.room_id, req.limit.unwrap_or(MAX_ROOM_HISTORY))
+                    .iter()
+                    .map(|message| message.to_response())
+                    .collect(),
+            },
+        )?
+    };
+
+    direct(Arc::clone(&ws_connections), conn_id, &response).await
+}
+
+async fn get_thread(
+    conn_id: Uuid,
+    req: &requests::GetThread,
+    ws_connections: Arc<Mutex<WsConnections>>,
+    room_manager: Arc<Mutex<RoomManager>>,
+    history: Arc<Mutex<History>>,
+) -> Result<(), ChatError> {
+    // copied out, the room manager is never locked while history is
+    let (room_id, thread) = {
+        let lock_history = history.lock().await;
+        let (parent, replies) = lock_history.thread(&req.message_id)?;
+        (
+            parent.room_id.ok_or(MESSAGE_NOT_FOUND)?,
+            responses::Thread {
+                parent: parent.to_response(),
+                replies: replies.iter().map(|reply| reply.to_response()).collect(),
+            },
+        )
+    };
+    if !room_manager
+        .lock()
+        .await
+        .is_member(&room_id, &conn_id)
+        .await?
+    {
+        return Err(NOT_ROOM_MEMBER.to_owned());
+    }
+
+    let response = create_response(responses::ResponseType::Thread, thread)?;
+    direct(Arc::clone(&ws_connections), conn_id, &response).await
+}
+
+async fn reaction(
+    conn_id: Uuid,
+    req: &requests::Reaction,
+    add: bool,
+    ws_connections: Arc<Mutex<WsConnections>>,
+    room_manager: Arc<Mutex<RoomManager>>,
+    history: Arc<Mutex<History>>,
+    cluster: &Cluster,
+) -> Result<(), ChatError> {
+    let (room_id, participants) = {
+        let lock_history = history.lock().await;
+        let message = lock_history.get(&req.message_id)?;
+        let participants: HashSet<Uuid> = message
+            .receiver_id
+            .into_iter()
+            .chain(Some(message.sender_id))
+            .collect();
+        (message.room_id, participants)
+    };
+
+    // same audience as the original message
+    match room_id {
+        Some(room_id) => {
+            if !room_manager
+                .lock()
                 .await
+                .is_member(&room_id, &conn_id)
+                .await?
+            {
+                return Err(NOT_ROOM_MEMBER.to_owned());
+            }
+        }
+        None => {
+            if !participants.contains(&conn_id) {
+                return Err(MESSAGE_NOT_FOUND.to_owned());
+            }
+        }
+    }
+
+    let response = {
+        let mut lock_history = history.lock().await;
+        let message = if add {
+            lock_history.add_reaction(&req.message_id, conn_id, &req.emoji)?
+        } else {
+            lock_history.remove_reaction(&req.message_id, &conn_id, &req.emoji)?
+        };
+        cluster
+            .reaction_changed(message.id, conn_id, &req.emoji, add)
+            .await;
+        create_response(
+            responses::ResponseType::ReactionsUpdated,
+            responses::ReactionsUpdated {
+                message_id: message.id,
+                room_id: message.room_id,
+                reactions: message.reactions_response(),
+            },
+        )?
+    };
+
+    match room_id {
+        Some(room_id) => room_manager.lock().await.all(&room_id, &response).await,
+        None => {
+            multicast(Arc::clone(&ws_connections), &participants, &response).await?;
+            let lock_connections = ws_connections.lock().await;
+            for participant in &participants {
+                if !lock_connections.contains_key(participant) {
+                    cluster.direct(*participant, &response).await;
+                }
             }
+            Ok(())
+        }
+    }
+}
+
+async fn mute_room(
+    conn_id: Uuid,
+    req: &requests::MuteRoom,
+    ws_connections: Arc<Mutex<WsConnections>>,
+    room_manager: Arc<Mutex<RoomManager>>,
+) -> Result<(), ChatError> {
+    room_manager
+        .lock()
+        .await
+        .set_muted(&req.room_id, &conn_id, req.muted)
+        .await?;
+
+    direct(
+        Arc::clone(&ws_connections),
+        conn_id,
+        &create_response(
+            responses::ResponseType::RoomMuted,
+            responses::RoomMuted {
+                room_id: req.room_id,
+                muted: req.muted,
+            },
+        )?,
+    )
+    .await
+}
+
+async fn unread_mentions(
+    conn_id: Uuid,
+    clear: Option<&requests::ClearMentions>,
+    ws_connections: Arc<Mutex<WsConnections>>,
+) -> Result<(), ChatError> {
+    let lock_connections = &mut ws_connections.lock().await;
+    let connection = lock_connections.get_mut(&conn_id).ok_or(CLIENT_NOT_FOUND)?;
+
+    match clear.map(|req| req.room_id) {
+        Some(Some(room_id)) => {
+            connection.unread_mentions.remove(&room_id);
+        }
+        Some(None) => connection.unread_mentions.clear(),
+        None => {}
+    }
+
+    let response = create_response(
+        responses::ResponseType::UnreadMentions,
+        responses::UnreadMentions {
+            total: connection.unread_mentions.values().sum(),
+            rooms: connection
+                .unread_mentions
+                .iter()
+                .map(|(room_id, count)| responses::RoomMentions {
+                    room_id: *room_id,
+                    count: *count,
+                })
+                .collect(),
         },
+    )?;
+    connection.send(&response).await
+}
+
+async fn upload_start(
+    conn_id: Uuid,
+    req: &requests::UploadStart,
+    ws_connections: Arc<Mutex<WsConnections>>,
+    uploads: Arc<Mutex<UploadManager>>,
+) -> Result<(), ChatError> {
+    let upload_id =
+        uploads
+            .lock()
+            .await
+            .start(conn_id, &req.file_name, &req.mime_type, req.size)?;
+
+    direct(
+        Arc::clone(&ws_connections),
+        conn_id,
+        &create_response(
+            responses::ResponseType::UploadStarted,
+            responses::UploadStarted {
+                upload_id,
+                chunk_size: CHUNK_SIZE,
+            },
+        )?,
+    )
+    .await
+}
+
+async fn upload_chunk(
+    conn_id: Uuid,
+    req: &requests::UploadChunk,
+    ws_connections: Arc<Mutex<WsConnections>>,
+    uploads: Arc<Mutex<UploadManager>>,
+) -> Result<(), ChatError> {
+    let attachment = append_chunk(&uploads, conn_id, req.upload_id, req.data.clone()).await?;
+
+    match attachment {
+        Some(attachment) => {
+            direct(
+                Arc::clone(&ws_connections),
+                conn_id,
+                &create_response(
+                    responses::ResponseType::UploadCompleted,
+                    responses::UploadCompleted {
+                        attachment_id: attachment.id,
+                        file_name: attachment.file_name,
+                        mime_type: attachment.mime_type,
+                        size: attachment.size,
+                    },
+                )?,
+            )
+            .await
+        }
+        None => Ok(()),
+    }
+}
+
+async fn download(
+    conn_id: Uuid,
+    req: &requests::Download,
+    ws_connections: Arc<Mutex<WsConnections>>,
+    room_manager: Arc<Mutex<RoomManager>>,
+    uploads: Arc<Mutex<UploadManager>>,
+) -> Result<(), ChatError> {
+    let attachment = uploads.lock().await.get(&req.attachment_id)?.clone();
+
+    // the owner, direct recipients and current members of rooms it was sent to
+    let mut authorized = attachment.owner_id == conn_id || attachment.users.contains(&conn_id);
+    for room_id in &attachment.rooms {
+        if authorized {
+            break;
+        }
+        authorized = room_manager
+            .lock()
+            .await
+            .is_member(room_id, &conn_id)
+            .await
+            .unwrap_or(false);
+    }
+    if !authorized {
+        return Err(ATTACHMENT_NOT_FOUND.to_owned());
+    }
+
+    let storage = uploads.lock().await.storage();
+
+    ws_connections
+        .lock()
+        .await
+        .get_mut(&conn_id)
+        .ok_or(CLIENT_NOT_FOUND)?
+        .send(&create_response(
+            responses::ResponseType::Download,
+            responses::Download {
+                attachment_id: attachment.id,
+                file_name: attachment.file_name,
+                mime_type: attachment.mime_type,
+                size: attachment.size,
+                chunk_size: CHUNK_SIZE,
+            },
+        )?)
+        .await?;
+    // one chunk in memory at a time, other clients are served in between
+    let mut offset = 0;
+    while offset < attachment.size {
+        let chunk = read_chunk(Arc::clone(&storage), attachment.id, offset).await?;
+        if chunk.is_empty() {
+            break;
+        }
+        let len = chunk.len() as u64;
+        let lock_connections = &mut ws_connections.lock().await;
+        let connection = lock_connections.get_mut(&conn_id).ok_or(CLIENT_NOT_FOUND)?;
+        match connection.codec {
+            Codec::Json => {
+                connection
+                    .send_binary(binary_frame(&attachment.id, &chunk))
+                    .await?
+            }
+            Codec::MessagePack => {
+                connection
+                    .send(&create_response(
+                        responses::ResponseType::DownloadChunk,
+                        responses::DownloadChunk {
+                            attachment_id: attachment.id,
+                            offset,
+                            data: chunk,
+                        },
+                    )?)
+                    .await?
+            }
+        }
+        offset += len;
+    }
+
+    Ok(())
+}
+
+/// Deletes a room for everyone, its members get `RoomDeleted`. Returns how
+/// many members it had.
+pub(crate) async fn delete_room(room_id: &Uuid, state: &ServerState) -> Result<usize, ChatError> {
+    let members = state.room_manager.lock().await.delete(room_id).await?;
+    room_deleted(room_id, members, state).await
+}
+
+/// Cleans up after a room deleted here or on another node.
+async fn room_deleted(
+    room_id: &Uuid,
+    members: HashSet<Uuid>,
+    state: &ServerState,
+) -> Result<usize, ChatError> {
+    state.presence.lock().await.remove_room(room_id);
+    state.history.lock().await.remove_room(room_id);
+
+    let response = create_response(
+        responses::ResponseType::RoomDeleted,
+        responses::RoomDeleted { room_id: *room_id },
+    )?;
+    let mut lock_connections = state.clients.lock().await;
+    for member in &members {
+        if let Some(connection) = lock_connections.get_mut(member) {
+            connection.unread_mentions.remove(room_id);
+            if let Err(err) = connection.send(&response).await {
+                warn!(conn_id = %connection.id, error = %err, "room deletion send failed");
+            }
+        }
+    }
+    info!(%room_id, members = members.len(), "room deleted");
+    Ok(members.len())
+}
+
+pub async fn send_error(
+    conn_id: Uuid,
+    error_message: &str,
+    ws_connections: Arc<Mutex<WsConnections>>,
+) -> Result<(), ChatError> {
+    direct(
+        Arc::clone(&ws_connections),
+        conn_id,
+        &create_response(
+            responses::ResponseType::Error,
+            responses::ResponseError {
+                message: error_message.to_owned(),
+            },
+        )?,
+    )
+    .await
+}
+
+/// Handles one request inside a span carrying the connection, the request
+/// type and the room it is about.
+pub async fn switch_request(conn_id: Uuid, request: requests::Request, state: ServerState) {
+    let span = info_span!(
+        "request",
+        conn_id = %conn_id,
+        request_type = ?request.request_type(),
+        room_id = field::Empty,
+    );
+    if let Some(room_id) = request.room_id() {
+        span.record("room_id", field::display(room_id));
+    }
+    handle_request(conn_id, request, state)
+        .instrument(span)
+        .await
+}
+
+async fn handle_request(conn_id: Uuid, mut request: requests::Request, state: ServerState) {
+    let ws_connections = Arc::clone(&state.clients);
+    let error_ws_connections = Arc::clone(&state.clients);
+    let room_manager = Arc::clone(&state.room_manager);
+    let presence = Arc::clone(&state.presence);
+    let history = Arc::clone(&state.history);
+    let uploads = Arc::clone(&state.uploads);
+    // contents are chat text, nicknames and file names, left out unless
+    // configured otherwise
+    if state.config.log_request_contents {
+        debug!(?request, "request received");
+    } else {
+        debug!("request received");
+    }
+    state.metrics.request(request.request_type());
+    if let Some(feature) = Feature::of_request(request.request_type()) {
+        let declared = ws_connections
+            .lock()
+            .await
+            .get(&conn_id)
+            .is_none_or(|connection| connection.declares(feature));
+        if !declared {
+            state.metrics.error(ErrorKind::Request);
+            let _ = send_error(conn_id, FEATURE_NOT_DECLARED, error_ws_connections).await;
+            info!(error = FEATURE_NOT_DECLARED, "request rejected");
+            return;
+        }
+    }
+    // slash commands are messages too and count against the rate
+    if let requests::Request::Message(_) = &request {
+        if let Err(error) = take_message_token(conn_id, &state.clients).await {
+            state.metrics.error(ErrorKind::Request);
+            let _ = send_error(conn_id, &error, error_ws_connections).await;
+            info!(%error, "request failed");
+            return;
+        }
+    }
+    request = match room_command(conn_id, request, &state).await {
+        Ok(Some(request)) => request,
+        Ok(None) => return,
+        Err(error) => {
+            state.metrics.error(ErrorKind::Command);
+            let _ = send_error(conn_id, &error, error_ws_connections).await;
+            info!(%error, "command failed");
+            return;
+        }
+    };
+    // hooks see the request that is executed, not the command producing it
+    let ctx = HookContext {
+        conn_id,
+        state: state.clone(),
+    };
+    if let Err(error) = state.hooks.before(&ctx, &mut request).await {
+        state.metrics.error(ErrorKind::Hook);
+        let _ = send_error(conn_id, &error, error_ws_connections).await;
+        info!(%error, "request rejected by hook");
+        return;
+    }
+
+    let result = match &request {
+        requests::Request::SetNickname(req) => {
+            set_nickname(
+                conn_id,
+                req,
+                state.auth.as_ref(),
+                ws_connections,
+                presence,
+                &state.cluster,
+            )
+            .await
+        }
+        requests::Request::Message(req) => {
+            message(
+                conn_id,
+                req,
+                ws_connections,
+                room_manager,
+                history,
+                uploads,
+                &state.cluster,
+            )
+            .await
+        }
+        requests::Request::Hello(req) => {
+            hello(conn_id, req, state.features(), ws_connections).await
+        }
         requests::Request::GetId => get_id(conn_id, ws_connections).await,
-        requests::Request::Online => online(conn_id, ws_connections).await,
-        requests::Request::GlobalOnline => global_online(conn_id, ws_connections).await,
-        requests::Request::Disconnected => disconnected(conn_id, ws_connections).await,
+        requests::Request::Online => {
+            online(conn_id, ws_connections, presence, &state.cluster).await
+        }
+        requests::Request::GlobalOnline => {
+            global_online(conn_id, ws_connections, &state.cluster).await
+        }
+        requests::Request::Disconnected => {
+            disconnected(
+                conn_id,
+                ws_connections,
+                room_manager,
+                presence,
+                uploads,
+                history,
+                &state.cluster,
+            )
+            .await
+        }
         requests::Request::CreateRoom(req) => {
-            create_room(conn_id, req, ws_connections, room_manager).await
+            create_room(conn_id, req, ws_connections, room_manager, presence).await
         }
         requests::Request::JoinRoom(req) => {
-            join_room(conn_id, req, ws_connections, room_manager).await
+            join_room(
+                conn_id,
+                req,
+                ws_connections,
+                room_manager,
+                presence,
+                history,
+                Arc::clone(&state.announcements),
+            )
+            .await
         }
-    } {
-        let _ = send_error(conn_id, &error, error_ws_connections).await;
-        
-        println!("{:?} failed with msg: '{}'", request, error);
+        requests::Request::SubscribePresence(req) => {
+            subscribe_presence(conn_id, req, ws_connections, presence, &state.cluster).await
+        }
+        requests::Request::UnsubscribePresence(req) => {
+            unsubscribe_presence(conn_id, req, presence).await
+        }
+        requests::Request::GetHistory(req) => {
+            get_history(conn_id, req, ws_connections, room_manager, history).await
+        }
+        requests::Request::GetThread(req) => {
+            get_thread(conn_id, req, ws_connections, room_manager, history).await
+        }
+        requests::Request::MuteRoom(req) => {
+            mute_room(conn_id, req, ws_connections, room_manager).await
+        }
+        requests::Request::GetMentions => unread_mentions(conn_id, None, ws_connections).await,
+        requests::Request::ClearMentions(req) => {
+            unread_mentions(conn_id, Some(req), ws_connections).await
+        }
+        requests::Request::UploadStart(req) => {
+            upload_start(conn_id, req, ws_connections, uploads).await
+        }
+        requests::Request::UploadChunk(req) => {
+            upload_chunk(conn_id, req, ws_connections, uploads).await
+        }
+        requests::Request::UpdateRoom(req) => {
+            update_room(conn_id, req, room_manager, uploads).await
+        }
+        requests::Request::Announce(req) => announce_request(conn_id, req, &state).await,
+        requests::Request::Unpin(req) => unpin(conn_id, req, &state).await,
+        requests::Request::LeaveRoom(req) => {
+            leave_room(
+                conn_id,
+                req,
+                ws_connections,
+                room_manager,
+                presence,
+                history,
+            )
+            .await
+        }
+        requests::Request::RegisterCommands(req) => {
+            register_commands(conn_id, req, ws_connections).await
+        }
+        requests::Request::Download(req) => {
+            download(conn_id, req, ws_connections, room_manager, uploads).await
+        }
+        requests::Request::AddReaction(req) => {
+            reaction(
+                conn_id,
+                req,
+                true,
+                ws_connections,
+                room_manager,
+                history,
+                &state.cluster,
+            )
+            .await
+        }
+        requests::Request::RemoveReaction(req) => {
+            reaction(
+                conn_id,
+                req,
+                false,
+                ws_connections,
+                room_manager,
+                history,
+                &state.cluster,
+            )
+            .await
+        }
+    };
+    match result {
+        Ok(()) => {
+            if let requests::Request::Message(req) = &request {
+                state.metrics.message_relayed(&req.message_type);
+            }
+            state.hooks.after(&ctx, &request).await
+        }
+        Err(error) => {
+            state.metrics.error(ErrorKind::Request);
+            let _ = send_error(conn_id, &error, error_ws_connections).await;
+            info!(%error, "request failed");
+        }
+    }
+}
+
+/// Handles the disconnect of a connection closed by the server with
+/// `close_handled`, as its reader would have reported it.
+pub(crate) async fn disconnect(conn_id: Uuid, state: &ServerState) {
+    handle_request(conn_id, requests::Request::Disconnected, state.clone()).await
+}
+
+/// Runs the connect hooks of a new connection, closing it when one rejects.
+pub async fn connected(conn_id: Uuid, state: ServerState) {
+    let ctx = HookContext {
+        conn_id,
+        state: state.clone(),
+    };
+    if let Err(error) = state.hooks.on_connect(&ctx).await {
+        let _ = send_error(conn_id, &error, Arc::clone(&state.clients)).await;
+        if let Some(connection) = state.clients.lock().await.get_mut(&conn_id) {
+            let _ = connection.close().await;
+        }
+        info!(%conn_id, %error, "connection rejected by hook");
+        return;
+    }
+
+    if let Err(error) = rejoin_rooms(conn_id, &state).await {
+        warn!(%conn_id, %error, "rejoining rooms failed");
+    }
+    register(conn_id, &state.clients, &state.presence, &state.cluster).await;
+
+    let pinned = state.announcements.lock().await.for_everyone();
+    if let Some(connection) = state.clients.lock().await.get_mut(&conn_id) {
+        for message in pinned {
+            if let Err(err) = connection.send(&message).await {
+                warn!(%conn_id, error = %err, "pinned announcement send failed");
+            }
+        }
+    }
+}
+
+/// Tells the other nodes about a connection held here.
+async fn register(
+    conn_id: Uuid,
+    ws_connections: &Mutex<WsConnections>,
+    presence: &Mutex<PresenceManager>,
+    cluster: &Cluster,
+) {
+    if !cluster.is_clustered() {
+        return;
+    }
+    let user = match ws_connections.lock().await.get(&conn_id) {
+        Some(connection) => connection.user_info(),
+        None => return,
+    };
+    let room_ids = presence.lock().await.rooms(&conn_id);
+    cluster
+        .publish(ClusterEvent::Connected {
+            user: RegisteredUser { user, room_ids },
+        })
+        .await;
+}
+
+/// Every connection held here, as the other nodes register it.
+async fn local_users(state: &ServerState) -> Vec<RegisteredUser> {
+    let users: Vec<responses::UserInfo> = state
+        .clients
+        .lock()
+        .await
+        .values()
+        .map(|connection| connection.user_info())
+        .collect();
+    let lock_presence = state.presence.lock().await;
+    users
+        .into_iter()
+        .map(|user| RegisteredUser {
+            room_ids: lock_presence.rooms(&user.id),
+            user,
+        })
+        .collect()
+}
+
+/// Tells whoever watches them that users of another node went offline
+/// without this node hearing about it.
+async fn users_gone(users: Vec<RegisteredUser>, state: &ServerState) -> Result<(), ChatError> {
+    for registered in users {
+        let user_id = registered.user.id;
+        let audience = state
+            .presence
+            .lock()
+            .await
+            .audience_in(&user_id, &registered.room_ids);
+        multicast(
+            Arc::clone(&state.clients),
+            &audience,
+            &create_response(
+                responses::ResponseType::Offline,
+                responses::Offline { id: user_id },
+            )?,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Renews the lease on the users of this node and expires the users of
+/// nodes that stopped renewing theirs.
+pub(crate) async fn heartbeat(state: &ServerState) {
+    let users = local_users(state).await;
+    state
+        .cluster
+        .publish(ClusterEvent::Heartbeat { users })
+        .await;
+
+    let expired = state.cluster.registry.lock().await.expire();
+    if !expired.is_empty() {
+        warn!(users = expired.len(), "presence lease of a node expired");
+    }
+    if let Err(err) = users_gone(expired, state).await {
+        warn!(error = %err, "offline send failed");
+    }
+}
+
+/// Keeps a message stored on another node and tells the members connected
+/// here who are mentioned in it.
+async fn message_stored(message: StoredMessage, state: &ServerState) -> Result<(), ChatError> {
+    let (sender_id, room_id, response) =
+        (message.sender_id, message.room_id, message.to_response());
+    {
+        let mut lock_history = state.history.lock().await;
+        match (message.room_id, message.reply_to, message.receiver_id) {
+            (Some(_), Some(_), _) => {
+                lock_history.add_reply(message)?;
+            }
+            (Some(room_id), None, _) => lock_history.add_room_message(room_id, message),
+            (None, _, Some(receiver_id)) => lock_history.add_direct_message(receiver_id, message),
+            (None, _, None) => {}
+        }
+    }
+    let room_id = match room_id {
+        Some(room_id) => room_id,
+        None => return Ok(()),
+    };
+    let result = mention_members(
+        sender_id,
+        room_id,
+        response,
+        Arc::clone(&state.clients),
+        Arc::clone(&state.room_manager),
+    )
+    .await;
+    match result {
+        // the room may not have reached this node yet
+        Err(err) if err == ROOM_NOT_FOUND => Ok(()),
+        result => result,
+    }
+}
+
+/// Delivers what another node published to the connections held here.
+pub(crate) async fn cluster_event(message: ClusterMessage, state: &ServerState) {
+    // a node sees its own messages too, it already delivered them
+    if message.origin == state.cluster.node_id {
+        return;
+    }
+    let result = match message.event {
+        ClusterEvent::Direct {
+            receiver_id,
+            response,
+        } => {
+            direct(
+                Arc::clone(&state.clients),
+                receiver_id,
+                &response.envelope(),
+            )
+            .await
+        }
+        ClusterEvent::Room {
+            room_id,
+            unmuted_only,
+            response,
+        } => {
+            let lock_room_manager = state.room_manager.lock().await;
+            match lock_room_manager
+                .deliver(&room_id, unmuted_only, &response.envelope())
+                .await
+            {
+                // the room may not have reached this node yet
+                Err(err) if err == ROOM_NOT_FOUND => Ok(()),
+                result => result,
+            }
+        }
+        ClusterEvent::Everyone { response } => {
+            send(Arc::clone(&state.clients), |_| true, &response.envelope()).await
+        }
+        ClusterEvent::Presence {
+            user_id,
+            room_ids,
+            response,
+        } => {
+            let audience = state.presence.lock().await.audience_in(&user_id, &room_ids);
+            multicast(Arc::clone(&state.clients), &audience, &response.envelope()).await
+        }
+        ClusterEvent::RoomChanged { room } => {
+            state
+                .room_manager
+                .lock()
+                .await
+                .replicate(room, Arc::clone(&state.clients))
+                .await;
+            Ok(())
+        }
+        ClusterEvent::Heartbeat { users } => {
+            let gone = state.cluster.registry.lock().await.renew(
+                message.origin,
+                users,
+                state.config.presence_lease,
+            );
+            users_gone(gone, state).await
+        }
+        ClusterEvent::Connected { user } => {
+            state.cluster.registry.lock().await.connected(
+                message.origin,
+                user,
+                state.config.presence_lease,
+            );
+            Ok(())
+        }
+        ClusterEvent::Disconnected { user_id } => {
+            state
+                .cluster
+                .registry
+                .lock()
+                .await
+                .disconnected(&message.origin, &user_id);
+            Ok(())
+        }
+        ClusterEvent::RoomDeleted { room_id } => {
+            let forgotten = state.room_manager.lock().await.forget(&room_id).await;
+            match forgotten {
+                Ok(members) => room_deleted(&room_id, members, state).await.map(|_| ()),
+                Err(err) if err == ROOM_NOT_FOUND => Ok(()),
+                Err(err) => Err(err),
+            }
+        }
+        ClusterEvent::MessageStored { message } => message_stored(message, state).await,
+        ClusterEvent::ReactionChanged {
+            message_id,
+            user_id,
+            emoji,
+            added,
+        } => {
+            let mut lock_history = state.history.lock().await;
+            let result = match added {
+                true => lock_history.add_reaction(&message_id, user_id, &emoji),
+                false => lock_history.remove_reaction(&message_id, &user_id, &emoji),
+            };
+            match result {
+                // the message may have left the history here already
+                Err(err) if err == MESSAGE_NOT_FOUND => Ok(()),
+                result => result.map(|_| ()),
+            }
+        }
+    };
+    if let Err(err) = result {
+        warn!(origin = %message.origin, error = %err, "cluster event failed");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::config::Config;
+    use crate::rate_limit::RateLimit;
+    use crate::test_support::{self, TestClient};
+
+    #[tokio::test]
+    async fn non_member_cannot_post_to_room() {
+        let handle = test_support::builder().start().await.unwrap();
+        let mut alice = TestClient::connect(handle.local_addr(), "alice").await;
+        let mut bob = TestClient::connect(handle.local_addr(), "bob").await;
+        let room_id = alice.create_room("team").await;
+
+        bob.send(
+            "Message",
+            json!({ "messageType": "Room", "receiverId": room_id, "message": "let me in" }),
+        )
+        .await;
+        assert_eq!(bob.expect("Error").await["message"], NOT_ROOM_MEMBER);
+        assert!(alice.drain().await.is_empty());
+
+        alice.send("GetHistory", json!({ "roomId": room_id })).await;
+        let history = alice.expect("History").await;
+        assert_eq!(history["messages"], json!([]));
+
+        drop((alice, bob));
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn undeclared_features_are_left_out() {
+        let handle = test_support::builder().start().await.unwrap();
+        let mut alice = TestClient::connect(handle.local_addr(), "alice").await;
+        let mut bob = TestClient::connect(handle.local_addr(), "bob").await;
+        let room_id = alice.create_room("team").await;
+        bob.send("JoinRoom", json!({ "id": room_id.to_string() }))
+            .await;
+        bob.send(
+            "Hello",
+            json!({ "protocolVersion": 1, "capabilities": ["history"] }),
+        )
+        .await;
+        bob.drain().await;
+
+        bob.send("GetThread", json!({ "messageId": Uuid::new_v4() }))
+            .await;
+        assert_eq!(bob.expect("Error").await["message"], FEATURE_NOT_DECLARED);
+
+        let post = json!({ "messageType": "Room", "receiverId": room_id, "message": "hi" });
+        alice.send("Message", post).await;
+        let message_id = alice.expect("Message").await["messageId"].clone();
+        alice.drain().await;
+        bob.expect("Message").await;
+        let reply = json!({
+            "messageType": "Room",
+            "receiverId": room_id,
+            "message": "hello",
+            "replyTo": message_id,
+        });
+        alice.send("Message", reply).await;
+        alice.expect("ThreadReply").await;
+        let received: Vec<String> = bob
+            .drain()
+            .await
+            .into_iter()
+            .map(|(kind, _)| kind)
+            .collect();
+        assert!(!received.contains(&"ThreadReply".to_owned()));
+
+        drop((alice, bob));
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn commands_count_against_the_message_rate() {
+        let config = Config {
+            message_rate: Some(RateLimit {
+                burst: 1,
+                per_second: 0.001,
+            }),
+            ..test_support::config()
+        };
+        let handle = test_support::builder()
+            .config(config)
+            .start()
+            .await
+            .unwrap();
+        let mut alice = TestClient::connect(handle.local_addr(), "alice").await;
+        let room_id = alice.create_room("team").await;
+        let command =
+            json!({ "messageType": "Room", "receiverId": room_id, "message": "/nick al" });
+
+        alice.send("Message", command.clone()).await;
+        alice.drain().await;
+        alice.send("Message", command).await;
+        assert_eq!(alice.expect("Error").await["message"], RATE_LIMITED);
+
+        drop(alice);
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn direct_message_to_unknown_user_is_rejected() {
+        let handle = test_support::builder().start().await.unwrap();
+        let mut alice = TestClient::connect(handle.local_addr(), "alice").await;
+
+        let dm = json!({ "messageType": "User", "receiverId": Uuid::new_v4(), "message": "hi" });
+        alice.send("Message", dm).await;
+        let responses = alice.drain().await;
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].0, "Error");
+        assert_eq!(responses[0].1["message"], CLIENT_NOT_FOUND);
+
+        drop(alice);
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn connections_without_a_nickname_are_refused_politely() {
+        let handle = test_support::builder().start().await.unwrap();
+        let mut alice = TestClient::connect(handle.local_addr(), "alice").await;
+        let mut stranger = TestClient::anonymous(handle.local_addr()).await;
+
+        alice.send("GlobalOnline", json!({})).await;
+        let online = alice.expect("GlobalOnline").await;
+        assert_eq!(online["users"].as_array().unwrap().len(), 1);
+        assert_eq!(online["users"][0]["name"], "alice");
+
+        let dm = json!({ "messageType": "User", "receiverId": alice.id, "message": "hi" });
+        stranger.send("Message", dm).await;
+        assert_eq!(stranger.expect("Error").await["message"], NICKNAME_REQUIRED);
+        stranger.send("Online", json!({})).await;
+        assert_eq!(stranger.expect("Error").await["message"], NICKNAME_REQUIRED);
+
+        // the server is still there
+        alice.send("GlobalOnline", json!({})).await;
+        alice.expect("GlobalOnline").await;
+
+        drop((alice, stranger));
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn presence_only_reaches_subscribers() {
+        let handle = test_support::builder().start().await.unwrap();
+        let mut alice = TestClient::connect(handle.local_addr(), "alice").await;
+        let mut bob = TestClient::connect(handle.local_addr(), "bob").await;
+        let mut stranger = TestClient::connect(handle.local_addr(), "stranger").await;
+
+        bob.send("SubscribePresence", json!({ "userIds": [alice.id] }))
+            .await;
+        let subscribed = bob.expect("PresenceSubscribed").await;
+        assert_eq!(subscribed["users"][0]["name"], "alice");
+
+        alice.send("Online", json!({})).await;
+        let online = bob.expect("Online").await;
+        assert_eq!(online["id"], alice.id.to_string());
+        assert_eq!(online["name"], "alice");
+
+        let alice_id = alice.id;
+        drop(alice);
+        assert_eq!(bob.expect("Offline").await["id"], alice_id.to_string());
+
+        let events = stranger.drain().await;
+        assert!(events
+            .iter()
+            .all(|(response_type, _)| response_type != "Online" && response_type != "Offline"));
+
+        drop((bob, stranger));
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn members_see_others_join_and_leave() {
+        let handle = test_support::builder().start().await.unwrap();
+        let mut alice = TestClient::connect(handle.local_addr(), "alice").await;
+        let mut bob = TestClient::connect(handle.local_addr(), "bob").await;
+        let room_id = alice.create_room("team").await;
+
+        bob.send("JoinRoom", json!({ "id": room_id.to_string() }))
+            .await;
+        let joined = bob.expect("RoomJoined").await;
+        let members: Vec<&str> = joined["members"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|member| member["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(members, ["alice", "bob"]);
+        let event = alice.expect("MemberJoined").await;
+        assert_eq!(event["roomId"], room_id.to_string());
+        assert_eq!(event["user"]["id"], bob.id.to_string());
+
+        bob.send("LeaveRoom", json!({ "roomId": room_id })).await;
+        let event = alice.expect("MemberLeft").await;
+        assert_eq!(event["user"]["name"], "bob");
+        assert_eq!(event["removedBy"], Value::Null);
+
+        alice.send("GetHistory", json!({ "roomId": room_id })).await;
+        let history = alice.expect("History").await;
+        let entries: Vec<(&Value, &Value)> = history["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|message| (&message["message"], &message["system"]))
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                (&json!("bob joined the room"), &json!(true)),
+                (&json!("bob left the room"), &json!(true)),
+            ]
+        );
+
+        drop((alice, bob));
+        handle.shutdown().await;
     }
 }
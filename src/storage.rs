@@ -0,0 +1,78 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use uuid::Uuid;
+
+pub const STORAGE_NOT_WRITABLE: &str = "Upload directory is not writable";
+
+/// Where uploaded attachment bytes live.
+pub trait AttachmentStorage: Send + Sync {
+    fn create(&self, id: &Uuid) -> std::io::Result<()>;
+    fn append(&self, id: &Uuid, chunk: &[u8]) -> std::io::Result<()>;
+    /// Up to `len` bytes from `offset` on, fewer at the end.
+    fn read(&self, id: &Uuid, offset: u64, len: usize) -> std::io::Result<Vec<u8>>;
+    fn remove(&self, id: &Uuid) -> std::io::Result<()>;
+
+    /// Whether the storage can take uploads, for readiness checks.
+    fn check(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+pub struct LocalStorage {
+    dir: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> std::io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    fn path(&self, id: &Uuid) -> PathBuf {
+        // file names are never taken from the client
+        self.dir.join(id.to_string())
+    }
+}
+
+impl AttachmentStorage for LocalStorage {
+    fn create(&self, id: &Uuid) -> std::io::Result<()> {
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(self.path(id))?;
+        Ok(())
+    }
+
+    fn append(&self, id: &Uuid, chunk: &[u8]) -> std::io::Result<()> {
+        OpenOptions::new()
+            .append(true)
+            .open(self.path(id))?
+            .write_all(chunk)
+    }
+
+    fn read(&self, id: &Uuid, offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        let mut file = File::open(self.path(id))?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut chunk = Vec::with_capacity(len);
+        file.take(len as u64).read_to_end(&mut chunk)?;
+        Ok(chunk)
+    }
+
+    fn remove(&self, id: &Uuid) -> std::io::Result<()> {
+        fs::remove_file(self.path(id))
+    }
+
+    fn check(&self) -> std::io::Result<()> {
+        let metadata = fs::metadata(&self.dir)?;
+        if !metadata.is_dir() || metadata.permissions().readonly() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                STORAGE_NOT_WRITABLE,
+            ));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,206 @@
+//! Helpers for tests that talk to a running server over websockets.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::header::{HeaderValue, AUTHORIZATION};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::server::{Server, ServerBuilder};
+
+// how long to wait for a response before deciding none is coming
+const RECV_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// A configuration that keeps uploads out of the working directory.
+pub fn config() -> Config {
+    Config {
+        upload_dir: std::env::temp_dir().join(format!("chat-server-test-{}", Uuid::new_v4())),
+        ..Config::default()
+    }
+}
+
+/// A builder on a free local port.
+pub fn builder() -> ServerBuilder {
+    Server::builder().bind("127.0.0.1:0").config(config())
+}
+
+/// Sends one HTTP request, returns the status and the body.
+pub async fn http(
+    addr: SocketAddr,
+    method: &str,
+    path: &str,
+    token: Option<&str>,
+    body: &str,
+) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let mut request = format!(
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n",
+        method,
+        path,
+        body.len()
+    );
+    if let Some(token) = token {
+        request.push_str(&format!("Authorization: Bearer {}\r\n", token));
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+    (status, body.to_owned())
+}
+
+pub struct TestClient {
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    pub id: Uuid,
+}
+
+impl TestClient {
+    /// Connects with a nickname and drains the greeting.
+    pub async fn connect(addr: SocketAddr, name: &str) -> Self {
+        let mut client = TestClient::open(format!("ws://{}", addr)).await;
+        client.send("SetNickname", json!({ "name": name })).await;
+        client.drain().await;
+        client
+    }
+
+    /// Connects without a nickname.
+    pub async fn anonymous(addr: SocketAddr) -> Self {
+        let mut client = TestClient::open(format!("ws://{}", addr)).await;
+        client.drain().await;
+        client
+    }
+
+    /// Connects with a bearer token, the API key of a bot or the admin token.
+    pub async fn with_token(addr: SocketAddr, token: &str) -> Self {
+        let mut request = format!("ws://{}", addr).into_client_request().unwrap();
+        let bearer = HeaderValue::from_str(&format!("Bearer {}", token)).unwrap();
+        request.headers_mut().insert(AUTHORIZATION, bearer);
+        let mut client = TestClient::open(request).await;
+        client.drain().await;
+        client
+    }
+
+    /// Connects without sending anything, what the server greets with is
+    /// left to read. The id stays nil.
+    pub async fn quiet(addr: SocketAddr) -> Self {
+        let (socket, _) = connect_async(format!("ws://{}", addr)).await.unwrap();
+        TestClient {
+            socket,
+            id: Uuid::nil(),
+        }
+    }
+
+    async fn open(request: impl IntoClientRequest + Unpin) -> Self {
+        let (socket, _) = connect_async(request).await.unwrap();
+        let mut client = TestClient {
+            socket,
+            id: Uuid::nil(),
+        };
+        client.send("GetId", json!({})).await;
+        let id = client.expect("GetId").await;
+        client.id = serde_json::from_value(id["id"].clone()).unwrap();
+        client
+    }
+
+    pub async fn send(&mut self, request_type: &str, data: Value) {
+        let request = json!({ "requestType": request_type, "data": data.to_string() });
+        self.socket
+            .send(Message::Text(request.to_string()))
+            .await
+            .unwrap();
+    }
+
+    pub async fn send_binary(&mut self, data: Vec<u8>) {
+        self.socket.send(Message::Binary(data)).await.unwrap();
+    }
+
+    /// Skips text responses until a binary frame arrives.
+    pub async fn recv_binary(&mut self) -> Vec<u8> {
+        loop {
+            let message = tokio::time::timeout(RECV_TIMEOUT, self.socket.next())
+                .await
+                .expect("no binary frame received")
+                .unwrap()
+                .unwrap();
+            if let Message::Binary(data) = message {
+                return data;
+            }
+        }
+    }
+
+    /// The next response type and its data, `None` after a quiet period.
+    pub async fn recv(&mut self) -> Option<(String, Value)> {
+        loop {
+            let message = tokio::time::timeout(RECV_TIMEOUT, self.socket.next())
+                .await
+                .ok()??
+                .ok()?;
+            if let Message::Text(text) = message {
+                let response: Value = serde_json::from_str(&text).unwrap();
+                let data = match &response["data"] {
+                    Value::String(data) => serde_json::from_str(data).unwrap(),
+                    data => data.clone(),
+                };
+                let response_type = response["responseType"].as_str().unwrap().to_owned();
+                return Some((response_type, data));
+            }
+        }
+    }
+
+    /// Skips other responses until one of the type arrives.
+    pub async fn expect(&mut self, response_type: &str) -> Value {
+        loop {
+            match self.recv().await {
+                Some((received, data)) if received == response_type => return data,
+                Some(_) => continue,
+                None => panic!("no {} received", response_type),
+            }
+        }
+    }
+
+    /// Every response until a quiet period.
+    pub async fn drain(&mut self) -> Vec<(String, Value)> {
+        let mut responses = vec![];
+        while let Some(response) = self.recv().await {
+            responses.push(response);
+        }
+        responses
+    }
+
+    /// The room id of a new room the client is the first member of.
+    pub async fn create_room(&mut self, name: &str) -> Uuid {
+        self.send("CreateRoom", json!({ "name": name })).await;
+        let room = self.expect("RoomCreated").await;
+        self.drain().await;
+        serde_json::from_value(room["id"].clone()).unwrap()
+    }
+
+    pub async fn join_room(&mut self, room_id: Uuid) {
+        self.send("JoinRoom", json!({ "id": room_id.to_string() }))
+            .await;
+        self.expect("RoomJoined").await;
+        self.drain().await;
+    }
+
+    /// Whether the server closed the connection.
+    pub async fn closed(&mut self) -> bool {
+        matches!(
+            tokio::time::timeout(RECV_TIMEOUT, async {
+                while let Some(Ok(_)) = self.socket.next().await {}
+            })
+            .await,
+            Ok(())
+        )
+    }
+}
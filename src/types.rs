@@ -1,11 +1,4 @@
-use std::{collections::HashMap, sync::Arc};
-
-use serde::{Deserialize, Serialize};
-use tokio::sync::Mutex;
 use tokio_tungstenite::tungstenite;
-use uuid::Uuid;
-
-use crate::ws_client_connection::WsClientConnection;
 
 pub type ChatError = String;
 
@@ -15,4 +8,8 @@ pub fn tungstenite_error_to_chat_error(error: tungstenite::Error) -> ChatError {
 
 pub fn serde_error_to_chat_error(error: serde_json::Error) -> ChatError {
     error.to_string()
-}
\ No newline at end of file
+}
+
+pub fn io_error_to_chat_error(error: std::io::Error) -> ChatError {
+    error.to_string()
+}
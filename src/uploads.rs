@@ -0,0 +1,368 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use tokio::sync::Mutex;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::content::MessageContent;
+use crate::storage::AttachmentStorage;
+use crate::types::{io_error_to_chat_error, ChatError};
+
+// payload bytes per binary frame, the 16 byte upload id prefix comes on top
+pub const CHUNK_SIZE: usize = 64 * 1024;
+pub const MAX_PENDING_UPLOADS: usize = 4;
+pub const ALLOWED_MIME_PREFIXES: [&str; 6] = [
+    "image/",
+    "text/",
+    "audio/",
+    "video/",
+    "application/pdf",
+    "application/zip",
+];
+
+pub const UPLOAD_NOT_FOUND: &str = "Upload not found";
+pub const ATTACHMENT_NOT_FOUND: &str = "Attachment not found";
+pub const MIME_TYPE_NOT_ALLOWED: &str = "Attachment type not allowed";
+pub const TOO_MANY_UPLOADS: &str = "Too many uploads in progress";
+pub const CHUNK_TOO_LARGE: &str = "Chunk is too large";
+pub const UPLOAD_SIZE_EXCEEDED: &str = "Upload exceeds declared size";
+
+pub struct Upload {
+    pub id: Uuid,
+    pub owner_id: Uuid,
+    pub file_name: String,
+    pub mime_type: String,
+    pub size: u64,
+    // counts chunks still being written too
+    pub received: u64,
+    pub written: u64,
+}
+
+#[derive(Clone)]
+pub struct Attachment {
+    pub id: Uuid,
+    pub owner_id: Uuid,
+    pub file_name: String,
+    pub mime_type: String,
+    pub size: u64,
+    // who the attachment was sent to
+    pub users: HashSet<Uuid>,
+    pub rooms: HashSet<Uuid>,
+}
+
+impl Attachment {
+    pub fn content(&self) -> MessageContent {
+        MessageContent::Attachment {
+            attachment_id: self.id,
+            file_name: self.file_name.to_owned(),
+            mime_type: self.mime_type.to_owned(),
+            size: self.size,
+        }
+    }
+}
+
+pub enum ShareTarget {
+    User(Uuid),
+    Room(Uuid),
+}
+
+pub struct UploadManager {
+    storage: Arc<dyn AttachmentStorage>,
+    uploads: HashMap<Uuid, Upload>,
+    attachments: HashMap<Uuid, Attachment>,
+}
+
+impl UploadManager {
+    pub fn new(storage: Box<dyn AttachmentStorage>) -> Self {
+        Self {
+            storage: Arc::from(storage),
+            uploads: HashMap::new(),
+            attachments: HashMap::new(),
+        }
+    }
+
+    pub fn check_storage(&self) -> std::io::Result<()> {
+        self.storage.check()
+    }
+
+    pub fn start(
+        &mut self,
+        owner_id: Uuid,
+        file_name: &str,
+        mime_type: &str,
+        size: u64,
+    ) -> Result<Uuid, ChatError> {
+        let id = Uuid::new_v4();
+        // same limits as an attachment reference in a message
+        MessageContent::Attachment {
+            attachment_id: id,
+            file_name: file_name.to_owned(),
+            mime_type: mime_type.to_owned(),
+            size,
+        }
+        .validate()?;
+        if !ALLOWED_MIME_PREFIXES
+            .iter()
+            .any(|prefix| mime_type.starts_with(prefix))
+        {
+            return Err(MIME_TYPE_NOT_ALLOWED.to_owned());
+        }
+        let pending = self
+            .uploads
+            .values()
+            .filter(|upload| upload.owner_id == owner_id)
+            .count();
+        if pending >= MAX_PENDING_UPLOADS {
+            return Err(TOO_MANY_UPLOADS.to_owned());
+        }
+
+        self.storage.create(&id).map_err(io_error_to_chat_error)?;
+        self.uploads.insert(
+            id,
+            Upload {
+                id,
+                owner_id,
+                file_name: file_name.to_owned(),
+                mime_type: mime_type.to_owned(),
+                size,
+                received: 0,
+                written: 0,
+            },
+        );
+
+        Ok(id)
+    }
+
+    /// Checks a chunk and counts it as received before `append_chunk`
+    /// writes it to the returned storage.
+    fn reserve(
+        &mut self,
+        owner_id: &Uuid,
+        upload_id: &Uuid,
+        len: usize,
+    ) -> Result<Arc<dyn AttachmentStorage>, ChatError> {
+        let upload = self
+            .uploads
+            .get_mut(upload_id)
+            .filter(|upload| upload.owner_id == *owner_id)
+            .ok_or(UPLOAD_NOT_FOUND)?;
+        if len > CHUNK_SIZE {
+            return Err(CHUNK_TOO_LARGE.to_owned());
+        }
+        if upload.received + len as u64 > upload.size {
+            self.abort(upload_id);
+            return Err(UPLOAD_SIZE_EXCEEDED.to_owned());
+        }
+        upload.received += len as u64;
+        Ok(Arc::clone(&self.storage))
+    }
+
+    /// Counts a written chunk, returns the attachment once every declared
+    /// byte is stored.
+    fn written(&mut self, upload_id: &Uuid, len: usize) -> Result<Option<Attachment>, ChatError> {
+        // gone when the connection went away during the write
+        let upload = self.uploads.get_mut(upload_id).ok_or(UPLOAD_NOT_FOUND)?;
+        upload.written += len as u64;
+        if upload.written < upload.size {
+            return Ok(None);
+        }
+
+        let upload = self.uploads.remove(upload_id).ok_or(UPLOAD_NOT_FOUND)?;
+        let attachment = Attachment {
+            id: upload.id,
+            owner_id: upload.owner_id,
+            file_name: upload.file_name,
+            mime_type: upload.mime_type,
+            size: upload.size,
+            users: HashSet::new(),
+            rooms: HashSet::new(),
+        };
+        self.attachments.insert(attachment.id, attachment.clone());
+
+        Ok(Some(attachment))
+    }
+
+    fn abort(&mut self, upload_id: &Uuid) {
+        if self.uploads.remove(upload_id).is_some() {
+            if let Err(err) = self.storage.remove(upload_id) {
+                warn!(%upload_id, error = %err, "upload cleanup failed");
+            }
+        }
+    }
+
+    /// Drops unfinished uploads of a connection that went away.
+    pub fn abort_all(&mut self, owner_id: &Uuid) {
+        let upload_ids: Vec<Uuid> = self
+            .uploads
+            .values()
+            .filter(|upload| upload.owner_id == *owner_id)
+            .map(|upload| upload.id)
+            .collect();
+        for upload_id in upload_ids {
+            self.abort(&upload_id);
+        }
+    }
+
+    /// Grants the target access to an attachment owned by the sender and
+    /// returns the server-side metadata to put in the message.
+    pub fn share(
+        &mut self,
+        attachment_id: &Uuid,
+        sender_id: &Uuid,
+        target: ShareTarget,
+    ) -> Result<MessageContent, ChatError> {
+        let attachment = self
+            .attachments
+            .get_mut(attachment_id)
+            .filter(|attachment| attachment.owner_id == *sender_id)
+            .ok_or(ATTACHMENT_NOT_FOUND)?;
+        match target {
+            ShareTarget::User(user_id) => attachment.users.insert(user_id),
+            ShareTarget::Room(room_id) => attachment.rooms.insert(room_id),
+        };
+
+        Ok(attachment.content())
+    }
+
+    pub fn get(&self, attachment_id: &Uuid) -> Result<&Attachment, ChatError> {
+        self.attachments
+            .get(attachment_id)
+            .ok_or_else(|| ATTACHMENT_NOT_FOUND.to_owned())
+    }
+
+    /// The storage, for reading attachments without holding the manager.
+    pub fn storage(&self) -> Arc<dyn AttachmentStorage> {
+        Arc::clone(&self.storage)
+    }
+}
+
+/// Appends a chunk off the async runtime, the manager is not held during
+/// the write. Returns the attachment once every declared byte arrived.
+pub async fn append_chunk(
+    uploads: &Mutex<UploadManager>,
+    owner_id: Uuid,
+    upload_id: Uuid,
+    chunk: Vec<u8>,
+) -> Result<Option<Attachment>, ChatError> {
+    let len = chunk.len();
+    let storage = uploads.lock().await.reserve(&owner_id, &upload_id, len)?;
+    let result = match tokio::task::spawn_blocking(move || storage.append(&upload_id, &chunk)).await
+    {
+        Ok(result) => result.map_err(io_error_to_chat_error),
+        Err(err) => Err(err.to_string()),
+    };
+
+    let mut lock_uploads = uploads.lock().await;
+    if let Err(err) = result {
+        lock_uploads.abort(&upload_id);
+        return Err(err);
+    }
+    lock_uploads.written(&upload_id, len)
+}
+
+/// One chunk of an attachment, read off the async runtime.
+pub async fn read_chunk(
+    storage: Arc<dyn AttachmentStorage>,
+    attachment_id: Uuid,
+    offset: u64,
+) -> Result<Vec<u8>, ChatError> {
+    tokio::task::spawn_blocking(move || storage.read(&attachment_id, offset, CHUNK_SIZE))
+        .await
+        .map_err(|err| err.to_string())?
+        .map_err(io_error_to_chat_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::content::{INVALID_ATTACHMENT_SIZE, MAX_ATTACHMENT_SIZE};
+    use crate::test_support::{self, TestClient};
+    use crate::ws_client_connection::{binary_frame, UUID_LEN};
+
+    async fn start_upload(client: &mut TestClient, size: u64) -> Uuid {
+        client
+            .send(
+                "UploadStart",
+                json!({ "fileName": "notes.txt", "mimeType": "text/plain", "size": size }),
+            )
+            .await;
+        let started = client.expect("UploadStarted").await;
+        assert_eq!(started["chunkSize"], CHUNK_SIZE);
+        serde_json::from_value(started["uploadId"].clone()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn uploads_complete_once_every_byte_arrived() {
+        let handle = test_support::builder().start().await.unwrap();
+        let mut alice = TestClient::connect(handle.local_addr(), "alice").await;
+        let data = b"hello world".to_vec();
+
+        let upload_id = start_upload(&mut alice, data.len() as u64).await;
+        alice
+            .send_binary(binary_frame(&upload_id, &data[..6]))
+            .await;
+        assert!(alice.drain().await.is_empty());
+        alice
+            .send_binary(binary_frame(&upload_id, &data[6..]))
+            .await;
+        let completed = alice.expect("UploadCompleted").await;
+        assert_eq!(completed["attachmentId"], upload_id.to_string());
+        assert_eq!(completed["size"], data.len());
+
+        alice
+            .send("Download", json!({ "attachmentId": upload_id }))
+            .await;
+        assert_eq!(alice.expect("Download").await["size"], data.len());
+        let frame = alice.recv_binary().await;
+        assert_eq!(&frame[..UUID_LEN], upload_id.as_bytes());
+        assert_eq!(&frame[UUID_LEN..], &data[..]);
+
+        drop(alice);
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn uploads_stay_within_their_size() {
+        let handle = test_support::builder().start().await.unwrap();
+        let mut alice = TestClient::connect(handle.local_addr(), "alice").await;
+
+        let too_large = json!({
+            "fileName": "movie.mp4",
+            "mimeType": "video/mp4",
+            "size": MAX_ATTACHMENT_SIZE + 1,
+        });
+        alice.send("UploadStart", too_large).await;
+        assert_eq!(
+            alice.expect("Error").await["message"],
+            INVALID_ATTACHMENT_SIZE
+        );
+
+        let upload_id = start_upload(&mut alice, 4).await;
+        alice.send_binary(binary_frame(&upload_id, b"hello")).await;
+        assert_eq!(alice.expect("Error").await["message"], UPLOAD_SIZE_EXCEEDED);
+        // the upload was dropped with the oversized chunk
+        alice.send_binary(binary_frame(&upload_id, b"hi")).await;
+        assert_eq!(alice.expect("Error").await["message"], UPLOAD_NOT_FOUND);
+
+        drop(alice);
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn unknown_attachments_cannot_be_downloaded() {
+        let handle = test_support::builder().start().await.unwrap();
+        let mut alice = TestClient::connect(handle.local_addr(), "alice").await;
+
+        alice
+            .send("Download", json!({ "attachmentId": Uuid::new_v4() }))
+            .await;
+        assert_eq!(alice.expect("Error").await["message"], ATTACHMENT_NOT_FOUND);
+        assert!(alice.drain().await.is_empty());
+
+        drop(alice);
+        handle.shutdown().await;
+    }
+}
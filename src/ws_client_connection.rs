@@ -1,92 +1,284 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
+use chrono::{DateTime, Utc};
 use futures_util::stream::SplitSink;
 use futures_util::{SinkExt, StreamExt};
 use serde::Deserialize;
-use tokio::sync::Mutex;
 
-use crate::requests::{self, RawRequest, Request, RequestType, SetNickname};
-use crate::room_manager::RoomManager;
+use crate::capabilities::Feature;
+use crate::codec::{
+    from_msgpack, request_type_from_tag, Codec, Envelope, MsgPackData, ProtocolVersion, RequestData,
+};
+use crate::metrics::{ErrorKind, Metrics};
+use crate::rate_limit::TokenBucket;
+use crate::requests::{self, NestedRequest, RawRequest, Request, RequestType, TaggedRequest};
+use crate::responses::UserInfo;
 use crate::types::{serde_error_to_chat_error, tungstenite_error_to_chat_error, ChatError};
 use tokio::{net::TcpStream, sync::mpsc::Sender};
 use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+use tracing::{debug, field, info, info_span, Instrument, Span};
 use uuid::Uuid;
 
 pub struct WsClientConnection {
     pub id: Uuid,
     pub name: Option<String>,
+    // room id -> mentions not cleared yet
+    pub unread_mentions: HashMap<Uuid, u32>,
+    pub codec: Codec,
+    pub protocol: ProtocolVersion,
+    // declared in the client `Hello`, `None` accepts every event
+    pub capabilities: Option<BTreeSet<Feature>>,
+    pub bot: bool,
+    // connected with the admin token
+    pub admin: bool,
+    // slash commands a bot handles
+    pub commands: HashSet<String>,
+    pub message_rate: Option<TokenBucket>,
+    pub remote_addr: Option<SocketAddr>,
+    pub connected_at: DateTime<Utc>,
+    // parent of everything logged about the connection outside requests
+    pub span: Span,
+    // the metrics of the server the connection belongs to
+    pub metrics: Arc<Metrics>,
+    // set when the server handled the disconnect itself, the reader then
+    // does not report it
+    disconnect_handled: Arc<AtomicBool>,
     write_sink: SplitSink<WebSocketStream<TcpStream>, Message>,
 }
 
+pub const UUID_LEN: usize = 16;
+pub const INVALID_CHUNK: &str = "Invalid binary chunk";
+
 pub fn from_str<'a, T: Deserialize<'a>>(s: &'a str) -> Result<T, ChatError> {
-    serde_json::from_str::<T>(&s).map_err(serde_error_to_chat_error)
+    serde_json::from_str::<T>(s).map_err(serde_error_to_chat_error)
 }
 
-fn raw_msg_to_msg(message_text: &str) -> Result<Request, ChatError> {
-    let raw_message = from_str::<RawRequest>(&message_text)?;
-    match raw_message.request_type {
-        RequestType::SetNickname => Ok(Request::SetNickname(from_str::<requests::SetNickname>(
-            &raw_message.data,
-        )?)),
-        RequestType::Message => Ok(Request::Message(from_str::<requests::Message>(
-            &raw_message.data,
-        )?)),
+fn to_request<D: RequestData>(request_type: RequestType, data: D) -> Result<Request, ChatError> {
+    match request_type {
+        RequestType::Hello => Ok(Request::Hello(data.parse()?)),
+        RequestType::SetNickname => Ok(Request::SetNickname(data.parse()?)),
+        RequestType::Message => Ok(Request::Message(data.parse()?)),
         RequestType::Disconnected => Ok(Request::Disconnected),
         RequestType::GetId => Ok(Request::GetId),
         RequestType::Online => Ok(Request::Online),
         RequestType::GlobalOnline => Ok(Request::GlobalOnline),
-        RequestType::CreateRoom => Ok(Request::CreateRoom(from_str::<requests::CreateRoom>(
-            &raw_message.data,
-        )?)),
-        RequestType::JoinRoom => Ok(Request::JoinRoom(from_str::<requests::JoinRoom>(
-            &raw_message.data,
-        )?)),
+        RequestType::CreateRoom => Ok(Request::CreateRoom(data.parse()?)),
+        RequestType::JoinRoom => Ok(Request::JoinRoom(data.parse()?)),
+        RequestType::SubscribePresence => Ok(Request::SubscribePresence(data.parse()?)),
+        RequestType::UnsubscribePresence => Ok(Request::UnsubscribePresence(data.parse()?)),
+        RequestType::GetHistory => Ok(Request::GetHistory(data.parse()?)),
+        RequestType::GetThread => Ok(Request::GetThread(data.parse()?)),
+        RequestType::MuteRoom => Ok(Request::MuteRoom(data.parse()?)),
+        RequestType::GetMentions => Ok(Request::GetMentions),
+        RequestType::ClearMentions => Ok(Request::ClearMentions(data.parse()?)),
+        RequestType::UploadStart => Ok(Request::UploadStart(data.parse()?)),
+        RequestType::UploadChunk => Ok(Request::UploadChunk(data.parse()?)),
+        RequestType::Download => Ok(Request::Download(data.parse()?)),
+        RequestType::RegisterCommands => Ok(Request::RegisterCommands(data.parse()?)),
+        RequestType::LeaveRoom => Ok(Request::LeaveRoom(data.parse()?)),
+        RequestType::UpdateRoom => Ok(Request::UpdateRoom(data.parse()?)),
+        RequestType::Announce => Ok(Request::Announce(data.parse()?)),
+        RequestType::Unpin => Ok(Request::Unpin(data.parse()?)),
+        RequestType::AddReaction => Ok(Request::AddReaction(data.parse()?)),
+        RequestType::RemoveReaction => Ok(Request::RemoveReaction(data.parse()?)),
+    }
+}
+
+fn raw_msg_to_msg(message_text: &str) -> Result<Request, ChatError> {
+    // v2 frames carry a `type` tag, anything else is read as v1
+    if let Ok(tagged_message) = from_str::<TaggedRequest>(message_text) {
+        return to_request(request_type_from_tag(&tagged_message.tag)?, message_text);
+    }
+    let raw_message = from_str::<RawRequest>(message_text)?;
+    to_request(raw_message.request_type, raw_message.data.as_str())
+}
+
+fn nested_msg_to_msg(bytes: &[u8]) -> Result<Request, ChatError> {
+    if let Ok(tagged_message) = from_msgpack::<TaggedRequest>(bytes) {
+        return to_request(
+            request_type_from_tag(&tagged_message.tag)?,
+            MsgPackData {
+                bytes,
+                version: ProtocolVersion::V2,
+            },
+        );
     }
+    let nested_message = from_msgpack::<NestedRequest>(bytes)?;
+    to_request(
+        nested_message.request_type,
+        MsgPackData {
+            bytes,
+            version: ProtocolVersion::V1,
+        },
+    )
+}
+
+fn raw_chunk_to_msg(data: Vec<u8>) -> Result<Request, ChatError> {
+    if data.len() < UUID_LEN {
+        return Err(INVALID_CHUNK.to_owned());
+    }
+    let upload_id = Uuid::from_slice(&data[..UUID_LEN]).map_err(|e| e.to_string())?;
+    Ok(Request::UploadChunk(requests::UploadChunk {
+        upload_id,
+        data: data[UUID_LEN..].to_vec(),
+    }))
+}
+
+/// Prefixes a binary payload with the id it belongs to.
+pub fn binary_frame(id: &Uuid, chunk: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(UUID_LEN + chunk.len());
+    frame.extend_from_slice(id.as_bytes());
+    frame.extend_from_slice(chunk);
+    frame
 }
 
 impl WsClientConnection {
     pub fn new(
         id: Uuid,
         web_socket: WebSocketStream<TcpStream>,
+        codec: Codec,
+        protocol: ProtocolVersion,
         sender: Sender<(Uuid, Request)>,
+        metrics: Arc<Metrics>,
     ) -> Self {
         let (write_sink, mut read_stream) = web_socket.split();
+        let span = info_span!("connection", conn_id = %id, remote_addr = field::Empty);
+        let reader_metrics = Arc::clone(&metrics);
+        let disconnect_handled = Arc::new(AtomicBool::new(false));
+        let reader_disconnect_handled = Arc::clone(&disconnect_handled);
 
-        tokio::spawn(async move {
-            while let Some(msg) = read_stream.next().await {
-                match msg {
-                    Ok(Message::Text(text)) => match raw_msg_to_msg(&text) {
-                        Ok(msg) => sender.send((id, msg)).await.unwrap(),
-                        Err(err) => println!("message_parse err {}", err),
-                    },
-                    Ok(Message::Close(_)) => {
-                        println!("Message::Close! disconnected");
-                        sender.send((id, Request::Disconnected)).await.unwrap();
+        tokio::spawn(
+            async move {
+                while let Some(msg) = read_stream.next().await {
+                    if let Ok(msg) = &msg {
+                        reader_metrics.bytes_in(msg.len());
                     }
-                    Ok(msg) => {
-                        println!("Unexpected msg: {:?}", msg);
-                    }
-                    Err(err) => {
-                        println!("Err(err)! disconnected {}", err);
-                        sender.send((id, Request::Disconnected)).await.unwrap();
-                        break;
+                    let parsed = match msg {
+                        Ok(Message::Text(text)) => raw_msg_to_msg(&text),
+                        // json connections only send raw upload chunks as binary
+                        Ok(Message::Binary(data)) => match codec {
+                            Codec::Json => raw_chunk_to_msg(data),
+                            Codec::MessagePack => nested_msg_to_msg(&data),
+                        },
+                        Ok(Message::Close(_)) => {
+                            debug!("closed by client");
+                            break;
+                        }
+                        Ok(msg) => {
+                            debug!(frame = ?msg, "unexpected frame");
+                            continue;
+                        }
+                        Err(err) => {
+                            info!(error = %err, "connection lost");
+                            break;
+                        }
+                    };
+                    match parsed {
+                        Ok(msg) => {
+                            // the receiver is gone once the server shut down
+                            if sender.send((id, msg)).await.is_err() {
+                                break;
+                            }
+                        }
+                        Err(err) => {
+                            reader_metrics.error(ErrorKind::Parse);
+                            info!(error = %err, "unreadable frame")
+                        }
                     }
                 }
+                // the stream is gone whichever way it ended
+                if !reader_disconnect_handled.load(Ordering::SeqCst) {
+                    let _ = sender.send((id, Request::Disconnected)).await;
+                }
             }
-        });
+            .instrument(span.clone()),
+        );
 
         Self {
             id,
             name: None,
+            unread_mentions: HashMap::new(),
+            codec,
+            protocol,
+            capabilities: None,
+            bot: false,
+            admin: false,
+            commands: HashSet::new(),
+            message_rate: None,
+            remote_addr: None,
+            connected_at: Utc::now(),
+            span,
+            metrics,
+            disconnect_handled,
             write_sink,
         }
     }
 
-    pub async fn send(&mut self, response_str: &str) -> Result<(), ChatError> {
-        Ok(self
-            .write_sink
-            .send(Message::Text(response_str.to_owned()))
+    pub fn set_remote_addr(&mut self, remote_addr: SocketAddr) {
+        self.remote_addr = Some(remote_addr);
+        self.span.record("remote_addr", field::display(remote_addr));
+    }
+
+    pub fn user_info(&self) -> UserInfo {
+        UserInfo {
+            id: self.id,
+            name: self.name.clone().unwrap_or_default(),
+            bot: self.bot,
+        }
+    }
+
+    /// Whether the client understands the feature, a client that sent no
+    /// capabilities understands all of them.
+    pub fn declares(&self, feature: Feature) -> bool {
+        match &self.capabilities {
+            Some(capabilities) => capabilities.contains(&feature),
+            None => true,
+        }
+    }
+
+    pub fn accepts(&self, response: &Envelope) -> bool {
+        match Feature::of_event(response.response_type) {
+            Some(feature) => self.declares(feature),
+            None => true,
+        }
+    }
+
+    /// Sends the response unless the client opted out of its feature.
+    pub async fn send(&mut self, response: &Envelope) -> Result<(), ChatError> {
+        if !self.accepts(response) {
+            return Ok(());
+        }
+        let message = response.encode(self.codec, self.protocol)?;
+        self.metrics.bytes_out(message.len());
+        self.write_sink
+            .send(message)
+            .await
+            .map_err(tungstenite_error_to_chat_error)
+    }
+
+    /// Starts the close handshake, the reader reports the disconnect.
+    pub async fn close(&mut self) -> Result<(), ChatError> {
+        self.write_sink
+            .send(Message::Close(None))
+            .await
+            .map_err(tungstenite_error_to_chat_error)
+    }
+
+    /// Starts the close handshake for a disconnect the server handles
+    /// itself, the reader does not report it.
+    pub async fn close_handled(&mut self) -> Result<(), ChatError> {
+        self.disconnect_handled.store(true, Ordering::SeqCst);
+        self.close().await
+    }
+
+    pub async fn send_binary(&mut self, data: Vec<u8>) -> Result<(), ChatError> {
+        self.metrics.bytes_out(data.len());
+        self.write_sink
+            .send(Message::Binary(data))
             .await
-            .map_err(tungstenite_error_to_chat_error)?)
+            .map_err(tungstenite_error_to_chat_error)
     }
 }